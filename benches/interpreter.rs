@@ -0,0 +1,125 @@
+// Interpreter benchmarks, one per instruction mix.
+//
+// Every benchmark sets `bytes` to the number of ops the program executes,
+// so the MB/s column bencher prints reads as millions of ops per second.
+// Run with `cargo bench`.
+#[macro_use]
+extern crate bencher;
+extern crate raptortime;
+
+use bencher::Bencher;
+
+use raptortime::Options;
+use raptortime::runtime::Runtime;
+use raptortime::assembler::{Assembler, ModuleBuilder};
+use raptortime::instructions::Instruction as Instr;
+
+const LOOP_COUNT: i32 = 10_000;
+
+// Wraps a loop body into `fn f() { local 0 = LOOP_COUNT; do { body } while --local 0 > 0 }`
+// called from main. Returns the module and the number of ops it executes.
+fn loop_module(body: &[u8], body_ops: u64, local_count: u32) -> (Vec<u8>, u64) {
+    let f = Assembler::new()
+        .iconst(LOOP_COUNT).op_with(Instr::STORE, 0)
+        .label("top")
+        .bytes(body)
+        .iconst(1).op_with(Instr::LOAD, 0).op(Instr::SUB).op_with(Instr::STORE, 0)
+        .op_with(Instr::LOAD, 0).jump(Instr::RELJUMP_GT, "top")
+        .iconst(0).op(Instr::RETURN)
+        .finish();
+
+    let main = Assembler::new().op_with(Instr::CALL, 0).op(Instr::POP).finish();
+    let module = ModuleBuilder::new()
+        .func(0, "loop", 0, local_count, &f)
+        .main(&main)
+        .build();
+    // setup + (body + counter) per iteration + return, plus main's CALL/POP
+    let ops = 2 + (body_ops + 6) * LOOP_COUNT as u64 + 2 + 2;
+    (module, ops)
+}
+
+// fn fib(n) { if n < 2 { return n } return fib(n - 1) + fib(n - 2) }
+fn fib_module(n: i32) -> (Vec<u8>, u64) {
+    let fib = Assembler::new()
+        .iconst(2).op_with(Instr::LOAD, 0).op(Instr::COMP_LT)
+        .jump(Instr::RELJUMP_GT, "base")
+        .iconst(1).op_with(Instr::LOAD, 0).op(Instr::SUB).op_with(Instr::CALL, 0)
+        .iconst(2).op_with(Instr::LOAD, 0).op(Instr::SUB).op_with(Instr::CALL, 0)
+        .op(Instr::ADD).op(Instr::RETURN)
+        .label("base")
+        .op_with(Instr::LOAD, 0).op(Instr::RETURN)
+        .finish();
+    let main = Assembler::new()
+        .iconst(n).op_with(Instr::CALL, 0).op(Instr::POP)
+        .finish();
+    let module = ModuleBuilder::new().func(0, "fib", 1, 0, &fib).main(&main).build();
+
+    // Base cases run 6 ops, the others 14
+    fn ops(n: i32) -> u64 {
+        if n < 2 { 6 } else { 14 + ops(n - 1) + ops(n - 2) }
+    }
+    (module, 3 + ops(n))
+}
+
+fn run(b: &mut Bencher, (data, ops): (Vec<u8>, u64)) {
+    b.bytes = ops;
+    b.iter(|| {
+        let mut runtime = Runtime::new(data.clone(), Options::default()).unwrap();
+        runtime.run().unwrap();
+    });
+}
+
+// Stack arithmetic only
+fn arithmetic(b: &mut Bencher) {
+    let body = Assembler::new()
+        .iconst(3).iconst(7).op(Instr::MULTIPLY)
+        .iconst(5).op(Instr::ADD)
+        .iconst(2).op(Instr::MODULUS)
+        .iconst(1).op(Instr::LSHIFT)
+        .iconst(6).op(Instr::AND)
+        .op(Instr::NOT).op(Instr::POP)
+        .finish();
+    run(b, loop_module(&body, 13, 1));
+}
+
+// Shuffling values between locals
+fn locals(b: &mut Bencher) {
+    let mut body = Assembler::new();
+    for i in 1..8 {
+        body.op_with(Instr::LOAD, i).op_with(Instr::LOAD, 0).op(Instr::ADD)
+            .op_with(Instr::STORE, 8 - i);
+    }
+    run(b, loop_module(&body.finish(), 4 * 7, 8));
+}
+
+// Comparisons and conditional jumps, all but one of them taken
+fn branches(b: &mut Bencher) {
+    let body = Assembler::new()
+        .iconst(1).iconst(2).op(Instr::COMP_LT)
+        .jump(Instr::RELJUMP_EQ, "a").op(Instr::NOP)
+        .label("a")
+        .iconst(1).iconst(2).op(Instr::COMP_GT)
+        .jump(Instr::RELJUMP_LT, "b").op(Instr::NOP)
+        .label("b")
+        .iconst(2).iconst(2).op(Instr::COMP)
+        .jump(Instr::RELJUMP_EQ, "c").op(Instr::NOP)
+        .label("c")
+        .finish();
+    run(b, loop_module(&body, 13, 1));
+}
+
+// CALL/RETURN heavy recursion, fib(30) makes about 2.7 million calls
+fn calls(b: &mut Bencher) {
+    run(b, fib_module(30));
+}
+
+// Header, const table and decoding, without running anything
+fn load(b: &mut Bencher) {
+    let (data, _) = fib_module(20);
+    b.bytes = data.len() as u64;
+    b.iter(|| Runtime::new(data.clone(), Options::default()).unwrap());
+}
+
+// TODO: Object/vector access, once STOREFIELD & co. are implemented
+benchmark_group!(interpreter, arithmetic, locals, branches, calls, load);
+benchmark_main!(interpreter);
@@ -0,0 +1,10 @@
+// Module loading: header, const table and decoding
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use raptortime::runtime::Runtime;
+use raptortime::Options;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Runtime::new(data.to_vec(), Options::default());
+});
@@ -0,0 +1,19 @@
+// Loading plus a bounded run of whatever loaded
+#![no_main]
+use std::io;
+
+use libfuzzer_sys::fuzz_target;
+
+use raptortime::runtime::Runtime;
+use raptortime::Options;
+
+const STEP_LIMIT: u64 = 100_000;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut runtime) = Runtime::new(data.to_vec(), Options::default()) {
+        runtime.set_step_limit(Some(STEP_LIMIT));
+        // READ_INT shouldn't wait on stdin
+        runtime.set_input(Box::new(io::empty()));
+        let _ = runtime.run();
+    }
+});
@@ -0,0 +1,258 @@
+// A tiny assembler for RaptorScript bytecode.
+// The compiler lives in its own repo, this is only meant for building
+// modules in benchmarks and tests without having to count bytes by hand.
+
+use std::collections::HashMap;
+
+use header::{MAGIC_VALUE, NO_ENTRY, HEADER_V2_SIZE, SECTION_ENTRY_SIZE, SectionKind};
+use constants::ConstInstr;
+use debug_info::DebugInstr;
+use instructions::Instruction as Instr;
+
+#[derive(Debug, Default)]
+pub struct Assembler {
+    code: Vec<u8>,
+    labels: HashMap<String, usize>,
+    // (offset of the jump opcode, label it jumps to)
+    fixups: Vec<(usize, String)>,
+    // (offset, source line), for the debug info line table
+    lines: Vec<(u32, u32)>,
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Default::default()
+    }
+
+    // Emits an instruction without operands
+    pub fn op(&mut self, instr: Instr) -> &mut Assembler {
+        self.code.push(instr as u8);
+        self
+    }
+
+    // Emits an instruction with a 4 byte operand
+    pub fn op_with(&mut self, instr: Instr, operand: u32) -> &mut Assembler {
+        self.code.push(instr as u8);
+        push_u32(&mut self.code, operand);
+        self
+    }
+
+    pub fn iconst(&mut self, val: i32) -> &mut Assembler {
+        self.op_with(Instr::ICONST, val as u32)
+    }
+
+    // Appends already assembled bytecode. Jumps are relative,
+    // so anything finish()ed on its own can be embedded as is.
+    pub fn bytes(&mut self, bytecode: &[u8]) -> &mut Assembler {
+        self.code.extend_from_slice(bytecode);
+        self
+    }
+
+    // Marks the current offset with a label that jumps can refer to
+    pub fn label(&mut self, name: &str) -> &mut Assembler {
+        self.labels.insert(name.to_string(), self.code.len());
+        self
+    }
+
+    // Instructions emitted from here on come from source line `line`
+    pub fn line(&mut self, line: u32) -> &mut Assembler {
+        self.lines.push((self.code.len() as u32, line));
+        self
+    }
+
+    pub fn lines(&self) -> &[(u32, u32)] {
+        &self.lines
+    }
+
+    // Emits a RELJUMP* instruction to `label`, resolved in finish()
+    pub fn jump(&mut self, instr: Instr, label: &str) -> &mut Assembler {
+        self.fixups.push((self.code.len(), label.to_string()));
+        self.op_with(instr, 0)
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        for &(at, ref label) in &self.fixups {
+            let target = match self.labels.get(label) {
+                Some(&target) => target,
+                None => panic!("Undefined label \"{}\"", label),
+            };
+            // Jump offsets are relative to the last operand byte
+            let offset = target as i64 - (at + 4) as i64;
+            let bytes = u32_bytes(offset as i32 as u32);
+            self.code[at + 1..at + 5].copy_from_slice(&bytes);
+        }
+        self.code.clone()
+    }
+}
+
+#[derive(Debug)]
+pub struct ModuleBuilder {
+    version: u8,
+    flags: u32,
+    entry: u32,
+    var_count: u32,
+    const_table: Vec<u8>,
+    main: Vec<u8>,
+    // (kind, data), only written by version 2
+    sections: Vec<(u32, Vec<u8>)>,
+    // DEBUG section records, without the END
+    debug_info: Vec<u8>,
+}
+
+impl Default for ModuleBuilder {
+    fn default() -> ModuleBuilder {
+        ModuleBuilder {
+            version: 1,
+            flags: 0,
+            entry: NO_ENTRY,
+            var_count: 0,
+            const_table: Vec::new(),
+            main: Vec::new(),
+            sections: Vec::new(),
+            debug_info: Vec::new(),
+        }
+    }
+}
+
+impl ModuleBuilder {
+    pub fn new() -> ModuleBuilder {
+        Default::default()
+    }
+
+    // 1 (the default) or 2. Flags, entry and extra sections need version 2.
+    pub fn version(&mut self, version: u8) -> &mut ModuleBuilder {
+        self.version = version;
+        self
+    }
+
+    pub fn flags(&mut self, flags: u32) -> &mut ModuleBuilder {
+        self.flags = flags;
+        self
+    }
+
+    pub fn entry(&mut self, id: u32) -> &mut ModuleBuilder {
+        self.entry = id;
+        self
+    }
+
+    pub fn custom_section(&mut self, kind: u32, data: &[u8]) -> &mut ModuleBuilder {
+        self.sections.push((kind, data.to_vec()));
+        self
+    }
+
+    pub fn var_count(&mut self, var_count: u32) -> &mut ModuleBuilder {
+        self.var_count = var_count;
+        self
+    }
+
+    pub fn func(&mut self, id: u32, name: &str, arg_count: u32,
+                local_count: u32, body: &[u8]) -> &mut ModuleBuilder {
+        self.const_table.push(ConstInstr::FUNC as u8);
+        push_u32(&mut self.const_table, id);
+        push_string(&mut self.const_table, name);
+        push_u32(&mut self.const_table, arg_count);
+        push_u32(&mut self.const_table, local_count);
+        push_u32(&mut self.const_table, body.len() as u32);
+        self.const_table.extend_from_slice(body);
+        self
+    }
+
+    pub fn main(&mut self, body: &[u8]) -> &mut ModuleBuilder {
+        self.main = body.to_vec();
+        self
+    }
+
+    // Debug info goes into a DEBUG section, so it needs version 2.
+    // Functions are referred to by id, main by MAIN_ID.
+    pub fn debug_file(&mut self, name: &str) -> &mut ModuleBuilder {
+        self.debug_info.push(DebugInstr::FILE as u8);
+        push_string(&mut self.debug_info, name);
+        self
+    }
+
+    pub fn debug_lines(&mut self, id: u32, lines: &[(u32, u32)]) -> &mut ModuleBuilder {
+        self.debug_info.push(DebugInstr::LINES as u8);
+        push_u32(&mut self.debug_info, id);
+        push_u32(&mut self.debug_info, lines.len() as u32);
+        for &(offset, line) in lines {
+            push_u32(&mut self.debug_info, offset);
+            push_u32(&mut self.debug_info, line);
+        }
+        self
+    }
+
+    pub fn debug_locals(&mut self, id: u32, names: &[&str]) -> &mut ModuleBuilder {
+        self.debug_info.push(DebugInstr::LOCALS as u8);
+        push_u32(&mut self.debug_info, id);
+        push_u32(&mut self.debug_info, names.len() as u32);
+        for name in names {
+            push_string(&mut self.debug_info, name);
+        }
+        self
+    }
+
+    pub fn debug_globals(&mut self, names: &[&str]) -> &mut ModuleBuilder {
+        self.debug_info.push(DebugInstr::GLOBALS as u8);
+        push_u32(&mut self.debug_info, names.len() as u32);
+        for name in names {
+            push_string(&mut self.debug_info, name);
+        }
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut const_table = self.const_table.clone();
+        const_table.push(ConstInstr::END as u8);
+
+        let mut data = Vec::new();
+        if self.version == 1 {
+            push_u32(&mut data, MAGIC_VALUE);
+            push_u32(&mut data, self.var_count);
+            data.extend_from_slice(&const_table);
+            data.extend_from_slice(&self.main);
+            return data;
+        }
+
+        let mut debug_info = self.debug_info.clone();
+        debug_info.push(DebugInstr::END as u8);
+
+        let mut sections = vec![
+            (SectionKind::CONSTANTS as u32, &const_table),
+            (SectionKind::CODE as u32, &self.main),
+        ];
+        if !self.debug_info.is_empty() {
+            sections.push((SectionKind::DEBUG as u32, &debug_info));
+        }
+        sections.extend(self.sections.iter().map(|&(kind, ref data)| (kind, data)));
+
+        push_u32(&mut data, MAGIC_VALUE | self.version as u32);
+        push_u32(&mut data, self.flags);
+        push_u32(&mut data, self.entry);
+        push_u32(&mut data, self.var_count);
+        push_u32(&mut data, sections.len() as u32);
+        let mut offset = HEADER_V2_SIZE + sections.len() * SECTION_ENTRY_SIZE;
+        for &(kind, section) in &sections {
+            push_u32(&mut data, kind);
+            push_u32(&mut data, offset as u32);
+            push_u32(&mut data, section.len() as u32);
+            offset += section.len();
+        }
+        for &(_, section) in &sections {
+            data.extend_from_slice(section);
+        }
+        data
+    }
+}
+
+fn u32_bytes(val: u32) -> [u8; 4] {
+    [(val >> 24) as u8, (val >> 16) as u8, (val >> 8) as u8, val as u8]
+}
+
+fn push_u32(data: &mut Vec<u8>, val: u32) {
+    data.extend_from_slice(&u32_bytes(val));
+}
+
+fn push_string(data: &mut Vec<u8>, string: &str) {
+    data.extend_from_slice(string.as_bytes());
+    data.push(0x00);
+}
@@ -1,12 +1,16 @@
-use std::str;
+use std::rc::Rc;
 use num::FromPrimitive;
 
+use decoder::{decode, Code};
+use error::{LoadError, MAX_FUNCS, MAX_VARS};
+
 #[derive(Debug, Default, Clone)]
 pub struct FuncConst {
     pub name: String,
     pub arg_count: u32,
     pub local_count: u32,
-    pub body: Vec<u8>
+    // Decoded body, shared with every StackFrame executing this function
+    pub code: Rc<Code>
 }
 
 #[derive(Debug, Default)]
@@ -16,38 +20,40 @@ pub struct ConstTable {
 }
 
 // Eats a null byte terminated string
-fn eat_string(data: &[u8], const_table: &mut ConstTable) -> String {
-    use std::ffi::CString;
-
+fn eat_string(data: &[u8], const_table: &mut ConstTable) -> Result<String, LoadError> {
     let str_start = const_table.bc_counter;
-    let mut str_len: usize = 0;
-    while data[str_start + str_len] != 0x00 {
-        str_len += 1;
-    }
-    let string = CString::new(&data[str_start..(str_start+str_len)]).unwrap();
+    let str_len = match data[str_start..].iter().position(|&b| b == 0x00) {
+        Some(len) => len,
+        None => return Err(LoadError::UnexpectedEnd(data.len())),
+    };
+    let string = String::from_utf8(data[str_start..(str_start+str_len)].to_vec())
+        .map_err(|_| LoadError::InvalidString(str_start))?;
     const_table.bc_counter += str_len + 1; // + 1 for null byte
     //debug!("Ate string {:?} of length {}", string, str_len);
-    string.into_string().unwrap()
+    Ok(string)
 }
 
 #[inline]
-fn get_next_4_bytes(data: &[u8], const_table: &mut ConstTable) -> u32 {
-
-    let val = (data[const_table.bc_counter] as u32) << 24 |
-        (data[const_table.bc_counter + 1] as u32) << 16 |
-        (data[const_table.bc_counter + 2] as u32) << 8 |
-        (data[const_table.bc_counter + 3] as u32);
+fn get_next_4_bytes(data: &[u8], const_table: &mut ConstTable) -> Result<u32, LoadError> {
+    let bytes = match data.get(const_table.bc_counter..const_table.bc_counter + 4) {
+        Some(bytes) => bytes,
+        None => return Err(LoadError::UnexpectedEnd(const_table.bc_counter)),
+    };
+    let val = (bytes[0] as u32) << 24 |
+        (bytes[1] as u32) << 16 |
+        (bytes[2] as u32) << 8 |
+        (bytes[3] as u32);
     const_table.bc_counter += 4;
     debug!("get_next_4_bytes: 0x{:04X}", val);
-    val
+    Ok(val)
 }
 
-pub fn read_const_table(data: &[u8]) -> ConstTable {
+pub fn read_const_table(data: &[u8]) -> Result<ConstTable, LoadError> {
 
     let mut const_table: ConstTable = Default::default();
 
     macro_rules! get_next_4_bytes {
-        () => (get_next_4_bytes(&data, &mut const_table))
+        () => (get_next_4_bytes(data, &mut const_table)?)
     }
 
     while const_table.bc_counter != data.len() {
@@ -65,15 +71,25 @@ pub fn read_const_table(data: &[u8]) -> ConstTable {
         match instr {
             ConstInstr::FUNC => {
                 // TODO: use this id
-                let id = get_next_4_bytes!() as usize;
-                let name = eat_string(&data, &mut const_table);
+                let id = get_next_4_bytes!();
+                if id >= MAX_FUNCS {
+                    return Err(LoadError::InvalidFunctionId(id));
+                }
+                let id = id as usize;
+                let name = eat_string(data, &mut const_table)?;
                 let arg_count = get_next_4_bytes!();
                 let local_count = get_next_4_bytes!();
+                if arg_count as u64 + local_count as u64 > MAX_VARS as u64 {
+                    return Err(LoadError::TooManyVars(arg_count.saturating_add(local_count)));
+                }
                 let bc_length = get_next_4_bytes!() as usize;
-                let body = data[const_table.bc_counter..{
-                    const_table.bc_counter += bc_length;
-                    const_table.bc_counter + 1
-                }].to_vec();
+                let body_start = const_table.bc_counter;
+                let body = match data.get(body_start..body_start.saturating_add(bc_length)) {
+                    Some(body) => body,
+                    None => return Err(LoadError::UnexpectedEnd(data.len())),
+                };
+                const_table.bc_counter += bc_length;
+                let code = Rc::new(decode(body)?);
 
                 if id >= const_table.funcs.len() {
                     const_table.funcs.resize(id + 1,FuncConst{
@@ -83,10 +99,10 @@ pub fn read_const_table(data: &[u8]) -> ConstTable {
 
                 info!("Added function \"{}\" to the constants table", name);
                 const_table.funcs[id] = FuncConst {
-                    name: name,
-                    arg_count: arg_count,
-                    local_count: local_count,
-                    body: body
+                    name,
+                    arg_count,
+                    local_count,
+                    code
                 };
             },
             ConstInstr::END => {
@@ -95,7 +111,7 @@ pub fn read_const_table(data: &[u8]) -> ConstTable {
             }
         }
     }
-    const_table
+    Ok(const_table)
 }
 
 
@@ -0,0 +1,243 @@
+// Bytecode coverage: how often each op of each function (and main) ran, and
+// which way the conditional jumps went. Mapped to source lines in the report
+// when the module has debug info.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use interpreter::{Interpreter, StackFrame, MAIN_ID};
+use decoder::Code;
+use observer::{Observer, Step};
+
+#[derive(Debug, Default)]
+pub struct FunctionCoverage {
+    // Bytecode offset of every op
+    pub offsets: Vec<usize>,
+    // Times every op was executed
+    pub hits: Vec<u64>,
+    // (times taken, times not taken) for every RELJUMP_GT/LT/EQ, by op index
+    pub branches: BTreeMap<usize, (u64, u64)>,
+}
+
+impl FunctionCoverage {
+    fn new(code: &Code) -> FunctionCoverage {
+        FunctionCoverage {
+            offsets: code.offsets.clone(),
+            hits: vec![0; code.ops.len()],
+            branches: BTreeMap::new(),
+        }
+    }
+
+    pub fn executed(&self) -> usize {
+        self.hits.iter().filter(|&&hits| hits != 0).count()
+    }
+
+    // Branch directions that were taken at least once
+    pub fn branches_covered(&self) -> usize {
+        self.branches.values()
+            .map(|&(taken, not_taken)| (taken != 0) as usize + (not_taken != 0) as usize)
+            .sum()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Coverage {
+    // By function id, MAIN_ID for main
+    pub functions: BTreeMap<u32, FunctionCoverage>,
+}
+
+impl Coverage {
+    // Starts with every op of every function unexecuted
+    pub fn new(interpreter: &Interpreter) -> Coverage {
+        let mut functions = BTreeMap::new();
+        for (id, func_const) in interpreter.functions().iter().enumerate() {
+            // Ids nobody defined are empty placeholders
+            if !func_const.code.ops.is_empty() {
+                functions.insert(id as u32, FunctionCoverage::new(&func_const.code));
+            }
+        }
+        functions.insert(MAIN_ID, FunctionCoverage::new(&interpreter.prog_code));
+        Coverage { functions }
+    }
+
+    // The op at `index` in function `func` was executed. For conditional
+    // jumps, `branch` says whether the jump was taken.
+    pub fn record(&mut self, func: u32, index: usize, branch: Option<bool>) {
+        let function = match self.functions.get_mut(&func) {
+            Some(function) => function,
+            None => return,
+        };
+        function.hits[index] += 1;
+        if let Some(taken) = branch {
+            let counts = function.branches.entry(index).or_insert((0, 0));
+            if taken {
+                counts.0 += 1;
+            } else {
+                counts.1 += 1;
+            }
+        }
+    }
+
+    // (executed ops, ops, covered branch directions, branch directions)
+    // over the whole module. Conditional jumps that never ran count as two
+    // uncovered directions.
+    pub fn totals(&self, interpreter: &Interpreter) -> (usize, usize, usize, usize) {
+        self.functions.iter().fold((0, 0, 0, 0), |totals, (&id, function)| {
+            (totals.0 + function.executed(),
+             totals.1 + function.hits.len(),
+             totals.2 + function.branches_covered(),
+             totals.3 + 2 * branch_count(interpreter, id))
+        })
+    }
+
+    pub fn report(&self, interpreter: &Interpreter) -> String {
+        let debug_info = &interpreter.debug_info;
+        let mut report = String::new();
+
+        for (&id, function) in &self.functions {
+            let name = if id == MAIN_ID {
+                "main".to_string()
+            } else {
+                format!("{} (id {})", interpreter.function_name(id), id)
+            };
+            let branches = 2 * branch_count(interpreter, id);
+            report.push_str(&format!("{}: {} instructions, {} branch directions",
+                                     name,
+                                     ratio(function.executed(), function.hits.len()),
+                                     ratio(function.branches_covered(), branches)));
+
+            let location = |offset: usize| match debug_info.location(id, offset) {
+                Some(location) => format!("offset {} ({})", offset, location),
+                None => format!("offset {}", offset),
+            };
+
+            if debug_info.lines.contains_key(&id) {
+                // A line is covered if any of its ops ran
+                let mut lines = BTreeSet::new();
+                let mut covered = BTreeSet::new();
+                for (&offset, &hits) in function.offsets.iter().zip(&function.hits) {
+                    if let Some(line) = debug_info.line(id, offset) {
+                        lines.insert(line);
+                        if hits != 0 {
+                            covered.insert(line);
+                        }
+                    }
+                }
+                report.push_str(&format!(", {} lines\n", ratio(covered.len(), lines.len())));
+                let missed: Vec<String> = lines.difference(&covered)
+                    .map(|line| line.to_string())
+                    .collect();
+                if !missed.is_empty() {
+                    report.push_str(&format!("  lines not executed: {}\n", missed.join(", ")));
+                }
+            } else {
+                report.push('\n');
+                let missed: Vec<String> = function.offsets.iter().zip(&function.hits)
+                    .filter(|&(_, &hits)| hits == 0)
+                    .map(|(offset, _)| offset.to_string())
+                    .collect();
+                if !missed.is_empty() {
+                    report.push_str(&format!("  offsets not executed: {}\n", missed.join(", ")));
+                }
+            }
+
+            for (&index, &(taken, not_taken)) in &function.branches {
+                report.push_str(&format!("  branch at {}: taken {}, not taken {}\n",
+                                         location(function.offsets[index]), taken, not_taken));
+            }
+        }
+
+        let (executed, ops, covered, branches) = self.totals(interpreter);
+        report.push_str(&format!("total: {} instructions, {} branch directions\n",
+                                 ratio(executed, ops), ratio(covered, branches)));
+        report
+    }
+}
+
+impl Observer for Coverage {
+    fn on_instruction(&mut self, _inpr: &Interpreter, frame: &StackFrame, step: &Step) {
+        self.record(frame.id, step.index, step.branch);
+    }
+}
+
+// Conditional jumps in function `id`, executed or not
+fn branch_count(interpreter: &Interpreter, id: u32) -> usize {
+    use decoder::Op;
+
+    let code = if id == MAIN_ID {
+        &interpreter.prog_code
+    } else {
+        match interpreter.function(id) {
+            Some(func_const) => &func_const.code,
+            None => return 0,
+        }
+    };
+    code.ops.iter()
+        .filter(|op| matches!(**op, Op::RELJUMP_GT(_) | Op::RELJUMP_LT(_) | Op::RELJUMP_EQ(_)))
+        .count()
+}
+
+fn ratio(covered: usize, total: usize) -> String {
+    if total == 0 {
+        return format!("{}/{}", covered, total);
+    }
+    format!("{}/{} ({:.1}%)", covered, total, covered as f64 * 100.0 / total as f64)
+}
+
+
+#[cfg(test)]
+mod coverage_tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use Options;
+    use assembler::{Assembler, ModuleBuilder};
+    use instructions::Instruction as Instr;
+    use runtime::Runtime;
+
+    fn run(builder: &mut ModuleBuilder) -> (Runtime, Rc<RefCell<Coverage>>) {
+        let mut runtime = Runtime::new(builder.build(), Options::default()).unwrap();
+        let coverage = Rc::new(RefCell::new(Coverage::new(runtime.interpreter())));
+        runtime.add_observer(Box::new(Rc::clone(&coverage)));
+        runtime.run().unwrap();
+        (runtime, coverage)
+    }
+
+    #[test]
+    fn offsets_and_branches() {
+        // abs(-3), the positive path is never taken
+        let mut f = Assembler::new();
+        f.line(1).op_with(Instr::LOAD, 0).jump(Instr::RELJUMP_LT, "negative")
+            .line(2).op_with(Instr::LOAD, 0).op(Instr::RETURN)
+            .label("negative")
+            .line(3).op_with(Instr::LOAD, 0).iconst(-1).op(Instr::MULTIPLY).op(Instr::RETURN);
+        let main = Assembler::new().iconst(-3).op_with(Instr::CALL, 1).finish();
+        let mut builder = ModuleBuilder::new();
+        builder.version(2).func(1, "abs", 1, 0, &f.finish()).main(&main);
+
+        let (runtime, coverage) = run(&mut builder);
+        let coverage = coverage.borrow();
+        // Id 0 is an empty placeholder
+        assert_eq!(coverage.functions.keys().collect::<Vec<_>>(), vec![&1, &MAIN_ID]);
+        let abs = &coverage.functions[&1];
+        assert_eq!(abs.hits, vec![1, 1, 0, 0, 1, 1, 1, 1]);
+        assert_eq!(abs.branches.get(&1), Some(&(1, 0)));
+        assert_eq!(coverage.totals(runtime.interpreter()), (8, 10, 1, 2));
+        assert_eq!(coverage.report(runtime.interpreter()), "\
+abs (id 1): 6/8 (75.0%) instructions, 1/2 (50.0%) branch directions
+  offsets not executed: 10, 15
+  branch at offset 5: taken 1, not taken 0
+main: 2/2 (100.0%) instructions, 0/0 branch directions
+total: 8/10 (80.0%) instructions, 1/2 (50.0%) branch directions
+");
+
+        let lines = f.lines().to_vec();
+        let (runtime, coverage) = run(builder.debug_file("abs.rapt").debug_lines(1, &lines));
+        let report = coverage.borrow().report(runtime.interpreter());
+        assert!(report.starts_with("\
+abs (id 1): 6/8 (75.0%) instructions, 1/2 (50.0%) branch directions, 2/3 (66.7%) lines
+  lines not executed: 2
+  branch at offset 5 (abs.rapt:1): taken 1, not taken 0
+"), "{}", report);
+    }
+}
@@ -0,0 +1,303 @@
+// Post-mortem crash dumps: the state of a runtime that failed, written where
+// it failed (a build server, say) and looked at later with --inspect.
+//
+// The file starts with the magic "RCRD" and a version byte, followed by
+//   module hash   u64, FNV-1a of the module, to tell which build crashed
+//   error         count, count * byte     the error message
+//   heap          u64 objects, u64 bytes
+//   snapshot      the rest of the file, as described in snapshot.rs
+// Everything is big endian. The snapshot has the module itself, the call
+// stack with every frame's locals and bc counter, the op stack and the
+// globals, so the dump is all --inspect needs. There's no heap until objects
+// are implemented, so its summary is always empty for now.
+
+use std::io::{self, Write};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use Options;
+use error::LoadError;
+use expr::Expr;
+use runtime::Runtime;
+use snapshot::{read_snapshot, Snapshot};
+
+pub const CRASH_MAGIC: u32 = 0x52435244;
+pub const CRASH_VERSION: u8 = 1;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct HeapSummary {
+    pub objects: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct CrashDump {
+    pub module_hash: u64,
+    pub error: String,
+    pub heap: HeapSummary,
+    pub snapshot: Snapshot,
+}
+
+// 64 bit FNV-1a
+pub fn module_hash(module: &[u8]) -> u64 {
+    module.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl CrashDump {
+    // Of a runtime run() just failed with `error`, or that panicked
+    pub fn new(runtime: &Runtime, error: &str) -> CrashDump {
+        let snapshot = runtime.snapshot();
+        CrashDump {
+            module_hash: module_hash(&snapshot.module),
+            error: error.to_string(),
+            heap: HeapSummary::default(),
+            snapshot,
+        }
+    }
+
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_u32::<BigEndian>(CRASH_MAGIC)?;
+        out.write_u8(CRASH_VERSION)?;
+        out.write_u64::<BigEndian>(self.module_hash)?;
+        out.write_u32::<BigEndian>(self.error.len() as u32)?;
+        out.write_all(self.error.as_bytes())?;
+        out.write_u64::<BigEndian>(self.heap.objects)?;
+        out.write_u64::<BigEndian>(self.heap.bytes)?;
+        self.snapshot.write(out)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        self.write(&mut data).unwrap();
+        data
+    }
+}
+
+pub fn read_crash_dump(data: &[u8]) -> Result<CrashDump, LoadError> {
+    let mut rest = data;
+    let end = |rest: &[u8]| LoadError::UnexpectedEnd(data.len() - rest.len());
+
+    let magic = rest.read_u32::<BigEndian>().map_err(|_| end(rest))?;
+    if magic != CRASH_MAGIC {
+        return Err(LoadError::InvalidMagic(magic));
+    }
+    let version = rest.read_u8().map_err(|_| end(rest))?;
+    if version != CRASH_VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+    let hash = rest.read_u64::<BigEndian>().map_err(|_| end(rest))?;
+    let len = rest.read_u32::<BigEndian>().map_err(|_| end(rest))? as usize;
+    if len > rest.len() {
+        return Err(end(rest));
+    }
+    let error = String::from_utf8(rest[..len].to_vec())
+        .map_err(|_| LoadError::InvalidString(data.len() - rest.len()))?;
+    rest = &rest[len..];
+    let objects = rest.read_u64::<BigEndian>().map_err(|_| end(rest))?;
+    let bytes = rest.read_u64::<BigEndian>().map_err(|_| end(rest))?;
+    let snapshot = read_snapshot(rest)?;
+    if module_hash(&snapshot.module) != hash {
+        return Err(LoadError::InvalidSnapshot("module hash"));
+    }
+    Ok(CrashDump { module_hash: hash, error, heap: HeapSummary { objects, bytes }, snapshot })
+}
+
+// Looks around a crash dump: the runtime is restored as it was when it
+// failed, and never runs
+pub struct Inspector {
+    pub dump: CrashDump,
+    pub runtime: Runtime,
+    // Selected frame, 0 being the innermost like in stack traces
+    frame: usize,
+}
+
+impl Inspector {
+    pub fn new(dump: CrashDump) -> Result<Inspector, LoadError> {
+        let options = Options { debug: false, ..Default::default() };
+        let runtime = Runtime::restore(&dump.snapshot, options)?;
+        Ok(Inspector { dump, runtime, frame: 0 })
+    }
+
+    // The error, where it happened and the stack trace
+    pub fn summary(&self, out: &mut dyn Write) -> io::Result<()> {
+        match self.runtime.location() {
+            Some(location) => writeln!(out, "{}: {}", location, self.dump.error)?,
+            None => writeln!(out, "{}", self.dump.error)?,
+        }
+        writeln!(out, "after {} steps, module {:016x}", self.runtime.steps(), self.dump.module_hash)?;
+        for line in self.runtime.stack_trace() {
+            writeln!(out, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    // Reads commands with `read_line` until it returns 0 or "quit"
+    pub fn repl(&mut self, read_line: &mut dyn FnMut(&mut String) -> io::Result<usize>,
+                out: &mut dyn Write) -> io::Result<()> {
+        self.summary(out)?;
+        loop {
+            write!(out, "(dump) ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            if !self.command(&line, out)? {
+                return Ok(());
+            }
+        }
+    }
+
+    // Runs a single command, false if it was "quit"
+    pub fn command(&mut self, line: &str, out: &mut dyn Write) -> io::Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (&command, args) = match words.split_first() {
+            Some(split) => split,
+            None => return Ok(true),
+        };
+        let call_stack = self.runtime.call_stack();
+        let interpreter = self.runtime.interpreter();
+        match command {
+            "summary" => self.summary(out)?,
+            "bt" | "backtrace" => for line in self.runtime.stack_trace() {
+                writeln!(out, "{}", line)?;
+            },
+            "f" | "frame" | "up" | "down" => {
+                let frame = match (command, args.first().map(|arg| arg.parse::<usize>())) {
+                    ("up", _) => self.frame + 1,
+                    ("down", _) => self.frame.wrapping_sub(1),
+                    (_, Some(Ok(frame))) => frame,
+                    (_, None) => self.frame,
+                    (_, Some(Err(_))) => {
+                        writeln!(out, "Usage: frame N")?;
+                        return Ok(true);
+                    },
+                };
+                if frame >= call_stack.len() {
+                    writeln!(out, "No frame {}", frame as isize)?;
+                    return Ok(true);
+                }
+                self.frame = frame;
+                let index = call_stack.len() - 1 - frame;
+                writeln!(out, "#{} {}", frame, interpreter.describe_frame(&call_stack[index]))?;
+            },
+            "stack" => {
+                // The selected frame's part of the op stack, from its return
+                // address up to the next frame's
+                let index = call_stack.len().saturating_sub(1 + self.frame);
+                let stack = &interpreter.op_stack;
+                let (start, end) = match call_stack.get(index) {
+                    Some(frame) => (frame.return_addr.min(stack.len()),
+                                    call_stack.get(index + 1).map_or(stack.len(), |f| f.return_addr)),
+                    None => (0, stack.len()),
+                };
+                writeln!(out, "frame {:?}", &stack[start..end.clamp(start, stack.len())])?;
+                writeln!(out, "whole {:?}", stack)?;
+            },
+            "globals" => writeln!(out, "globals {}", interpreter.named_globals())?,
+            "heap" => writeln!(out, "{} objects, {} bytes", self.dump.heap.objects, self.dump.heap.bytes)?,
+            "p" | "print" => {
+                let index = call_stack.len().saturating_sub(1 + self.frame);
+                let value = Expr::parse(&args.join(" ")).and_then(|expr| match call_stack.get(index) {
+                    Some(frame) => expr.eval(interpreter, frame, 0),
+                    None => Err("No frames".to_string()),
+                });
+                match value {
+                    Ok(value) => writeln!(out, "{}", value)?,
+                    Err(e) => writeln!(out, "{}", e)?,
+                }
+            },
+            "q" | "quit" => return Ok(false),
+            "h" | "help" => writeln!(out, "{}", HELP)?,
+            _ => writeln!(out, "Unknown command {}, try help", command)?,
+        }
+        Ok(true)
+    }
+}
+
+const HELP: &str = "\
+summary                 the error, where it happened and the stack trace
+backtrace, bt           print the call stack
+frame, f [N]            select frame N (0 is the innermost) and print it
+up, down                select the caller or the callee
+stack                   print the selected frame's part of the op stack, and all of it
+globals                 print the globals
+heap                    print the heap summary
+print, p EXPRESSION     evaluate an expression in the selected frame, like
+                        \"local[1] * 2\" (see break in the debugger)
+quit, q                 stop inspecting";
+
+
+#[cfg(test)]
+mod crash_tests {
+    use std::io::{BufRead, Cursor};
+
+    use super::*;
+    use assembler::{Assembler, ModuleBuilder};
+    use instructions::Instruction as Instr;
+
+    // main pushes 7 and calls f(3), which divides n by n - 3 and leaves both on the stack
+    fn crashed() -> Runtime {
+        let mut f = Assembler::new();
+        f.line(2).iconst(3).op_with(Instr::LOAD, 0).op(Instr::SUB).op_with(Instr::LOAD, 0)
+            .op(Instr::DIVIDE);
+        let main = Assembler::new().iconst(7).iconst(3).op_with(Instr::CALL, 0).finish();
+        let data = ModuleBuilder::new()
+            .version(2)
+            .func(0, "f", 1, 0, &f.finish())
+            .main(&main)
+            .debug_file("div.rapt")
+            .debug_lines(0, f.lines())
+            .debug_locals(0, &["n"])
+            .build();
+        let mut runtime = Runtime::new(data, Options::default()).unwrap();
+        assert_eq!(runtime.run(), Err(::error::RuntimeError::DivisionByZero));
+        runtime
+    }
+
+    #[test]
+    fn round_trip() {
+        let runtime = crashed();
+        let dump = CrashDump::new(&runtime, "Runtime error: Division by zero");
+        let data = dump.to_bytes();
+        let read = read_crash_dump(&data).unwrap();
+        assert_eq!(read.to_bytes(), data);
+        assert_eq!(read.module_hash, module_hash(&runtime.snapshot().module));
+        assert_eq!(read.error, "Runtime error: Division by zero");
+        assert_eq!(read.snapshot.call_stack[1].locals, vec![3]);
+
+        for len in 0..data.len() {
+            assert!(read_crash_dump(&data[..len]).is_err(), "{}", len);
+        }
+        let mut other = data.clone();
+        other[5] ^= 1;
+        assert_eq!(read_crash_dump(&other).err(), Some(LoadError::InvalidSnapshot("module hash")));
+    }
+
+    #[test]
+    fn inspect() {
+        let dump = CrashDump::new(&crashed(), "Runtime error: Division by zero");
+        let hash = dump.module_hash;
+        let mut inspector = Inspector::new(dump).unwrap();
+        let mut input = Cursor::new("stack\nprint n * 2\nup\nstack\nup\nheap\ndown\np x\nq\n");
+        let mut out = Vec::new();
+        inspector.repl(&mut |line| input.read_line(line), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("\
+div.rapt:2: Runtime error: Division by zero
+after 8 steps, module {:016x}
+#0 f (id 0), offset 16 (div.rapt:2), args [n = 3], locals []
+#1 main, offset 10
+(dump) frame [0, 3]
+whole [7, 0, 3]
+(dump) 6
+(dump) #1 main, offset 10
+(dump) frame [7]
+whole [7, 0, 3]
+(dump) No frame 2
+(dump) 0 objects, 0 bytes
+(dump) #0 f (id 0), offset 16 (div.rapt:2), args [n = 3], locals []
+(dump) No local or global named x
+(dump) ", hash));
+    }
+}
@@ -0,0 +1,592 @@
+// Debug Adapter Protocol server, so editors can drive the debugger.
+//
+// Messages both ways are a "Content-Length: N" header, an empty line and N
+// bytes of JSON. Breakpoints are set by source line, or by instruction with
+// an instructionReference like "f+36" (what stack frames report as their
+// instructionPointerReference), either can have a condition (see expr.rs).
+// The launch request takes
+//   program      path of the module to run
+//   args         the program's arguments, for ARGC and ARG_INT
+//   input        file for READ_INT to read, no input at all if missing (stdin
+//                is taken by the protocol)
+//   stopOnEntry  stop before the first instruction
+// The program's output is sent as output events.
+// Requests are read on a thread of their own, so a pause (or a terminate or
+// disconnect) can stop a program that's running, through its interrupt
+// handle. Only a pause reports it as stopped.
+// Stepping goes by source line, or by instruction without line info or when
+// the client asks for instruction granularity. Stepping back and reverse
+// continue are supported, see debugger.rs.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use json::{self, JsonValue};
+
+use debugger::{Breakpoint, Debugger, Stop};
+use expr::Expr;
+use output::Capture;
+use runtime::Runtime;
+use Options;
+
+const THREAD_ID: u32 = 1;
+// variablesReference of the globals and the op stack. The locals of frame
+// i (counting from the outermost) are LOCALS + i.
+const GLOBALS: usize = 1;
+const OP_STACK: usize = 2;
+const LOCALS: usize = 3;
+
+// Reads one message, None at the end of the input
+pub fn read_message(input: &mut dyn BufRead) -> io::Result<Option<JsonValue>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = match line.find(':') {
+            Some(colon) => (&line[..colon], line[colon + 1..].trim()),
+            None => return Err(invalid(format!("Invalid header {}", line))),
+        };
+        if name.eq_ignore_ascii_case("Content-Length") {
+            length = Some(value.parse::<usize>()
+                .map_err(|_| invalid(format!("Invalid Content-Length {}", value)))?);
+        }
+    }
+    let length = length.ok_or_else(|| invalid("No Content-Length".to_string()))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    let body = String::from_utf8(body).map_err(|e| invalid(e.to_string()))?;
+    json::parse(&body).map(Some).map_err(|e| invalid(e.to_string()))
+}
+
+pub fn write_message(out: &mut dyn Write, message: &JsonValue) -> io::Result<()> {
+    let body = message.dump();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+struct Session {
+    debugger: Debugger,
+    output: Capture,
+    // Bytes of output already sent
+    sent: usize,
+    // The module's source file, relative to the module
+    source: Option<PathBuf>,
+    // By the client's source path
+    line_breakpoints: HashMap<String, Vec<Breakpoint>>,
+    instruction_breakpoints: Vec<Breakpoint>,
+    stop_on_entry: bool,
+}
+
+impl Session {
+    fn update_breakpoints(&mut self) {
+        let mut breakpoints: Vec<Breakpoint> =
+            self.line_breakpoints.values().flat_map(|b| b.iter().cloned()).collect();
+        breakpoints.extend(self.instruction_breakpoints.iter().cloned());
+        self.debugger.breakpoints = breakpoints;
+    }
+
+    // Whether the client means the module's source file
+    fn is_source(&self, path: &str) -> bool {
+        match self.source {
+            Some(ref source) => source == Path::new(path) ||
+                source.file_name() == Path::new(path).file_name(),
+            None => false,
+        }
+    }
+}
+
+pub struct Server {
+    out: Box<dyn Write>,
+    seq: u64,
+    debug: bool,
+    session: Option<Session>,
+    // Sent after the response to the current request
+    events: Vec<(&'static str, JsonValue)>,
+    // The launched runtime's interrupt handle, set as soon as a pause,
+    // terminate or disconnect request is read
+    interrupt: Arc<AtomicBool>,
+    // Set along with it by terminate and disconnect, which end the program
+    // rather than pause it
+    ending: Arc<AtomicBool>,
+}
+
+impl Server {
+    // `debug` is Options::debug for the launched program
+    pub fn new(out: Box<dyn Write>, debug: bool) -> Server {
+        Server {
+            out,
+            seq: 0,
+            debug,
+            session: None,
+            events: Vec::new(),
+            interrupt: Arc::new(AtomicBool::new(false)),
+            ending: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // Serves requests until the client disconnects or the input ends
+    pub fn run<R: BufRead + Send + 'static>(&mut self, mut input: R) -> io::Result<()> {
+        let (sender, requests) = mpsc::channel();
+        let interrupt = Arc::clone(&self.interrupt);
+        let ending = Arc::clone(&self.ending);
+        thread::spawn(move || loop {
+            let message = read_message(&mut input);
+            if let Ok(Some(ref request)) = message {
+                match request["command"].as_str() {
+                    Some("pause") => interrupt.store(true, Ordering::Relaxed),
+                    Some("disconnect") | Some("terminate") => {
+                        ending.store(true, Ordering::Relaxed);
+                        interrupt.store(true, Ordering::Relaxed);
+                    },
+                    _ => {},
+                }
+            }
+            let done = !matches!(message, Ok(Some(_)));
+            if sender.send(message).is_err() || done {
+                return;
+            }
+        });
+
+        // Ends when the reader does
+        while let Ok(message) = requests.recv() {
+            let request = match message? {
+                Some(request) => request,
+                None => break,
+            };
+            let command = request["command"].as_str().unwrap_or("").to_string();
+            debug!("DAP request {}", command);
+            let result = self.request(&command, &request["arguments"]);
+            let mut response = object! {
+                type: "response",
+                request_seq: request["seq"].clone(),
+                command: command.as_str(),
+                success: result.is_ok(),
+            };
+            match result {
+                Ok(body) => response["body"] = body,
+                Err(message) => response["message"] = message.into(),
+            }
+            self.send(response)?;
+            for (event, body) in self.events.split_off(0) {
+                self.send(object! { type: "event", event: event, body: body })?;
+            }
+            if command == "disconnect" {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, mut message: JsonValue) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = self.seq.into();
+        write_message(&mut self.out, &message)
+    }
+
+    fn session(&mut self) -> Result<&mut Session, String> {
+        self.session.as_mut().ok_or_else(|| "No program launched".to_string())
+    }
+
+    fn request(&mut self, command: &str, args: &JsonValue) -> Result<JsonValue, String> {
+        match command {
+            "initialize" => Ok(object! {
+                supportsConfigurationDoneRequest: true,
+                supportsStepBack: true,
+                supportsInstructionBreakpoints: true,
+                supportsConditionalBreakpoints: true,
+                supportsSteppingGranularity: true,
+                supportsTerminateRequest: true,
+            }),
+            "launch" => {
+                self.launch(args)?;
+                self.events.push(("initialized", JsonValue::new_object()));
+                Ok(JsonValue::Null)
+            },
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "configurationDone" => {
+                let stop = {
+                    let session = self.session()?;
+                    if session.stop_on_entry {
+                        None
+                    } else {
+                        Some(session.debugger.cont())
+                    }
+                };
+                match stop {
+                    Some(stop) => self.report(stop),
+                    None => self.stopped("entry", None),
+                }
+                Ok(JsonValue::Null)
+            },
+            "threads" => Ok(object! { threads: [object! { id: THREAD_ID, name: "main" }] }),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => {
+                let index = args["frameId"].as_usize().unwrap_or(0).wrapping_sub(1);
+                if index >= self.session()?.debugger.runtime.call_stack().len() {
+                    return Err("Invalid frame".to_string());
+                }
+                Ok(object! { scopes: [
+                    object! { name: "Locals", presentationHint: "locals",
+                              variablesReference: LOCALS + index, expensive: false },
+                    object! { name: "Globals", variablesReference: GLOBALS, expensive: false },
+                    object! { name: "Operand stack", variablesReference: OP_STACK, expensive: false },
+                ] })
+            },
+            "variables" => self.variables(args["variablesReference"].as_usize().unwrap_or(0)),
+            "continue" | "next" | "stepIn" | "stepOut" | "stepBack" | "reverseContinue" => {
+                let instruction = args["granularity"].as_str() == Some("instruction");
+                let stop = {
+                    let debugger = &mut self.session()?.debugger;
+                    if let Some(&Stop::Error(_)) = debugger.ended() {
+                        if command != "stepBack" && command != "reverseContinue" {
+                            // Going on after an error ends the session
+                            self.events.push(("exited", object! { exitCode: 1 }));
+                            self.events.push(("terminated", JsonValue::new_object()));
+                            return Ok(JsonValue::Null);
+                        }
+                    }
+                    match command {
+                        "continue" => debugger.cont(),
+                        "next" if instruction => debugger.step_over(),
+                        "next" => step_line(debugger, true),
+                        "stepIn" if instruction => debugger.step(1),
+                        "stepIn" => step_line(debugger, false),
+                        "stepOut" => debugger.finish(),
+                        "stepBack" if instruction => debugger.reverse_step(1),
+                        "stepBack" => step_back_line(debugger),
+                        _ => debugger.reverse_cont(),
+                    }
+                };
+                self.report(stop);
+                Ok(match command {
+                    "continue" => object! { allThreadsContinued: true },
+                    _ => JsonValue::Null,
+                })
+            },
+            "pause" => {
+                // A running program already stopped for it, and a stopped
+                // one has nothing to do
+                self.interrupt.store(false, Ordering::Relaxed);
+                self.session()?;
+                Ok(JsonValue::Null)
+            },
+            "terminate" => {
+                self.clear_interrupt();
+                self.events.push(("terminated", JsonValue::new_object()));
+                Ok(JsonValue::Null)
+            },
+            "disconnect" => {
+                self.clear_interrupt();
+                Ok(JsonValue::Null)
+            },
+            _ => Err(format!("Unsupported request {}", command)),
+        }
+    }
+
+    fn launch(&mut self, args: &JsonValue) -> Result<(), String> {
+        let program = args["program"].as_str().ok_or("launch needs a program")?;
+        let data = fs::read(program).map_err(|e| format!("Couldn't read {}: {}", program, e))?;
+        let options = Options {
+            debug: self.debug,
+            input: program.to_string(),
+            args: args["args"].members().map(|arg| arg.to_string()).collect(),
+        };
+        let mut runtime = Runtime::new(data, options)
+            .map_err(|e| format!("Couldn't load module: {}", e))?;
+        let output = runtime.capture_output();
+        runtime.interpreter_mut().interrupt = Arc::clone(&self.interrupt);
+        match args["input"].as_str() {
+            Some(path) => {
+                let file = File::open(path).map_err(|e| format!("Couldn't open {}: {}", path, e))?;
+                runtime.set_input(Box::new(BufReader::new(file)));
+            },
+            None => runtime.set_input(Box::new(io::empty())),
+        }
+
+        let source = runtime.interpreter().debug_info.file.as_ref().map(|file| {
+            match Path::new(program).parent() {
+                Some(dir) => dir.join(file),
+                None => PathBuf::from(file),
+            }
+        });
+        self.session = Some(Session {
+            debugger: Debugger::new(runtime),
+            output,
+            sent: 0,
+            source,
+            line_breakpoints: HashMap::new(),
+            instruction_breakpoints: Vec::new(),
+            stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+        });
+        Ok(())
+    }
+
+    fn set_breakpoints(&mut self, args: &JsonValue) -> Result<JsonValue, String> {
+        let session = self.session()?;
+        let path = args["source"]["path"].as_str()
+            .or_else(|| args["source"]["name"].as_str())
+            .unwrap_or("")
+            .to_string();
+        let is_source = session.is_source(&path);
+        let mut set = Vec::new();
+        let mut results = JsonValue::new_array();
+        for requested in args["breakpoints"].members() {
+            let line = requested["line"].as_u32().unwrap_or(0);
+            let found = if is_source {
+                session.debugger.parse_location(&line.to_string())
+                    .and_then(|breakpoints| with_condition(breakpoints, requested))
+            } else {
+                Err(format!("No line info for {}", path))
+            };
+            let mut result = object! { verified: found.is_ok(), line: line };
+            match found {
+                Ok(breakpoints) => set.extend(breakpoints),
+                Err(message) => result["message"] = message.into(),
+            }
+            results.push(result).unwrap();
+        }
+        session.line_breakpoints.insert(path, set);
+        session.update_breakpoints();
+        Ok(object! { breakpoints: results })
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &JsonValue) -> Result<JsonValue, String> {
+        let session = self.session()?;
+        let mut set = Vec::new();
+        let mut results = JsonValue::new_array();
+        for requested in args["breakpoints"].members() {
+            let reference = requested["instructionReference"].as_str().unwrap_or("");
+            // Bytes from the reference
+            let spec = match (requested["offset"].as_i64(), reference.rfind('+')) {
+                (Some(offset), Some(plus)) if offset != 0 => {
+                    let base = reference[plus + 1..].parse::<i64>().unwrap_or(-1);
+                    format!("{}+{}", &reference[..plus], base + offset)
+                },
+                _ => reference.to_string(),
+            };
+            let found = session.debugger.parse_location(&spec)
+                .and_then(|breakpoints| with_condition(breakpoints, requested));
+            let mut result = object! { verified: found.is_ok(), instructionReference: spec };
+            match found {
+                Ok(breakpoints) => set.extend(breakpoints),
+                Err(message) => result["message"] = message.into(),
+            }
+            results.push(result).unwrap();
+        }
+        session.instruction_breakpoints = set;
+        session.update_breakpoints();
+        Ok(object! { breakpoints: results })
+    }
+
+    fn stack_trace(&mut self) -> Result<JsonValue, String> {
+        let session = self.session()?;
+        let interpreter = session.debugger.runtime.interpreter();
+        let call_stack = session.debugger.runtime.call_stack();
+        let mut frames = JsonValue::new_array();
+        for (i, frame) in call_stack.iter().enumerate().rev() {
+            // The innermost frame is about to execute its next op, the
+            // others are in the middle of a CALL
+            let offset = if i + 1 == call_stack.len() {
+                frame.code.offset(frame.bc_counter)
+            } else {
+                frame.offset()
+            };
+            let name = interpreter.function_name(frame.id);
+            let mut json_frame = object! {
+                id: i + 1,
+                name: name,
+                line: 0,
+                column: 0,
+                instructionPointerReference: format!("{}+{}", name, offset),
+            };
+            if let (Some(source), Some(line)) =
+                (session.source.as_ref(), interpreter.debug_info.line(frame.id, offset)) {
+                json_frame["source"] = object! {
+                    name: source.file_name().map(|name| name.to_string_lossy().into_owned()),
+                    path: source.to_string_lossy().into_owned(),
+                };
+                json_frame["line"] = line.into();
+                json_frame["column"] = 1.into();
+            }
+            frames.push(json_frame).unwrap();
+        }
+        Ok(object! { totalFrames: frames.len(), stackFrames: frames })
+    }
+
+    fn variables(&mut self, reference: usize) -> Result<JsonValue, String> {
+        let runtime = &self.session()?.debugger.runtime;
+        let interpreter = runtime.interpreter();
+        let variables: Vec<(String, i32)> = match reference {
+            GLOBALS => interpreter.globals().iter().enumerate()
+                .map(|(i, &value)| match interpreter.debug_info.global_name(i) {
+                    Some(name) => (name.to_string(), value),
+                    None => (format!("global {}", i), value),
+                })
+                .collect(),
+            OP_STACK => interpreter.op_stack.iter().enumerate()
+                .map(|(i, &value)| (format!("[{}]", i), value))
+                .collect(),
+            _ => match runtime.call_stack().get(reference.wrapping_sub(LOCALS)) {
+                Some(frame) => frame.locals.iter().enumerate()
+                    .map(|(i, &value)| (frame.local_name(interpreter, i as u32), value))
+                    .collect(),
+                None => return Err("Invalid variablesReference".to_string()),
+            },
+        };
+        let mut json_variables = JsonValue::new_array();
+        for (name, value) in variables {
+            json_variables.push(object! {
+                name: name,
+                value: value.to_string(),
+                type: "i32",
+                variablesReference: 0,
+            }).unwrap();
+        }
+        Ok(object! { variables: json_variables })
+    }
+
+    // Queues the events for how the debugger stopped
+    fn report(&mut self, stop: Stop) {
+        self.flush_output();
+        match stop {
+            Stop::Step | Stop::Start => self.stopped("step", None),
+            // Ended by a terminate or disconnect, which has its own response
+            Stop::Interrupted if self.ending.load(Ordering::Relaxed) => {},
+            Stop::Interrupted => self.stopped("pause", None),
+            Stop::Breakpoint(_) => self.stopped("breakpoint", None),
+            Stop::Watch(_) => self.stopped("data breakpoint", None),
+            Stop::Finished | Stop::Halted => {
+                self.events.push(("exited", object! { exitCode: 0 }));
+                self.events.push(("terminated", JsonValue::new_object()));
+            },
+            Stop::Error(e) => {
+                let message = match self.session.as_ref().and_then(|s| s.debugger.runtime.location()) {
+                    Some(location) => format!("{}: Runtime error: {}\n", location, e),
+                    None => format!("Runtime error: {}\n", e),
+                };
+                self.events.push(("output", object! { category: "stderr", output: message }));
+                self.stopped("exception", Some(e.to_string()));
+            },
+            Stop::Rewind(e) => {
+                let message = format!("Couldn't go back: {}\n", e);
+                self.events.push(("output", object! { category: "stderr", output: message }));
+                self.stopped("exception", Some(e.to_string()));
+            },
+        }
+    }
+
+    // Once a terminate or disconnect is handled
+    fn clear_interrupt(&mut self) {
+        self.ending.store(false, Ordering::Relaxed);
+        self.interrupt.store(false, Ordering::Relaxed);
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>) {
+        let mut body = object! { reason: reason, threadId: THREAD_ID, allThreadsStopped: true };
+        if let Some(description) = description {
+            body["description"] = description.clone().into();
+            body["text"] = description.into();
+        }
+        self.events.push(("stopped", body));
+    }
+
+    fn flush_output(&mut self) {
+        let session = match self.session.as_mut() {
+            Some(session) => session,
+            None => return,
+        };
+        let output = session.output.bytes();
+        if output.len() > session.sent {
+            let text = String::from_utf8_lossy(&output[session.sent..]).into_owned();
+            session.sent = output.len();
+            self.events.push(("output", object! { category: "stdout", output: text }));
+        }
+    }
+}
+
+// Gives the breakpoints the requested condition, if there's one
+fn with_condition(mut breakpoints: Vec<Breakpoint>, requested: &JsonValue)
+                  -> Result<Vec<Breakpoint>, String> {
+    if let Some(condition) = requested["condition"].as_str().filter(|c| !c.trim().is_empty()) {
+        let condition = Expr::parse(condition)?;
+        for breakpoint in &mut breakpoints {
+            breakpoint.condition = Some(condition.clone());
+        }
+    }
+    Ok(breakpoints)
+}
+
+// Call depth, and where the next instruction is ("file:line", or
+// "function+offset" without line info)
+fn position(debugger: &Debugger) -> (usize, String) {
+    let interpreter = debugger.runtime.interpreter();
+    match debugger.next_op() {
+        Some((frame, _)) => (debugger.runtime.call_stack().len(),
+                             interpreter.location_at(frame.id, frame.code.offset(frame.bc_counter))),
+        None => (0, String::new()),
+    }
+}
+
+// Until the next line, over calls or into them
+fn step_line(debugger: &mut Debugger, over: bool) -> Stop {
+    let (depth, location) = position(debugger);
+    loop {
+        let stop = debugger.step(1);
+        if stop != Stop::Step {
+            return stop;
+        }
+        if let Some(index) = debugger.breakpoint_hit() {
+            return Stop::Breakpoint(index);
+        }
+        let (new_depth, new_location) = position(debugger);
+        if new_depth < depth || (new_depth == depth && new_location != location) ||
+            (new_depth > depth && !over) {
+            return Stop::Step;
+        }
+    }
+}
+
+// Back to the start of the previous line, over calls
+fn step_back_line(debugger: &mut Debugger) -> Stop {
+    let (depth, location) = position(debugger);
+    // The last instruction of the previous line...
+    let found = debugger.reverse_until(|debugger| {
+        let (new_depth, new_location) = position(debugger);
+        new_depth < depth || (new_depth == depth && new_location != location)
+    });
+    match found {
+        Ok(true) => {},
+        Ok(false) => return Stop::Start,
+        Err(e) => return Stop::Rewind(e),
+    }
+    // ...and the first one, after whatever came before it at its depth
+    let target = position(debugger);
+    let found = debugger.reverse_until(|debugger| {
+        let (new_depth, new_location) = position(debugger);
+        new_depth < target.0 || (new_depth == target.0 && new_location != target.1)
+    });
+    if let Err(e) = found {
+        return Stop::Rewind(e);
+    }
+    while position(debugger) != target {
+        let stop = debugger.step(1);
+        if stop != Stop::Step {
+            return stop;
+        }
+    }
+    Stop::Step
+}
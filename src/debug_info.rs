@@ -0,0 +1,225 @@
+// Optional debug info, read from the DEBUG section of version 2 modules.
+//
+// Like the constants table it's a list of records, each starting with a
+// DebugInstr byte. Functions are referred to by id, main by MAIN_ID.
+//   FILE     name
+//   LINES    func, count, count * (bytecode offset, line)
+//   LOCALS   func, count, count * name
+//   GLOBALS  count, count * name
+//   END
+// Strings are null byte terminated, everything else is a big endian u32.
+//
+// The section is optional and no header flag marks it: version 1 modules,
+// and version 2 ones without it, load with empty debug info. An unknown tag
+// byte is skipped with a warning and reading goes on from the next byte.
+// With debug info stack traces, error locations and the debugger show
+// file:line and variable names, without it function offsets and bare values.
+
+use std::collections::HashMap;
+use num::FromPrimitive;
+
+use error::LoadError;
+
+#[derive(Debug, Default)]
+pub struct DebugInfo {
+    pub file: Option<String>,
+    // (bytecode offset, line) pairs per function, sorted by offset
+    pub lines: HashMap<u32, Vec<(u32, u32)>>,
+    pub local_names: HashMap<u32, Vec<String>>,
+    pub global_names: Vec<String>,
+}
+
+impl DebugInfo {
+    // Source line of the instruction at `offset` in function `func`
+    pub fn line(&self, func: u32, offset: usize) -> Option<u32> {
+        let lines = self.lines.get(&func)?;
+        match lines.binary_search_by_key(&(offset as u32), |&(o, _)| o) {
+            Ok(i) => Some(lines[i].1),
+            Err(0) => None,
+            Err(i) => Some(lines[i - 1].1),
+        }
+    }
+
+    // "file:line" if we know the line
+    pub fn location(&self, func: u32, offset: usize) -> Option<String> {
+        let line = self.line(func, offset)?;
+        let file = match self.file {
+            Some(ref file) => file.as_str(),
+            None => "<unknown>",
+        };
+        Some(format!("{}:{}", file, line))
+    }
+
+    // Where `line` starts: the first offset with that line in every
+    // function that has it, by function id
+    pub fn find_line(&self, line: u32) -> Vec<(u32, usize)> {
+        let mut found: Vec<(u32, usize)> = self.lines.iter()
+            .filter_map(|(&func, lines)| {
+                lines.iter().find(|&&(_, l)| l == line).map(|&(offset, _)| (func, offset as usize))
+            })
+            .collect();
+        found.sort();
+        found
+    }
+
+    pub fn local_name(&self, func: u32, index: usize) -> Option<&str> {
+        self.local_names.get(&func)
+            .and_then(|names| names.get(index))
+            .map(|name| name.as_str())
+    }
+
+    pub fn global_name(&self, index: usize) -> Option<&str> {
+        self.global_names.get(index).map(|name| name.as_str())
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        let val = *self.data.get(self.pos).ok_or(LoadError::UnexpectedEnd(self.pos))?;
+        self.pos += 1;
+        Ok(val)
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        let bytes = self.data.get(self.pos..self.pos + 4)
+            .ok_or(LoadError::UnexpectedEnd(self.pos))?;
+        self.pos += 4;
+        Ok((bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 |
+           (bytes[2] as u32) << 8 | (bytes[3] as u32))
+    }
+
+    fn string(&mut self) -> Result<String, LoadError> {
+        let len = self.data[self.pos..].iter().position(|&b| b == 0x00)
+            .ok_or(LoadError::UnexpectedEnd(self.data.len()))?;
+        let string = String::from_utf8(self.data[self.pos..self.pos + len].to_vec())
+            .map_err(|_| LoadError::InvalidString(self.pos))?;
+        self.pos += len + 1;
+        Ok(string)
+    }
+
+    // Counts come from the module, don't preallocate with them
+    fn count(&mut self, item_size: usize) -> Result<u32, LoadError> {
+        let count = self.u32()?;
+        if count as u64 * item_size as u64 > (self.data.len() - self.pos) as u64 {
+            return Err(LoadError::UnexpectedEnd(self.data.len()));
+        }
+        Ok(count)
+    }
+}
+
+pub fn read_debug_info(data: &[u8]) -> Result<DebugInfo, LoadError> {
+    let mut info: DebugInfo = Default::default();
+    let mut reader = Reader { data, pos: 0 };
+
+    while reader.pos != data.len() {
+        let byte = reader.u8()?;
+        let instr = match DebugInstr::from_u8(byte) {
+            Some(instr) => instr,
+            None => {
+                warn!("Unimplemented debug info instruction: {:04X}", byte);
+                continue;
+            }
+        };
+
+        match instr {
+            DebugInstr::FILE => {
+                info.file = Some(reader.string()?);
+            },
+            DebugInstr::LINES => {
+                let func = reader.u32()?;
+                let count = reader.count(8)?;
+                let mut lines = Vec::new();
+                for _ in 0..count {
+                    lines.push((reader.u32()?, reader.u32()?));
+                }
+                lines.sort();
+                info.lines.insert(func, lines);
+            },
+            DebugInstr::LOCALS => {
+                let func = reader.u32()?;
+                let count = reader.count(1)?;
+                let mut names = Vec::new();
+                for _ in 0..count {
+                    names.push(reader.string()?);
+                }
+                info.local_names.insert(func, names);
+            },
+            DebugInstr::GLOBALS => {
+                let count = reader.count(1)?;
+                for _ in 0..count {
+                    let name = reader.string()?;
+                    info.global_names.push(name);
+                }
+            },
+            DebugInstr::END => {
+                break;
+            },
+        }
+    }
+
+    debug!("Read debug info for {} functions", info.lines.len());
+    Ok(info)
+}
+
+
+enum_from_primitive! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, PartialEq)]
+    pub enum DebugInstr {
+        FILE = 0xD0,
+        LINES = 0xD1,
+        LOCALS = 0xD2,
+        GLOBALS = 0xD3,
+        END = 0xED
+    }
+}
+
+
+#[cfg(test)]
+mod debug_info_tests {
+    use super::*;
+    use header::{read_header, SectionKind};
+    use assembler::ModuleBuilder;
+
+    fn debug_section(builder: &mut ModuleBuilder) -> Vec<u8> {
+        let data = builder.version(2).build();
+        let header = read_header(&data).unwrap();
+        let section = header.section(SectionKind::DEBUG).unwrap();
+        data[section.offset as usize..][..section.length as usize].to_vec()
+    }
+
+    #[test]
+    fn lookup() {
+        let data = debug_section(ModuleBuilder::new()
+            .debug_file("test.rapt")
+            .debug_lines(0, &[(5, 2), (0, 1), (10, 4)])
+            .debug_locals(0, &["n", "acc"])
+            .debug_globals(&["count"]));
+        let info = read_debug_info(&data).unwrap();
+
+        assert_eq!(info.line(0, 0), Some(1));
+        assert_eq!(info.line(0, 7), Some(2));
+        assert_eq!(info.line(0, 100), Some(4));
+        assert_eq!(info.line(1, 0), None);
+        assert_eq!(info.location(0, 5), Some("test.rapt:2".to_string()));
+        assert_eq!(info.find_line(2), vec![(0, 5)]);
+        assert_eq!(info.find_line(3), vec![]);
+        assert_eq!(info.local_name(0, 1), Some("acc"));
+        assert_eq!(info.local_name(0, 2), None);
+        assert_eq!(info.global_name(0), Some("count"));
+    }
+
+    #[test]
+    fn truncated() {
+        // Cut anywhere inside the record, END is optional
+        let data = debug_section(ModuleBuilder::new().debug_lines(0, &[(0, 1), (5, 2)]));
+        for len in 1..data.len() - 1 {
+            assert!(read_debug_info(&data[..len]).is_err(), "length {}", len);
+        }
+    }
+}
@@ -0,0 +1,1145 @@
+// Interactive debugger, with reverse execution.
+//
+// The program runs one instruction at a time, and every `snapshot_every`
+// steps the debugger keeps a snapshot. Going back to an earlier step restores
+// the last snapshot before it and executes forward from there, with output
+// discarded and input replayed from what the program read the first time, so
+// it takes the exact same path. Reverse continue and "when did this local last
+// change" search backwards the same way, one snapshot interval at a time.
+// Observers only see each step once, the first time it's executed.
+//
+// There are at most `max_snapshots` snapshots: when there would be more,
+// the interval doubles and the snapshots in between are dropped, so going
+// back on a long run executes more but memory stays bounded.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::mem;
+use std::sync::atomic::Ordering;
+
+use decoder::{Code, Op};
+use error::{LoadError, RuntimeError};
+use expr::Expr;
+use interpreter::{StackFrame, MAIN_ID};
+use replay::{Event, Recording, Replay};
+use runtime::{Runtime, Status};
+use snapshot::Snapshot;
+use watch::{Action, Condition, Target, Watchpoint, WatchHit};
+
+pub const DEFAULT_SNAPSHOT_EVERY: u64 = 10_000;
+pub const DEFAULT_MAX_SNAPSHOTS: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+    pub func: u32,
+    // Bytecode offset of the instruction to stop before
+    pub offset: usize,
+    // Only stop if it holds, see expr.rs
+    pub condition: Option<Expr>,
+    // Times execution reached it up to the current step, since it was set
+    pub hits: u64,
+    // `hits` at each snapshot, to set it back when going back
+    hits_at: BTreeMap<u64, u64>,
+}
+
+impl Breakpoint {
+    pub fn new(func: u32, offset: usize) -> Breakpoint {
+        Breakpoint { func, offset, condition: None, hits: 0, hits_at: BTreeMap::new() }
+    }
+
+    fn is_at(&self, frame: &StackFrame) -> bool {
+        self.func == frame.id && self.offset == frame.code.offset(frame.bc_counter)
+    }
+}
+
+// Why the debugger stopped
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    // Went as far as it was asked
+    Step,
+    // Right before the instruction of this breakpoint (its index)
+    Breakpoint(usize),
+    // Right after a write a pausing watchpoint matched
+    Watch(WatchHit),
+    // Back at the oldest step we have a snapshot of
+    Start,
+    // Ctrl-C, see Runtime::interrupt_handle. The program can go on.
+    Interrupted,
+    // The program ended, it can only go back from here
+    Finished,
+    Halted,
+    Error(RuntimeError),
+    // Going back failed, a snapshot didn't fit the runtime
+    Rewind(LoadError),
+}
+
+// When a local last changed, see Debugger::last_change
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    // The STORE executed as step .0 changed it from .1 to .2
+    Store(u64, i32, i32),
+    // It's had its value since the CALL at this step entered the frame
+    Call(u64),
+    // Not since the oldest step we can go back to
+    Unknown,
+}
+
+pub struct Debugger {
+    pub runtime: Runtime,
+    pub breakpoints: Vec<Breakpoint>,
+    pub snapshot_every: u64,
+    pub max_snapshots: usize,
+    // By step
+    snapshots: BTreeMap<u64, Snapshot>,
+    // The furthest step executed so far. Steps before it are executed again.
+    frontier: u64,
+    // Every READ_INT up to the frontier, to replay when executing again
+    timeline: Vec<Event>,
+    // The runtime's own replay mode while we feed it the timeline
+    saved_replay: Option<Replay>,
+    // Set once the program ended
+    ended: Option<Stop>,
+}
+
+impl Debugger {
+    pub fn new(runtime: Runtime) -> Debugger {
+        let mut debugger = Debugger {
+            frontier: runtime.steps(),
+            runtime,
+            breakpoints: Vec::new(),
+            snapshot_every: DEFAULT_SNAPSHOT_EVERY,
+            max_snapshots: DEFAULT_MAX_SNAPSHOTS,
+            snapshots: BTreeMap::new(),
+            timeline: Vec::new(),
+            saved_replay: None,
+            ended: None,
+        };
+        let steps = debugger.steps();
+        debugger.snapshots.insert(steps, debugger.runtime.snapshot());
+        debugger
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.runtime.steps()
+    }
+
+    // How the program ended, if it did
+    pub fn ended(&self) -> Option<&Stop> {
+        self.ended.as_ref()
+    }
+
+    // The innermost frame and the op it executes next
+    pub fn next_op(&self) -> Option<(&StackFrame, Op)> {
+        let frame = self.runtime.call_stack().last()?;
+        frame.code.ops.get(frame.bc_counter).map(|&op| (frame, op))
+    }
+
+    // Index of the breakpoint at the next instruction, if its condition
+    // holds. One that fails to evaluate stops too, so it can be fixed.
+    pub fn breakpoint_hit(&self) -> Option<usize> {
+        let frame = self.runtime.call_stack().last()?;
+        (0..self.breakpoints.len()).find(|&index| {
+            self.breakpoints[index].is_at(frame) && self.check_condition(index) != Ok(false)
+        })
+    }
+
+    // Evaluates the condition of a breakpoint in the innermost frame, true
+    // if it has none
+    pub fn check_condition(&self, index: usize) -> Result<bool, String> {
+        let breakpoint = &self.breakpoints[index];
+        match (breakpoint.condition.as_ref(), self.runtime.call_stack().last()) {
+            (Some(condition), Some(frame)) =>
+                condition.holds(self.runtime.interpreter(), frame, breakpoint.hits),
+            _ => Ok(true),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        let same = |b: &Breakpoint| b.func == breakpoint.func && b.offset == breakpoint.offset &&
+            b.condition == breakpoint.condition;
+        match self.breakpoints.iter().position(same) {
+            Some(index) => index,
+            None => {
+                self.breakpoints.push(breakpoint);
+                self.breakpoints.len() - 1
+            }
+        }
+    }
+
+    fn code(&self, func: u32) -> Option<&Code> {
+        let interpreter = self.runtime.interpreter();
+        if func == MAIN_ID {
+            Some(&interpreter.prog_code)
+        } else {
+            interpreter.function(func).map(|func_const| &*func_const.code)
+        }
+    }
+
+    // Breakpoints for "line", "file:line", "function" or "function+offset"
+    pub fn parse_location(&self, spec: &str) -> Result<Vec<Breakpoint>, String> {
+        let interpreter = self.runtime.interpreter();
+        let line = match spec.rfind(':') {
+            Some(colon) => {
+                let file = &spec[..colon];
+                if interpreter.debug_info.file.as_deref() != Some(file) {
+                    return Err(format!("No line info for {}", file));
+                }
+                Some(&spec[colon + 1..])
+            },
+            None if spec.starts_with(|c: char| c.is_ascii_digit()) => Some(spec),
+            None => None,
+        };
+        if let Some(line) = line {
+            let line = line.parse().map_err(|_| format!("Invalid line {}", line))?;
+            let found = interpreter.debug_info.find_line(line);
+            if found.is_empty() {
+                return Err(format!("No code at line {}", line));
+            }
+            return Ok(found.into_iter().map(|(func, offset)| Breakpoint::new(func, offset)).collect());
+        }
+
+        let (name, offset) = match spec.find('+') {
+            Some(plus) => {
+                let offset = &spec[plus + 1..];
+                (&spec[..plus], offset.parse().map_err(|_| format!("Invalid offset {}", offset))?)
+            },
+            None => (spec, 0),
+        };
+        let func = match interpreter.function_id(name) {
+            Some(id) => id,
+            None if name == "main" => MAIN_ID,
+            None => return Err(format!("No function named {}", name)),
+        };
+        match self.code(func) {
+            Some(code) if code.offsets.binary_search(&offset).is_ok() =>
+                Ok(vec![Breakpoint::new(func, offset)]),
+            _ => Err(format!("No instruction at {}+{}", name, offset)),
+        }
+    }
+
+    // "step N: location: op", or how the program ended
+    pub fn describe(&self) -> String {
+        let interpreter = self.runtime.interpreter();
+        match self.next_op() {
+            Some((frame, op)) => {
+                let offset = frame.code.offset(frame.bc_counter);
+                format!("step {}: {}: {:?}", self.steps(),
+                        interpreter.location_at(frame.id, offset), op.instruction())
+            },
+            None => format!("step {}: the program ended", self.steps()),
+        }
+    }
+
+    // One instruction forward, Some if the program ended
+    fn step_once(&mut self) -> Option<Stop> {
+        if let Some(ref stop) = self.ended {
+            return Some(stop.clone());
+        }
+        self.end_replay();
+
+        let read = matches!(self.next_op(), Some((_, Op::READ_INT)));
+        // Output was already written and observers told the first time
+        let again = self.steps() < self.frontier;
+        let output = if again {
+            self.runtime.interpreter_mut().output.replace(Box::new(io::sink()))
+        } else {
+            None
+        };
+        self.runtime.interpreter_mut().mute_observers = again;
+        let result = self.runtime.step();
+        self.runtime.interpreter_mut().mute_observers = false;
+        if again {
+            self.runtime.interpreter_mut().output = output;
+        }
+
+        let steps = self.steps();
+        if read && result.is_ok() && steps > self.frontier {
+            let stack = &self.runtime.interpreter().op_stack;
+            let (value, status) = (stack[stack.len() - 2], stack[stack.len() - 1]);
+            self.timeline.push(Event::ReadInt { step: steps, value, status });
+        }
+        self.frontier = self.frontier.max(steps);
+
+        let stop = match result {
+            Ok(Status::Paused) => None,
+            Ok(Status::Finished) => Some(Stop::Finished),
+            Ok(Status::Halted) => Some(Stop::Halted),
+            Ok(Status::Interrupted) => return Some(Stop::Interrupted),
+            Err(e) => Some(Stop::Error(e)),
+        };
+        if stop.is_none() {
+            if let Some(frame) = self.runtime.call_stack().last() {
+                for breakpoint in self.breakpoints.iter_mut().filter(|b| b.is_at(frame)) {
+                    breakpoint.hits += 1;
+                }
+            }
+        }
+        if stop.is_none() && steps % self.snapshot_every.max(1) == 0 {
+            if !self.snapshots.contains_key(&steps) {
+                self.snapshots.insert(steps, self.runtime.snapshot());
+            }
+            for breakpoint in &mut self.breakpoints {
+                breakpoint.hits_at.insert(steps, breakpoint.hits);
+            }
+            if self.snapshots.len() > self.max_snapshots {
+                self.thin_snapshots();
+            }
+        }
+        self.ended = stop.clone();
+        stop
+    }
+
+    // Like step_once, but also stops at watchpoints
+    fn step_watched(&mut self) -> Option<Stop> {
+        match self.step_once() {
+            None => self.runtime.watch_hit().map(Stop::Watch),
+            stop => stop,
+        }
+    }
+
+    // Gives the runtime its own input back once the timeline is used up
+    fn end_replay(&mut self) {
+        let done = match self.runtime.interpreter().replay {
+            Replay::Replay(ref recording) => recording.events.is_empty(),
+            _ => false,
+        };
+        if done {
+            if let Some(replay) = self.saved_replay.take() {
+                self.runtime.interpreter_mut().replay = replay;
+            }
+        }
+    }
+
+    // Doubles the interval until there are few enough snapshots. The oldest
+    // stays, it's as far back as we can go.
+    fn thin_snapshots(&mut self) {
+        let oldest = self.oldest();
+        while self.snapshots.len() > self.max_snapshots.max(2) {
+            self.snapshot_every = self.snapshot_every.max(1).saturating_mul(2);
+            let every = self.snapshot_every;
+            let keep = |step: u64| step == oldest || step % every == 0;
+            self.snapshots.retain(|&step, _| keep(step));
+            for breakpoint in &mut self.breakpoints {
+                breakpoint.hits_at.retain(|&step, _| keep(step));
+            }
+        }
+    }
+
+    fn rewind(&mut self, snapshot: u64) -> Result<(), LoadError> {
+        self.runtime.rewind(&self.snapshots[&snapshot])?;
+        self.ended = None;
+        // Not set yet at that step if it isn't there
+        for breakpoint in &mut self.breakpoints {
+            breakpoint.hits = breakpoint.hits_at.get(&snapshot).cloned().unwrap_or(0);
+        }
+        let events = self.timeline.iter().filter(|e| e.step() > snapshot).cloned().collect();
+        let recording = Recording { args: Vec::new(), events };
+        let replay = mem::replace(&mut self.runtime.interpreter_mut().replay,
+                                  Replay::Replay(recording));
+        if self.saved_replay.is_none() {
+            self.saved_replay = Some(replay);
+        }
+        Ok(())
+    }
+
+    fn oldest(&self) -> u64 {
+        *self.snapshots.keys().next().unwrap()
+    }
+
+    // To the state after `step` instructions, forward or backward
+    pub fn goto(&mut self, step: u64) -> Stop {
+        self.try_goto(step).unwrap_or_else(Stop::Rewind)
+    }
+
+    fn try_goto(&mut self, step: u64) -> Result<Stop, LoadError> {
+        if step < self.steps() {
+            let snapshot = match self.snapshots.range(..=step).next_back() {
+                Some((&snapshot, _)) => snapshot,
+                None => {
+                    let oldest = self.oldest();
+                    self.rewind(oldest)?;
+                    return Ok(Stop::Start);
+                }
+            };
+            self.rewind(snapshot)?;
+        }
+        while self.steps() < step {
+            if let Some(stop) = self.step_once() {
+                return Ok(stop);
+            }
+        }
+        Ok(Stop::Step)
+    }
+
+    pub fn step(&mut self, count: u64) -> Stop {
+        for _ in 0..count {
+            if let Some(stop) = self.step_watched() {
+                return stop;
+            }
+        }
+        Stop::Step
+    }
+
+    // Until the call stack is at most `depth` frames deep, or a breakpoint
+    fn step_until_depth(&mut self, depth: usize) -> Stop {
+        loop {
+            if let Some(stop) = self.step_watched() {
+                return stop;
+            }
+            if self.runtime.call_stack().len() <= depth {
+                return Stop::Step;
+            }
+            if let Some(index) = self.breakpoint_hit() {
+                return Stop::Breakpoint(index);
+            }
+        }
+    }
+
+    // Steps over calls
+    pub fn step_over(&mut self) -> Stop {
+        let depth = self.runtime.call_stack().len();
+        self.step_until_depth(depth)
+    }
+
+    // Out of the current frame
+    pub fn finish(&mut self) -> Stop {
+        let depth = self.runtime.call_stack().len();
+        self.step_until_depth(depth.saturating_sub(1))
+    }
+
+    pub fn cont(&mut self) -> Stop {
+        loop {
+            if let Some(stop) = self.step_watched() {
+                return stop;
+            }
+            if let Some(index) = self.breakpoint_hit() {
+                return Stop::Breakpoint(index);
+            }
+        }
+    }
+
+    pub fn reverse_step(&mut self, count: u64) -> Stop {
+        let oldest = self.oldest();
+        if count > self.steps() - oldest {
+            self.goto(oldest);
+            return Stop::Start;
+        }
+        let step = self.steps() - count;
+        self.goto(step)
+    }
+
+    // Back to the last breakpoint or pausing watchpoint hit before the
+    // current step, where cont() would have stopped
+    pub fn reverse_cont(&mut self) -> Stop {
+        let found = self.reverse_until(|debugger| {
+            debugger.breakpoint_hit().is_some() || debugger.runtime.watch_hit().is_some()
+        });
+        match found {
+            Ok(true) => {},
+            Ok(false) => return Stop::Start,
+            Err(e) => return Stop::Rewind(e),
+        }
+        match (self.runtime.watch_hit(), self.breakpoint_hit()) {
+            (Some(hit), _) => Stop::Watch(hit),
+            (None, Some(index)) => Stop::Breakpoint(index),
+            // Landed on a snapshot right after the write, so the hit is gone
+            (None, None) => Stop::Step,
+        }
+    }
+
+    // Back to the last step before the current one where `f` is true, or to
+    // the oldest step if there's none. Returns whether it found one.
+    pub fn reverse_until<F: FnMut(&Debugger) -> bool>(&mut self, f: F) -> Result<bool, LoadError> {
+        let end = self.steps();
+        match self.search_back(end, f)? {
+            Some(step) => {
+                self.try_goto(step)?;
+                Ok(true)
+            },
+            None => {
+                let oldest = self.oldest();
+                self.try_goto(oldest)?;
+                Ok(false)
+            },
+        }
+    }
+
+    // The last step before `end` where `f` is true (called before executing
+    // each step), going back one snapshot interval at a time. Leaves the
+    // runtime somewhere before `end`.
+    fn search_back<F: FnMut(&Debugger) -> bool>(&mut self, end: u64, mut f: F)
+                                                  -> Result<Option<u64>, LoadError> {
+        let starts: Vec<u64> = self.snapshots.range(..end).map(|(&step, _)| step).rev().collect();
+        let mut interval_end = end;
+        for start in starts {
+            self.rewind(start)?;
+            let mut found = None;
+            while self.steps() < interval_end {
+                if f(self) {
+                    found = Some(self.steps());
+                }
+                if self.step_once().is_some() {
+                    break;
+                }
+            }
+            if found.is_some() {
+                return Ok(found);
+            }
+            interval_end = start;
+        }
+        Ok(None)
+    }
+
+    // When local `index` of the innermost frame last changed. Stays at the current step.
+    pub fn last_change(&mut self, index: usize) -> Result<Change, LoadError> {
+        let end = self.steps();
+        let depth = self.runtime.call_stack().len();
+        match self.runtime.call_stack().last() {
+            Some(frame) if index < frame.locals.len() => {},
+            _ => return Ok(Change::Unknown),
+        }
+
+        let starts: Vec<u64> = self.snapshots.range(..end).map(|(&step, _)| step).rev().collect();
+        let mut interval_end = end;
+        let mut change = Change::Unknown;
+        for start in starts {
+            self.rewind(start)?;
+            let mut found = None;
+            while self.steps() < interval_end {
+                let before = self.runtime.call_stack().len();
+                let old = match self.next_op() {
+                    Some((frame, Op::STORE(i))) if i as usize == index && before == depth =>
+                        Some(frame.locals[index]),
+                    _ => None,
+                };
+                if self.step_once().is_some() {
+                    break;
+                }
+                let call_stack = self.runtime.call_stack();
+                if let Some(old) = old {
+                    let new = call_stack[depth - 1].locals[index];
+                    if new != old {
+                        found = Some(Change::Store(self.steps(), old, new));
+                    }
+                }
+                // Another activation of the frame, what came before doesn't count
+                if before < depth && call_stack.len() >= depth {
+                    found = Some(Change::Call(self.steps()));
+                }
+            }
+            if let Some(found) = found {
+                change = found;
+                break;
+            }
+            interval_end = start;
+        }
+        self.try_goto(end)?;
+        Ok(change)
+    }
+
+    // Resolves a local of the innermost frame by name or index
+    fn local_index(&self, spec: &str) -> Option<usize> {
+        let frame = self.runtime.call_stack().last()?;
+        let index = match spec.parse() {
+            Ok(index) => index,
+            Err(_) => (0..frame.locals.len()).find(|&i| {
+                self.runtime.interpreter().debug_info.local_name(frame.id, i) == Some(spec)
+            })?,
+        };
+        if index < frame.locals.len() {Some(index)} else {None}
+    }
+
+    // "global N" or a global's name, "function.local" (name or index), or a
+    // local of the innermost frame
+    pub fn parse_target(&self, spec: &str) -> Result<Target, String> {
+        let interpreter = self.runtime.interpreter();
+        let global = |name: &str| (0..interpreter.globals().len()).find(|&i| {
+            interpreter.debug_info.global_name(i) == Some(name)
+        });
+        if let Some(index) = spec.strip_prefix("global ") {
+            let index = index.trim();
+            return match index.parse() {
+                Ok(index) if index < interpreter.globals().len() => Ok(Target::Global(index)),
+                _ => Err(format!("No global {}", index)),
+            };
+        }
+        if let Some(dot) = spec.find('.') {
+            let (name, local) = (&spec[..dot], &spec[dot + 1..]);
+            let (func, count) = match interpreter.function_id(name) {
+                Some(id) => {
+                    let func_const = interpreter.function(id).unwrap();
+                    (id, (func_const.arg_count + func_const.local_count) as usize)
+                },
+                None => return Err(format!("No function named {}", name)),
+            };
+            let index = match local.parse() {
+                Ok(index) => Some(index),
+                Err(_) => (0..count).find(|&i| interpreter.debug_info.local_name(func, i) == Some(local)),
+            };
+            return match index {
+                Some(index) if index < count => Ok(Target::Local(func, index)),
+                _ => Err(format!("No local {} in {}", local, name)),
+            };
+        }
+        if let Some(index) = self.local_index(spec) {
+            let func = self.runtime.call_stack().last().unwrap().id;
+            return Ok(Target::Local(func, index));
+        }
+        match global(spec) {
+            Some(index) => Ok(Target::Global(index)),
+            None => Err(format!("No local or global named {}", spec)),
+        }
+    }
+
+    // Reads commands with `read_line` until it returns 0 or "quit". Empty lines
+    // don't repeat the last command: the program reads from the same input,
+    // and leaves the end of the line it read an int from.
+    pub fn repl(&mut self, read_line: &mut dyn FnMut(&mut String) -> io::Result<usize>,
+                out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "{}", self.describe())?;
+        loop {
+            write!(out, "(rdb) ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            // A Ctrl-C at the prompt isn't for the command
+            self.runtime.interrupt_handle().store(false, Ordering::Relaxed);
+            if !self.command(&line, out)? {
+                return Ok(());
+            }
+        }
+    }
+
+    // Runs a single command, false if it was "quit"
+    pub fn command(&mut self, line: &str, out: &mut dyn Write) -> io::Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (&command, args) = match words.split_first() {
+            Some(split) => split,
+            None => return Ok(true),
+        };
+        let counted = ["s", "step", "rs", "reverse-step", "goto"].contains(&command);
+        let count = match args.first().map(|arg| arg.parse::<u64>()) {
+            Some(Ok(count)) => count,
+            Some(Err(_)) if counted => {
+                writeln!(out, "Invalid count {}", args[0])?;
+                return Ok(true);
+            },
+            _ => 1,
+        };
+
+        let stop = match command {
+            "s" | "step" => self.step(count),
+            "n" | "next" => self.step_over(),
+            "finish" => self.finish(),
+            "c" | "continue" => self.cont(),
+            "rs" | "reverse-step" => self.reverse_step(count),
+            "rc" | "reverse-continue" => self.reverse_cont(),
+            "goto" => match args.first() {
+                Some(_) => self.goto(count),
+                None => {
+                    writeln!(out, "Usage: goto STEP")?;
+                    return Ok(true);
+                },
+            },
+            "b" | "break" => {
+                let condition = match args.get(1..) {
+                    Some(&[]) | None => None,
+                    Some(&["if", ref condition @ ..]) if !condition.is_empty() =>
+                        match Expr::parse(&condition.join(" ")) {
+                            Ok(condition) => Some(condition),
+                            Err(e) => {
+                                writeln!(out, "{}", e)?;
+                                return Ok(true);
+                            },
+                        },
+                    Some(_) => {
+                        writeln!(out, "Usage: break LOCATION [if CONDITION]")?;
+                        return Ok(true);
+                    },
+                };
+                match args.first().map(|spec| self.parse_location(spec)) {
+                    Some(Ok(breakpoints)) => for mut breakpoint in breakpoints {
+                        breakpoint.condition = condition.clone();
+                        let index = self.add_breakpoint(breakpoint);
+                        writeln!(out, "Breakpoint {} at {}", index, self.describe_breakpoint(index))?;
+                    },
+                    Some(Err(e)) => writeln!(out, "{}", e)?,
+                    None => writeln!(out, "Usage: break LOCATION [if CONDITION]")?,
+                }
+                return Ok(true);
+            },
+            "condition" => {
+                let index = match args.first().and_then(|arg| arg.parse::<usize>().ok()) {
+                    Some(index) if index < self.breakpoints.len() => index,
+                    _ => {
+                        writeln!(out, "Usage: condition BREAKPOINT [CONDITION]")?;
+                        return Ok(true);
+                    },
+                };
+                if args.len() == 1 {
+                    self.breakpoints[index].condition = None;
+                    return Ok(true);
+                }
+                match Expr::parse(&args[1..].join(" ")) {
+                    Ok(condition) => self.breakpoints[index].condition = Some(condition),
+                    Err(e) => writeln!(out, "{}", e)?,
+                }
+                return Ok(true);
+            },
+            "w" | "watch" => {
+                // watch TARGET [CONDITION] [log], the target can be two words
+                let (target, rest) = match args {
+                    ["global", index, rest @ ..] => (format!("global {}", index), rest),
+                    [target, rest @ ..] => (target.to_string(), rest),
+                    [] => {
+                        writeln!(out, "Usage: watch TARGET [CONDITION] [log]")?;
+                        return Ok(true);
+                    },
+                };
+                let (action, rest) = match rest.split_last() {
+                    Some((&"log", rest)) => (Action::Log, rest),
+                    _ => (Action::Pause, rest),
+                };
+                let condition = match rest.join(" ") {
+                    ref condition if condition.is_empty() => None,
+                    condition => match Condition::parse(&condition) {
+                        Some(condition) => Some(condition),
+                        None => {
+                            writeln!(out, "Invalid condition {}", condition)?;
+                            return Ok(true);
+                        },
+                    },
+                };
+                match self.parse_target(&target) {
+                    Ok(target) => {
+                        let watchpoint = Watchpoint { target, condition, action };
+                        let index = self.runtime.add_watchpoint(watchpoint);
+                        writeln!(out, "Watchpoint {}: {}", index, self.describe_watchpoint(index))?;
+                    },
+                    Err(e) => writeln!(out, "{}", e)?,
+                }
+                return Ok(true);
+            },
+            "unwatch" => {
+                let watchpoints = &mut self.runtime.interpreter_mut().watchpoints;
+                match args.first().and_then(|arg| arg.parse::<usize>().ok()) {
+                    Some(index) if index < watchpoints.len() => {
+                        watchpoints.remove(index);
+                    },
+                    _ => writeln!(out, "Usage: unwatch WATCHPOINT")?,
+                }
+                return Ok(true);
+            },
+            "delete" => {
+                match args.first().and_then(|arg| arg.parse::<usize>().ok()) {
+                    Some(index) if index < self.breakpoints.len() => {
+                        self.breakpoints.remove(index);
+                    },
+                    _ => writeln!(out, "Usage: delete BREAKPOINT")?,
+                }
+                return Ok(true);
+            },
+            "i" | "info" => {
+                for i in 0..self.breakpoints.len() {
+                    writeln!(out, "Breakpoint {} at {}, hit {} times", i,
+                             self.describe_breakpoint(i), self.breakpoints[i].hits)?;
+                }
+                for i in 0..self.runtime.interpreter().watchpoints.len() {
+                    writeln!(out, "Watchpoint {}: {}", i, self.describe_watchpoint(i))?;
+                }
+                return Ok(true);
+            },
+            "bt" | "backtrace" => {
+                for line in self.runtime.stack_trace() {
+                    writeln!(out, "{}", line)?;
+                }
+                return Ok(true);
+            },
+            "p" | "print" => {
+                let interpreter = self.runtime.interpreter();
+                if let Some(frame) = self.runtime.call_stack().last() {
+                    writeln!(out, "{}", interpreter.describe_frame(frame))?;
+                }
+                writeln!(out, "stack {:?}", interpreter.op_stack)?;
+                writeln!(out, "globals {}", interpreter.named_globals())?;
+                return Ok(true);
+            },
+            "last-change" => {
+                let index = match args.first().and_then(|spec| self.local_index(spec)) {
+                    Some(index) => index,
+                    None => {
+                        writeln!(out, "Usage: last-change LOCAL (name or index)")?;
+                        return Ok(true);
+                    },
+                };
+                match self.last_change(index) {
+                    Ok(Change::Store(step, old, new)) =>
+                        writeln!(out, "Changed from {} to {} at step {}", old, new, step)?,
+                    Ok(Change::Call(step)) =>
+                        writeln!(out, "Unchanged since the frame was entered at step {}", step)?,
+                    Ok(Change::Unknown) =>
+                        writeln!(out, "Unchanged since step {}", self.oldest())?,
+                    Err(e) => writeln!(out, "Couldn't go back: {}", e)?,
+                }
+                return Ok(true);
+            },
+            "q" | "quit" => return Ok(false),
+            "h" | "help" => {
+                writeln!(out, "{}", HELP)?;
+                return Ok(true);
+            },
+            _ => {
+                writeln!(out, "Unknown command {}, try help", command)?;
+                return Ok(true);
+            },
+        };
+        self.report(&stop, out)?;
+        Ok(true)
+    }
+
+    // Like "sum.rapt:4 if acc > 10"
+    fn describe_breakpoint(&self, index: usize) -> String {
+        let breakpoint = &self.breakpoints[index];
+        let location = self.runtime.interpreter().location_at(breakpoint.func, breakpoint.offset);
+        match breakpoint.condition {
+            Some(ref condition) => format!("{} if {}", location, condition),
+            None => location,
+        }
+    }
+
+    // Like "f.acc > 10, log"
+    fn describe_watchpoint(&self, index: usize) -> String {
+        let interpreter = self.runtime.interpreter();
+        let watchpoint = &interpreter.watchpoints[index];
+        let mut description = watchpoint.target.describe(interpreter);
+        if let Some(condition) = watchpoint.condition {
+            description += &format!(" {}", condition);
+        }
+        if watchpoint.action == Action::Log {
+            description += ", log";
+        }
+        description
+    }
+
+    fn report(&self, stop: &Stop, out: &mut dyn Write) -> io::Result<()> {
+        match *stop {
+            Stop::Step => {},
+            Stop::Breakpoint(index) => match self.check_condition(index) {
+                Err(e) => writeln!(out, "Breakpoint {}, its condition failed: {}", index, e)?,
+                Ok(_) => writeln!(out, "Breakpoint {}", index)?,
+            },
+            Stop::Watch(hit) => writeln!(out, "Watchpoint {}: {} changed from {} to {}", hit.index,
+                                         hit.target.describe(self.runtime.interpreter()),
+                                         hit.old, hit.new)?,
+            Stop::Start => writeln!(out, "Can't go back any further")?,
+            Stop::Interrupted => writeln!(out, "Interrupted")?,
+            Stop::Finished => match self.runtime.return_value() {
+                Some(value) => writeln!(out, "The program finished, returning {}", value)?,
+                None => writeln!(out, "The program finished")?,
+            },
+            Stop::Halted => writeln!(out, "The program halted")?,
+            Stop::Error(ref e) => match self.runtime.location() {
+                Some(location) => writeln!(out, "{}: Runtime error: {}", location, e)?,
+                None => writeln!(out, "Runtime error: {}", e)?,
+            },
+            Stop::Rewind(ref e) => writeln!(out, "Couldn't go back: {}", e)?,
+        }
+        writeln!(out, "{}", self.describe())
+    }
+}
+
+const HELP: &str = "\
+step, s [N]             execute N instructions
+next, n                 execute an instruction, stepping over calls
+finish                  run until the current function returns
+continue, c             run until a breakpoint, a watchpoint or the end
+reverse-step, rs [N]    go back N instructions
+reverse-continue, rc    go back to the last breakpoint or watchpoint hit
+goto STEP               go to the state after STEP instructions
+last-change LOCAL       when the local (name or index) last changed
+break, b LOCATION [if CONDITION]
+                        break at LINE, FILE:LINE or FUNCTION[+OFFSET], only
+                        when CONDITION holds, like \"local[1] > 100 && hits % 10 == 0\"
+condition N [CONDITION] set or clear the condition of breakpoint N
+delete N                delete breakpoint N
+watch, w TARGET [COND] [log]
+                        pause (or log) when TARGET is written, if the new
+                        value passes COND like \"< 0\"; TARGET is a local of
+                        the current frame, FUNCTION.LOCAL or a global by name
+                        or as \"global N\"
+unwatch N               delete watchpoint N
+info, i                 list breakpoints and watchpoints
+backtrace, bt           print the call stack
+print, p                print the current frame, op stack and globals
+quit, q                 stop debugging";
+
+
+#[cfg(test)]
+mod debugger_tests {
+    use std::io::{BufRead, Cursor};
+
+    use super::*;
+    use Options;
+    use assembler::{Assembler, ModuleBuilder};
+    use instructions::Instruction as Instr;
+
+    // f(n) sums n..1 into acc by looping, main calls f(READ_INT) and prints it
+    fn debugger(input: &'static str) -> Debugger {
+        let mut f = Assembler::new();
+        f.line(2).iconst(0).op_with(Instr::STORE, 1)
+            .label("loop")
+            .line(3).op_with(Instr::LOAD, 0).jump(Instr::RELJUMP_EQ, "end")
+            .line(4).op_with(Instr::LOAD, 0).op_with(Instr::LOAD, 1).op(Instr::ADD)
+            .op_with(Instr::STORE, 1)
+            .line(5).iconst(1).op_with(Instr::LOAD, 0).op(Instr::SUB).op_with(Instr::STORE, 0)
+            .jump(Instr::RELJUMP, "loop")
+            .label("end")
+            .line(6).op_with(Instr::LOAD, 1).op(Instr::RETURN);
+        let main = Assembler::new()
+            .op(Instr::READ_INT).op(Instr::POP).op_with(Instr::CALL, 0).op(Instr::PRINT)
+            .finish();
+        let data = ModuleBuilder::new()
+            .version(2)
+            .func(0, "f", 1, 1, &f.finish())
+            .main(&main)
+            .debug_file("sum.rapt")
+            .debug_lines(0, f.lines())
+            .debug_locals(0, &["n", "acc"])
+            .build();
+        let mut runtime = Runtime::new(data, Options::default()).unwrap();
+        runtime.set_input(Box::new(Cursor::new(input)));
+        let mut debugger = Debugger::new(runtime);
+        debugger.snapshot_every = 7;
+        debugger
+    }
+
+    fn locals(debugger: &Debugger) -> Vec<i32> {
+        debugger.runtime.call_stack().last().unwrap().locals.clone()
+    }
+
+    #[test]
+    fn reverse() {
+        let mut debugger = debugger("3");
+        let output = debugger.runtime.capture_output();
+        let mut states = Vec::new();
+        while debugger.step(1) == Stop::Step {
+            states.push((debugger.steps(), debugger.runtime.snapshot().to_bytes()));
+        }
+        assert_eq!(debugger.ended(), Some(&Stop::Finished));
+        assert_eq!(output.contents(), "PRINT: 6\n");
+
+        // Every state again, input replayed and no output repeated
+        for &(step, ref state) in states.iter().rev() {
+            assert_eq!(debugger.reverse_step(debugger.steps() - step), Stop::Step);
+            assert_eq!(debugger.runtime.snapshot().to_bytes(), *state, "{}", step);
+        }
+        assert_eq!(debugger.reverse_step(5), Stop::Start);
+        assert_eq!(debugger.steps(), 0);
+        assert_eq!(debugger.cont(), Stop::Finished);
+        assert_eq!(output.contents(), "PRINT: 6\n");
+    }
+
+    // Steps executed again don't count twice
+    #[test]
+    #[cfg(feature = "observers")]
+    fn observers() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        use stats::Stats;
+
+        let stats = Rc::new(RefCell::new(Stats::new()));
+        let mut debugger = debugger("3");
+        debugger.runtime.add_observer(Box::new(Rc::clone(&stats)));
+        assert_eq!(debugger.cont(), Stop::Finished);
+        let steps = debugger.steps();
+        assert_eq!(stats.borrow().instructions, steps);
+
+        assert_eq!(debugger.goto(3), Stop::Step);
+        assert_eq!(debugger.reverse_cont(), Stop::Start);
+        assert_eq!(debugger.cont(), Stop::Finished);
+        assert_eq!(stats.borrow().instructions, steps);
+        assert_eq!(stats.borrow().count(Instr::CALL), 1);
+    }
+
+    #[test]
+    fn thinning() {
+        let mut debugger = debugger("3");
+        debugger.snapshot_every = 1;
+        debugger.max_snapshots = 4;
+        let mut states = vec![debugger.runtime.snapshot().to_bytes()];
+        while debugger.step(1) == Stop::Step {
+            states.push(debugger.runtime.snapshot().to_bytes());
+            assert!(debugger.snapshots.len() <= 4);
+        }
+        assert_eq!(debugger.snapshot_every, 16);
+        assert_eq!(debugger.snapshots.keys().cloned().collect::<Vec<_>>(), vec![0, 16, 32]);
+
+        // Still every state, from further back
+        for step in (0..states.len()).rev() {
+            assert_eq!(debugger.goto(step as u64), Stop::Step);
+            assert_eq!(debugger.runtime.snapshot().to_bytes(), states[step], "{}", step);
+        }
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut debugger = debugger("3");
+        let breakpoints = debugger.parse_location("sum.rapt:5").unwrap();
+        assert_eq!(breakpoints, vec![Breakpoint::new(0, 36)]);
+        assert_eq!(debugger.parse_location("f+36").unwrap(), breakpoints);
+        assert!(debugger.parse_location("f+37").is_err());
+        assert!(debugger.parse_location("g").is_err());
+        assert!(debugger.parse_location("7").is_err());
+        debugger.add_breakpoint(breakpoints[0].clone());
+
+        let mut n = Vec::new();
+        while debugger.cont() == Stop::Breakpoint(0) {
+            n.push(locals(&debugger));
+        }
+        assert_eq!(n, vec![vec![3, 3], vec![2, 5], vec![1, 6]]);
+
+        assert_eq!(debugger.reverse_cont(), Stop::Breakpoint(0));
+        assert_eq!(locals(&debugger), vec![1, 6]);
+        assert_eq!(debugger.reverse_cont(), Stop::Breakpoint(0));
+        assert_eq!(locals(&debugger), vec![2, 5]);
+        assert_eq!(debugger.cont(), Stop::Breakpoint(0));
+        assert_eq!(locals(&debugger), vec![1, 6]);
+    }
+
+    #[test]
+    fn conditions() {
+        let mut debugger = debugger("10");
+        let mut breakpoint = debugger.parse_location("4").unwrap().remove(0);
+        breakpoint.condition = Some(Expr::parse("n % 2 == 0 && hits > 1").unwrap());
+        debugger.add_breakpoint(breakpoint);
+
+        let mut n = Vec::new();
+        while debugger.cont() == Stop::Breakpoint(0) {
+            n.push((locals(&debugger)[0], debugger.breakpoints[0].hits));
+        }
+        assert_eq!(n, vec![(8, 3), (6, 5), (4, 7), (2, 9)]);
+        assert_eq!(debugger.breakpoints[0].hits, 10);
+
+        // Hits are counted again going back, and forward from there
+        assert_eq!(debugger.reverse_cont(), Stop::Breakpoint(0));
+        assert_eq!((locals(&debugger)[0], debugger.breakpoints[0].hits), (2, 9));
+        assert_eq!(debugger.reverse_cont(), Stop::Breakpoint(0));
+        assert_eq!((locals(&debugger)[0], debugger.breakpoints[0].hits), (4, 7));
+        assert_eq!(debugger.cont(), Stop::Breakpoint(0));
+        assert_eq!((locals(&debugger)[0], debugger.breakpoints[0].hits), (2, 9));
+
+        // Conditions that fail stop, to be fixed
+        debugger.breakpoints[0].condition = Some(Expr::parse("100 / (n - 5) < 0").unwrap());
+        assert_eq!(debugger.goto(0), Stop::Step);
+        assert_eq!(debugger.cont(), Stop::Breakpoint(0));
+        assert_eq!(locals(&debugger)[0], 5);
+        assert_eq!(debugger.check_condition(0), Err("Division by zero".to_string()));
+
+        let mut debugger = self::debugger("3");
+        let mut out = Vec::new();
+        for line in &["break 4 if acc >", "break 4 when acc > 2", "break 4 if acc > 2",
+                      "c", "condition 0 stack[0] == 3",
+                      "c", "info", "condition 0", "c"] {
+            debugger.command(line, &mut out).unwrap();
+        }
+        assert_eq!(String::from_utf8(out).unwrap(), "\
+Unexpected end of condition
+Usage: break LOCATION [if CONDITION]
+Breakpoint 0 at sum.rapt:4 if acc > 2
+Breakpoint 0
+step 18: sum.rapt:4: LOAD
+Breakpoint 0, its condition failed: Only 0 values on the stack
+step 29: sum.rapt:4: LOAD
+Breakpoint 0 at sum.rapt:4 if stack[0] == 3, hit 3 times
+The program finished
+step 43: the program ended
+");
+    }
+
+    #[test]
+    fn last_change() {
+        let mut debugger = debugger("3");
+        // Before n = n - 1
+        debugger.add_breakpoint(Breakpoint::new(0, 46));
+        assert_eq!(debugger.cont(), Stop::Breakpoint(0));
+        assert_eq!(debugger.last_change(1), Ok(Change::Store(11, 0, 3)));
+        // Never stored to in this call, it's the argument
+        assert_eq!(debugger.last_change(0), Ok(Change::Call(3)));
+
+        assert_eq!(debugger.cont(), Stop::Breakpoint(0));
+        let steps = debugger.steps();
+        assert_eq!(debugger.last_change(1), Ok(Change::Store(22, 3, 5)));
+        assert_eq!(debugger.last_change(0), Ok(Change::Store(15, 3, 2)));
+        assert_eq!(debugger.last_change(2), Ok(Change::Unknown));
+        assert_eq!(debugger.steps(), steps);
+        assert_eq!(locals(&debugger), vec![2, 5]);
+    }
+
+    #[test]
+    fn repl() {
+        let mut debugger = debugger("2");
+        let output = debugger.runtime.capture_output();
+        let mut input = Cursor::new("break 4\nc\nc\nlast-change acc\nrs\np\nbt\nc\nc\nq\n");
+        let mut out = Vec::new();
+        debugger.repl(&mut |line| input.read_line(line), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "\
+step 0: main+0: READ_INT
+(rdb) Breakpoint 0 at sum.rapt:4
+(rdb) Breakpoint 0
+step 7: sum.rapt:4: LOAD
+(rdb) Breakpoint 0
+step 18: sum.rapt:4: LOAD
+(rdb) Changed from 0 to 2 at step 11
+(rdb) step 17: sum.rapt:3: RELJUMP_EQ
+(rdb) f (id 0), offset 10 (sum.rapt:3), args [n = 1], locals [acc = 2]
+stack [1]
+globals []
+(rdb) #0 f (id 0), offset 10 (sum.rapt:3), args [n = 1], locals [acc = 2]
+#1 main, offset 2
+(rdb) Breakpoint 0
+step 18: sum.rapt:4: LOAD
+(rdb) The program finished
+step 32: the program ended
+(rdb) ");
+        assert_eq!(output.contents(), "PRINT: 3\n");
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut debugger = debugger("3");
+        let mut input = Cursor::new("\
+watch acc
+watch f.acc > 4
+watch f.n <= 1 log
+watch global 0
+c
+c
+rc
+info
+unwatch 0
+c
+q
+");
+        let mut out = Vec::new();
+        debugger.repl(&mut |line| input.read_line(line), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "\
+step 0: main+0: READ_INT
+(rdb) No local or global named acc
+(rdb) Watchpoint 0: f.acc > 4
+(rdb) Watchpoint 1: f.n <= 1, log
+(rdb) No global 0
+(rdb) Watchpoint 0: f.acc changed from 3 to 5
+step 22: sum.rapt:5: ICONST
+(rdb) Watchpoint 0: f.acc changed from 5 to 6
+step 33: sum.rapt:5: ICONST
+(rdb) Watchpoint 0: f.acc changed from 3 to 5
+step 22: sum.rapt:5: ICONST
+(rdb) Watchpoint 0: f.acc > 4
+Watchpoint 1: f.n <= 1, log
+(rdb) (rdb) The program finished
+step 43: the program ended
+(rdb) ");
+    }
+}
@@ -0,0 +1,282 @@
+// Load-time translation of bytecode into an array of decoded ops, so that
+// dispatch doesn't have to decode opcodes and reassemble operands on every step.
+
+use num::FromPrimitive;
+
+use instructions::Instruction as Instr;
+use error::LoadError;
+
+// Same instructions as `Instruction`, with their operands resolved.
+// Jump targets and the bytecode counter are indices into `Code::ops`.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    NOP,
+    HALT,
+    ICONST(i32),
+    POP,
+    ADD,
+    SUB,
+    MULTIPLY,
+    DIVIDE,
+    MODULUS,
+    AND,
+    OR,
+    NOT,
+    RSHIFT,
+    LSHIFT,
+    COMP,
+    COMP_LT,
+    COMP_EQ,
+    COMP_GT,
+    RELJUMP(u32),
+    RELJUMP_GT(u32),
+    RELJUMP_LT(u32),
+    RELJUMP_EQ(u32),
+    STORE(u32),
+    LOAD(u32),
+    STOREFIELD,
+    LOADFIELD,
+    VECTORSTORE,
+    VECTORLOAD,
+    CALL(u32),
+    RETURN,
+    PRINT,
+    READ_INT,
+    ARGC,
+    ARG_INT,
+    DUMP_STACK,
+    DUMP_GLOBALS,
+}
+
+impl Op {
+    // The Instruction this op was decoded from (NOP for unknown opcodes)
+    pub fn instruction(&self) -> Instr {
+        match *self {
+            Op::NOP => Instr::NOP,
+            Op::HALT => Instr::HALT,
+            Op::ICONST(_) => Instr::ICONST,
+            Op::POP => Instr::POP,
+            Op::ADD => Instr::ADD,
+            Op::SUB => Instr::SUB,
+            Op::MULTIPLY => Instr::MULTIPLY,
+            Op::DIVIDE => Instr::DIVIDE,
+            Op::MODULUS => Instr::MODULUS,
+            Op::AND => Instr::AND,
+            Op::OR => Instr::OR,
+            Op::NOT => Instr::NOT,
+            Op::RSHIFT => Instr::RSHIFT,
+            Op::LSHIFT => Instr::LSHIFT,
+            Op::COMP => Instr::COMP,
+            Op::COMP_LT => Instr::COMP_LT,
+            Op::COMP_EQ => Instr::COMP_EQ,
+            Op::COMP_GT => Instr::COMP_GT,
+            Op::RELJUMP(_) => Instr::RELJUMP,
+            Op::RELJUMP_GT(_) => Instr::RELJUMP_GT,
+            Op::RELJUMP_LT(_) => Instr::RELJUMP_LT,
+            Op::RELJUMP_EQ(_) => Instr::RELJUMP_EQ,
+            Op::STORE(_) => Instr::STORE,
+            Op::LOAD(_) => Instr::LOAD,
+            Op::STOREFIELD => Instr::STOREFIELD,
+            Op::LOADFIELD => Instr::LOADFIELD,
+            Op::VECTORSTORE => Instr::VECTORSTORE,
+            Op::VECTORLOAD => Instr::VECTORLOAD,
+            Op::CALL(_) => Instr::CALL,
+            Op::RETURN => Instr::RETURN,
+            Op::PRINT => Instr::PRINT,
+            Op::READ_INT => Instr::READ_INT,
+            Op::ARGC => Instr::ARGC,
+            Op::ARG_INT => Instr::ARG_INT,
+            Op::DUMP_STACK => Instr::DUMP_STACK,
+            Op::DUMP_GLOBALS => Instr::DUMP_GLOBALS,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Code {
+    pub ops: Vec<Op>,
+    // Bytecode offset each op was decoded from
+    pub offsets: Vec<usize>,
+    // Length of the bytecode
+    pub len: usize,
+}
+
+impl Code {
+    // Bytecode offset of the op at `index`. The index one past the last op,
+    // where jumps to the end of the bytecode go, is at `len`.
+    pub fn offset(&self, index: usize) -> usize {
+        match self.offsets.get(index) {
+            Some(&offset) => offset,
+            None => self.len,
+        }
+    }
+}
+
+fn read_u32(bytecode: &[u8], at: usize) -> Result<u32, LoadError> {
+    if at + 4 > bytecode.len() {
+        return Err(LoadError::UnexpectedEnd(at));
+    }
+    Ok((bytecode[at] as u32) << 24 |
+       (bytecode[at + 1] as u32) << 16 |
+       (bytecode[at + 2] as u32) << 8 |
+       (bytecode[at + 3] as u32))
+}
+
+pub fn decode(bytecode: &[u8]) -> Result<Code, LoadError> {
+    let mut code = Code { len: bytecode.len(), ..Default::default() };
+    // Jumps are decoded with their target bytecode offset first,
+    // then patched to op indices once every op's offset is known
+    let mut jumps: Vec<(usize, usize, usize)> = Vec::new();
+
+    let mut bc_counter = 0;
+    while bc_counter != bytecode.len() {
+        let start = bc_counter;
+        bc_counter += 1;
+
+        let instr = match Instr::from_u8(bytecode[start]) {
+            Some(instr) => instr,
+            None => {
+                // Unknown opcodes were always skipped, keep doing that
+                warn!("Unimplemented instruction: {:04X}", bytecode[start]);
+                code.ops.push(Op::NOP);
+                code.offsets.push(start);
+                continue;
+            },
+        };
+
+        macro_rules! operand {
+            () => ({
+                let val = read_u32(bytecode, bc_counter)?;
+                bc_counter += 4;
+                val
+            })
+        }
+        macro_rules! jump {
+            ($op:ident) => ({
+                // Offsets are relative to the last byte of the jump
+                let offset = operand!() as i32 as i64;
+                let target = start as i64 + 4 + offset;
+                if target < 0 || target > bytecode.len() as i64 {
+                    return Err(LoadError::InvalidJump(start, target));
+                }
+                if target as usize == bc_counter {
+                    warn!("RELJUMP to the next instruction is redundant. This is a compiler bug");
+                }
+                jumps.push((code.ops.len(), start, target as usize));
+                Op::$op(0)
+            })
+        }
+
+        let op = match instr {
+            Instr::NOP => Op::NOP,
+            Instr::HALT => Op::HALT,
+            Instr::ICONST => Op::ICONST(operand!() as i32),
+            Instr::POP => Op::POP,
+            Instr::ADD => Op::ADD,
+            Instr::SUB => Op::SUB,
+            Instr::MULTIPLY => Op::MULTIPLY,
+            Instr::DIVIDE => Op::DIVIDE,
+            Instr::MODULUS => Op::MODULUS,
+            Instr::AND => Op::AND,
+            Instr::OR => Op::OR,
+            Instr::NOT => Op::NOT,
+            Instr::RSHIFT => Op::RSHIFT,
+            Instr::LSHIFT => Op::LSHIFT,
+            Instr::COMP => Op::COMP,
+            Instr::COMP_LT => Op::COMP_LT,
+            Instr::COMP_EQ => Op::COMP_EQ,
+            Instr::COMP_GT => Op::COMP_GT,
+            Instr::RELJUMP => jump!(RELJUMP),
+            Instr::RELJUMP_GT => jump!(RELJUMP_GT),
+            Instr::RELJUMP_LT => jump!(RELJUMP_LT),
+            Instr::RELJUMP_EQ => jump!(RELJUMP_EQ),
+            Instr::STORE => Op::STORE(operand!()),
+            Instr::LOAD => Op::LOAD(operand!()),
+            Instr::STOREFIELD => Op::STOREFIELD,
+            Instr::LOADFIELD => Op::LOADFIELD,
+            Instr::VECTORSTORE => Op::VECTORSTORE,
+            Instr::VECTORLOAD => Op::VECTORLOAD,
+            Instr::CALL => Op::CALL(operand!()),
+            Instr::RETURN => Op::RETURN,
+            Instr::PRINT => Op::PRINT,
+            Instr::READ_INT => Op::READ_INT,
+            Instr::ARGC => Op::ARGC,
+            Instr::ARG_INT => Op::ARG_INT,
+            Instr::DUMP_STACK => Op::DUMP_STACK,
+            Instr::DUMP_GLOBALS => Op::DUMP_GLOBALS,
+        };
+        code.ops.push(op);
+        code.offsets.push(start);
+    }
+
+    for (index, start, target) in jumps {
+        // Jumping to the very end just leaves the frame
+        let target_index = if target == bytecode.len() {
+            code.ops.len()
+        } else {
+            match code.offsets.binary_search(&target) {
+                Ok(i) => i,
+                // Into the middle of an instruction
+                Err(_) => return Err(LoadError::InvalidJump(start, target as i64)),
+            }
+        } as u32;
+        code.ops[index] = match code.ops[index] {
+            Op::RELJUMP(_) => Op::RELJUMP(target_index),
+            Op::RELJUMP_GT(_) => Op::RELJUMP_GT(target_index),
+            Op::RELJUMP_LT(_) => Op::RELJUMP_LT(target_index),
+            Op::RELJUMP_EQ(_) => Op::RELJUMP_EQ(target_index),
+            op => op,
+        };
+    }
+
+    debug!("Decoded {} bytes into {} ops", bytecode.len(), code.ops.len());
+    Ok(code)
+}
+
+
+#[cfg(test)]
+mod decoder_tests {
+    use super::*;
+    use assembler::Assembler;
+
+    #[test]
+    fn op_size() {
+        use std::mem;
+
+        assert_eq!(8, mem::size_of::<Op>());
+    }
+
+    #[test]
+    fn jump_targets() {
+        let bytecode = Assembler::new()
+            .label("top")
+            .iconst(1)
+            .jump(Instr::RELJUMP_GT, "end")
+            .op(Instr::NOP)
+            .jump(Instr::RELJUMP, "top")
+            .label("end")
+            .finish();
+        let code = decode(&bytecode).unwrap();
+
+        assert_eq!(code.ops, vec![Op::ICONST(1), Op::RELJUMP_GT(4), Op::NOP, Op::RELJUMP(0)]);
+        assert_eq!(code.offsets, vec![0, 5, 10, 11]);
+    }
+
+    #[test]
+    fn instructions() {
+        for opcode in 0..=255u8 {
+            let instr = match Instr::from_u8(opcode) {
+                Some(instr) => instr,
+                None => continue,
+            };
+            let mut bytecode = vec![opcode, 0, 0, 0, 0];
+            // Keep jumps in bounds
+            if opcode >= Instr::RELJUMP as u8 && opcode <= Instr::RELJUMP_EQ as u8 {
+                bytecode[4] = 1;
+            }
+            let code = decode(&bytecode).unwrap();
+            assert_eq!(code.ops[0].instruction(), instr);
+        }
+    }
+}
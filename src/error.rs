@@ -0,0 +1,126 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+// Limits on what a module may ask for, so a bogus header or const table
+// can't make us allocate gigabytes before running a single instruction
+pub const MAX_VARS: u32 = 0x10000;
+pub const MAX_FUNCS: u32 = 0x10000;
+pub const MAX_CALL_DEPTH: usize = 0x10000;
+// Locals (arguments included) of all the frames on the call stack together.
+// Deep recursion of a function with many locals hits this before the depth.
+pub const MAX_STACK_LOCALS: usize = 0x100000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    InvalidHeaderSize(usize),
+    InvalidMagic(u32),
+    UnsupportedVersion(u8),
+    UnsupportedFlags(u32),
+    // A section of this kind doesn't fit in the module
+    InvalidSection(u32),
+    // The entry point doesn't exist or takes arguments
+    InvalidEntry(u32),
+    // Ran past the end of the data while reading something at `offset`
+    UnexpectedEnd(usize),
+    InvalidString(usize),
+    TooManyVars(u32),
+    InvalidFunctionId(u32),
+    // Jump at bytecode offset .0 to an invalid target .1
+    InvalidJump(usize, i64),
+    // A snapshot doesn't fit the module it was saved with
+    InvalidSnapshot(&'static str),
+    // Unknown event kind in a recording, at this offset
+    InvalidEvent(usize),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::InvalidHeaderSize(size) =>
+                write!(f, "Invalid header size: {} bytes", size),
+            LoadError::InvalidMagic(magic) =>
+                write!(f, "Invalid header magic: 0x{:08X}", magic),
+            LoadError::UnsupportedVersion(version) =>
+                write!(f, "Unsupported module format version: {}", version),
+            LoadError::UnsupportedFlags(flags) =>
+                write!(f, "Unsupported module flags: 0x{:08X}", flags),
+            LoadError::InvalidSection(kind) =>
+                write!(f, "Section of kind 0x{:02X} out of bounds", kind),
+            LoadError::InvalidEntry(id) =>
+                write!(f, "Invalid entry point: function {}", id),
+            LoadError::UnexpectedEnd(offset) =>
+                write!(f, "Unexpected end of data at offset {}", offset),
+            LoadError::InvalidString(offset) =>
+                write!(f, "Invalid UTF-8 string at offset {}", offset),
+            LoadError::TooManyVars(count) =>
+                write!(f, "Too many variables: {} (max {})", count, MAX_VARS),
+            LoadError::InvalidFunctionId(id) =>
+                write!(f, "Invalid function id: {} (max {})", id, MAX_FUNCS - 1),
+            LoadError::InvalidJump(offset, target) =>
+                write!(f, "Jump at offset {} to invalid target {}", offset, target),
+            LoadError::InvalidSnapshot(what) =>
+                write!(f, "Invalid snapshot: bad {}", what),
+            LoadError::InvalidEvent(offset) =>
+                write!(f, "Invalid event in recording at offset {}", offset),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    StackUnderflow,
+    CallStackOverflow,
+    DivisionByZero,
+    InvalidLocal(u32),
+    UnknownFunction(u32),
+    // Function .0 takes .1 arguments but was entered with .2
+    WrongArgCount(u32, u32, usize),
+    Unimplemented(&'static str),
+    // Writing program output failed
+    Output(io::ErrorKind),
+    // Reading program input failed
+    Input(io::ErrorKind),
+    // Writing the recording failed
+    Record(io::ErrorKind),
+    // The program did something at this step that the recording doesn't have
+    ReplayDiverged(u64),
+    // The program ended with this many recorded events not replayed
+    ReplayUnfinished(usize),
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RuntimeError::StackUnderflow =>
+                write!(f, "Pop from an empty op stack"),
+            RuntimeError::CallStackOverflow =>
+                write!(f, "Call stack overflow (max depth {}, max {} locals in all frames)",
+                       MAX_CALL_DEPTH, MAX_STACK_LOCALS),
+            RuntimeError::DivisionByZero =>
+                write!(f, "Division by zero"),
+            RuntimeError::InvalidLocal(index) =>
+                write!(f, "Invalid local index: {}", index),
+            RuntimeError::UnknownFunction(id) =>
+                write!(f, "Call to unknown function id {}", id),
+            RuntimeError::WrongArgCount(id, expected, given) =>
+                write!(f, "Function {} takes {} arguments, {} given", id, expected, given),
+            RuntimeError::Unimplemented(instr) =>
+                write!(f, "Unimplemented instruction: {}", instr),
+            RuntimeError::Output(kind) =>
+                write!(f, "Couldn't write program output: {:?}", kind),
+            RuntimeError::Input(kind) =>
+                write!(f, "Couldn't read program input: {:?}", kind),
+            RuntimeError::Record(kind) =>
+                write!(f, "Couldn't write the recording: {:?}", kind),
+            RuntimeError::ReplayDiverged(step) =>
+                write!(f, "Replay diverged from the recording at step {}", step),
+            RuntimeError::ReplayUnfinished(count) =>
+                write!(f, "Replay diverged from the recording, the program ended with {} recorded events left", count),
+        }
+    }
+}
+
+impl Error for RuntimeError {}
@@ -0,0 +1,355 @@
+// Conditions for breakpoints, a small C-like expression language over i32s:
+//
+//   local[1] > 100 && hits % 10 == 0
+//
+// Operands are integers, local[N] (the innermost frame's locals, arguments
+// first), global[N], stack[N] (the op stack, stack[0] being the top), hits
+// (how many times the breakpoint was reached, this time included) and names
+// of locals and globals from the debug info. Operators, loosest first:
+//   ||
+//   &&
+//   == !=
+//   < <= > >=
+//   + -
+//   * / %
+//   unary - and !
+// Comparisons and logic give 1 or 0 and anything but 0 is true, like in C.
+// Arithmetic wraps. Names and indexes are only checked when evaluating, so a
+// condition can be set before the frame it talks about exists.
+
+use std::fmt;
+
+use interpreter::{Interpreter, StackFrame};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+// By precedence, loosest first
+const BINARY_OPS: [&[(&str, BinaryOp)]; 6] = [
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[("==", BinaryOp::Eq), ("!=", BinaryOp::Ne)],
+    &[("<=", BinaryOp::Le), (">=", BinaryOp::Ge), ("<", BinaryOp::Lt), (">", BinaryOp::Gt)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+    &[("*", BinaryOp::Mul), ("/", BinaryOp::Div), ("%", BinaryOp::Rem)],
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Int(i32),
+    Local(usize),
+    Global(usize),
+    Stack(usize),
+    Hits,
+    Name(String),
+    Neg(Box<Node>),
+    Not(Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    // As written, for showing it back
+    source: String,
+    root: Node,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Int(i32),
+    Ident(String),
+    // Operators and brackets
+    Punct(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Int(value) => write!(f, "{}", value),
+            Token::Ident(ref name) => write!(f, "{}", name),
+            Token::Punct(punct) => write!(f, "{}", punct),
+        }
+    }
+}
+
+// Longest first, so "<=" isn't read as "<"
+const PUNCTS: [&str; 19] = [
+    "||", "&&", "==", "!=", "<=", ">=",
+    "<", ">", "+", "-", "*", "/", "%", "!", "(", ")", "[", "]", "=",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_digit() {
+            let len = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+            let value = rest[..len].parse().map_err(|_| format!("Integer {} is too big", &rest[..len]))?;
+            tokens.push(Token::Int(value));
+            len
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..len].to_string()));
+            len
+        } else {
+            match PUNCTS.iter().find(|&&punct| rest.starts_with(punct)) {
+                // A lone "=" is only there to say it's not "=="
+                Some(&"=") | None => return Err(format!("Unexpected {} in condition", c)),
+                Some(&punct) => {
+                    tokens.push(Token::Punct(punct));
+                    punct.len()
+                },
+            }
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self.tokens.get(self.pos).cloned().ok_or("Unexpected end of condition")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), String> {
+        match self.next()? {
+            Token::Punct(p) if p == punct => Ok(()),
+            token => Err(format!("Expected {} but found {}", punct, token)),
+        }
+    }
+
+    // Binary operators of precedence `level` and tighter
+    fn binary(&mut self, level: usize) -> Result<Node, String> {
+        if level == BINARY_OPS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        loop {
+            let op = match self.peek() {
+                Some(&Token::Punct(punct)) =>
+                    BINARY_OPS[level].iter().find(|&&(p, _)| p == punct).map(|&(_, op)| op),
+                _ => None,
+            };
+            let op = match op {
+                Some(op) => op,
+                None => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.binary(level + 1)?;
+            left = Node::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        match self.next()? {
+            Token::Int(value) => Ok(Node::Int(value)),
+            Token::Punct("-") => Ok(Node::Neg(Box::new(self.unary()?))),
+            Token::Punct("!") => Ok(Node::Not(Box::new(self.unary()?))),
+            Token::Punct("(") => {
+                let node = self.binary(0)?;
+                self.expect(")")?;
+                Ok(node)
+            },
+            Token::Ident(ref name) if name == "hits" => Ok(Node::Hits),
+            Token::Ident(name) => {
+                if self.peek() != Some(&Token::Punct("[")) {
+                    return Ok(Node::Name(name));
+                }
+                self.pos += 1;
+                let index = match self.next()? {
+                    Token::Int(index) if index >= 0 => index as usize,
+                    token => return Err(format!("Invalid index {}", token)),
+                };
+                self.expect("]")?;
+                match name.as_str() {
+                    "local" => Ok(Node::Local(index)),
+                    "global" => Ok(Node::Global(index)),
+                    "stack" => Ok(Node::Stack(index)),
+                    _ => Err(format!("Can't index {}, only local, global and stack", name)),
+                }
+            },
+            token => Err(format!("Unexpected {} in condition", token)),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr, String> {
+        let mut parser = Parser { tokens: tokenize(source)?, pos: 0 };
+        let root = parser.binary(0)?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("Unexpected {} in condition", token));
+        }
+        Ok(Expr { source: source.trim().to_string(), root })
+    }
+
+    // In `frame`, the innermost one. Fails on a missing local, global or
+    // stack slot, an unknown name or a division by zero.
+    pub fn eval(&self, inpr: &Interpreter, frame: &StackFrame, hits: u64) -> Result<i32, String> {
+        eval(&self.root, inpr, frame, hits)
+    }
+
+    pub fn holds(&self, inpr: &Interpreter, frame: &StackFrame, hits: u64) -> Result<bool, String> {
+        self.eval(inpr, frame, hits).map(|value| value != 0)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn eval(node: &Node, inpr: &Interpreter, frame: &StackFrame, hits: u64) -> Result<i32, String> {
+    let value = match *node {
+        Node::Int(value) => value,
+        Node::Local(index) => *frame.locals.get(index)
+            .ok_or_else(|| format!("No local {} in {}", index, inpr.function_name(frame.id)))?,
+        Node::Global(index) => *inpr.globals().get(index)
+            .ok_or_else(|| format!("No global {}", index))?,
+        Node::Stack(index) => {
+            let stack = &inpr.op_stack;
+            if index >= stack.len() {
+                return Err(format!("Only {} values on the stack", stack.len()));
+            }
+            stack[stack.len() - 1 - index]
+        },
+        Node::Hits => hits.min(i32::MAX as u64) as i32,
+        Node::Name(ref name) => {
+            let local = (0..frame.locals.len())
+                .find(|&i| inpr.debug_info.local_name(frame.id, i) == Some(name.as_str()));
+            let global = || (0..inpr.globals().len())
+                .find(|&i| inpr.debug_info.global_name(i) == Some(name.as_str()));
+            match (local, global()) {
+                (Some(index), _) => frame.locals[index],
+                (None, Some(index)) => inpr.globals()[index],
+                (None, None) => return Err(format!("No local or global named {}", name)),
+            }
+        },
+        Node::Neg(ref node) => eval(node, inpr, frame, hits)?.wrapping_neg(),
+        Node::Not(ref node) => (eval(node, inpr, frame, hits)? == 0) as i32,
+        Node::Binary(BinaryOp::Or, ref left, ref right) =>
+            (eval(left, inpr, frame, hits)? != 0 || eval(right, inpr, frame, hits)? != 0) as i32,
+        Node::Binary(BinaryOp::And, ref left, ref right) =>
+            (eval(left, inpr, frame, hits)? != 0 && eval(right, inpr, frame, hits)? != 0) as i32,
+        Node::Binary(op, ref left, ref right) => {
+            let (a, b) = (eval(left, inpr, frame, hits)?, eval(right, inpr, frame, hits)?);
+            match op {
+                BinaryOp::Eq => (a == b) as i32,
+                BinaryOp::Ne => (a != b) as i32,
+                BinaryOp::Lt => (a < b) as i32,
+                BinaryOp::Le => (a <= b) as i32,
+                BinaryOp::Gt => (a > b) as i32,
+                BinaryOp::Ge => (a >= b) as i32,
+                BinaryOp::Add => a.wrapping_add(b),
+                BinaryOp::Sub => a.wrapping_sub(b),
+                BinaryOp::Mul => a.wrapping_mul(b),
+                BinaryOp::Div | BinaryOp::Rem if b == 0 => return Err("Division by zero".to_string()),
+                BinaryOp::Div => a.wrapping_div(b),
+                BinaryOp::Rem => a.wrapping_rem(b),
+                BinaryOp::Or | BinaryOp::And => unreachable!(),
+            }
+        },
+    };
+    Ok(value)
+}
+
+
+#[cfg(test)]
+mod expr_tests {
+    use super::*;
+    use Options;
+    use assembler::{Assembler, ModuleBuilder};
+    use instructions::Instruction as Instr;
+    use runtime::Runtime;
+
+    // Paused in f(7, 2) with local 2 = 40 and 5, 6 on the stack
+    fn runtime() -> Runtime {
+        let f = Assembler::new()
+            .iconst(40).op_with(Instr::STORE, 2)
+            .iconst(5).iconst(6)
+            .op(Instr::RETURN)
+            .finish();
+        let main = Assembler::new().iconst(2).iconst(7).op_with(Instr::CALL, 0).finish();
+        let data = ModuleBuilder::new()
+            .version(2)
+            .func(0, "f", 2, 1, &f)
+            .main(&main)
+            .debug_locals(0, &["a", "b", "sum"])
+            .build();
+        let mut runtime = Runtime::new(data, Options::default()).unwrap();
+        runtime.set_step_limit(Some(7));
+        runtime.run().unwrap();
+        runtime
+    }
+
+    fn eval(source: &str, hits: u64) -> Result<i32, String> {
+        let runtime = runtime();
+        let frame = runtime.call_stack().last().unwrap();
+        Expr::parse(source)?.eval(runtime.interpreter(), frame, hits)
+    }
+
+    #[test]
+    fn operands() {
+        assert_eq!(eval("local[0] + local[1] * local[2]", 0), Ok(87));
+        assert_eq!(eval("a - sum", 0), Ok(-33));
+        assert_eq!(eval("stack[0] * 10 + stack[1]", 0), Ok(65));
+        assert_eq!(eval("hits", 3), Ok(3));
+        assert_eq!(eval("local[3]", 0), Err("No local 3 in f".to_string()));
+        assert_eq!(eval("stack[2]", 0), Err("Only 2 values on the stack".to_string()));
+        assert_eq!(eval("global[0]", 0), Err("No global 0".to_string()));
+        assert_eq!(eval("c", 0), Err("No local or global named c".to_string()));
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(eval("local[2] > 30 && hits % 10 == 0", 20), Ok(1));
+        assert_eq!(eval("local[2] > 30 && hits % 10 == 0", 21), Ok(0));
+        assert_eq!(eval("1 + 2 * 3 - -4", 0), Ok(11));
+        assert_eq!(eval("(1 + 2) * 3", 0), Ok(9));
+        assert_eq!(eval("7 / 2 + 7 % 2 + 10 - 3 - 2", 0), Ok(9));
+        assert_eq!(eval("1 < 2 == 1 && !(2 <= 1) && 3 >= 3 && 2 != 1", 0), Ok(1));
+        assert_eq!(eval("0 || 5 > 4", 0), Ok(1));
+        assert_eq!(eval("-2147483647 - 2", 0), Ok(2147483647));
+        assert_eq!(eval("1 / (a - 7)", 0), Err("Division by zero".to_string()));
+        // Short-circuits past the error
+        assert_eq!(eval("0 && 1 / 0", 0), Ok(0));
+    }
+
+    #[test]
+    fn syntax() {
+        assert_eq!(Expr::parse(" hits > 2 ").unwrap().to_string(), "hits > 2");
+        assert_eq!(Expr::parse("local[1] = 3"), Err("Unexpected = in condition".to_string()));
+        assert_eq!(Expr::parse("(1 + 2"), Err("Unexpected end of condition".to_string()));
+        assert_eq!(Expr::parse("1 2"), Err("Unexpected 2 in condition".to_string()));
+        assert_eq!(Expr::parse("foo[1]"), Err("Can't index foo, only local, global and stack".to_string()));
+        assert_eq!(Expr::parse("local[-1]"), Err("Invalid index -".to_string()));
+        assert_eq!(Expr::parse("99999999999"), Err("Integer 99999999999 is too big".to_string()));
+        assert_eq!(Expr::parse("local[1) "), Err("Expected ] but found )".to_string()));
+    }
+}
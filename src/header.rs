@@ -1,18 +1,67 @@
 use std::fmt;
 use byteorder::{BigEndian, ReadBytesExt};
+use num::FromPrimitive;
 
+use error::{LoadError, MAX_VARS};
+
+// Version 1: magic, var_count, then the const table and the program bytecode
 pub const HEADER_SIZE: usize = 8;
-const MAGIC_VALUE: u32 = 0x5AB70500;
+// Version 2: magic, flags, entry, var_count, section count, then the sections
+pub const HEADER_V2_SIZE: usize = 20;
+pub const SECTION_ENTRY_SIZE: usize = 12;
+
+// The low byte of the magic word is the format version. It used to be
+// padding, which is why 0 means version 1.
+pub const MAGIC_VALUE: u32 = 0x5AB70500;
+const MAGIC_MASK: u32 = 0xFFFFFF00;
+pub const CURRENT_VERSION: u8 = 2;
+
+// Entry point meaning "run the code section"
+pub const NO_ENTRY: u32 = 0xFFFFFFFF;
+
+// Flags in the low half are required: a runtime that doesn't know one of them
+// must refuse the module. Unknown flags in the high half can be ignored.
+pub const REQUIRED_FLAGS_MASK: u32 = 0x0000FFFF;
+pub const KNOWN_FLAGS: u32 = 0;
+
+enum_from_primitive! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum SectionKind {
+        CODE = 0x01,
+        CONSTANTS = 0x02,
+        DEBUG = 0x03,
+        CUSTOM = 0x04,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Section {
+    // Kinds we don't know about are kept around, and skipped
+    pub kind: u32,
+    // From the start of the module
+    pub offset: u32,
+    pub length: u32,
+}
 
 #[derive(Default)]
 pub struct RaptorHeader {
-    magic: u32,      // Magic number + padding ( 0x5AB70500 )
+    magic: u32,      // Magic number + version ( 0x5AB705XX )
+    pub version: u8,
+    pub flags: u32,
+    pub entry: u32,      // Function id to start at, or NO_ENTRY
     pub var_count: u32,  // Number of variables
+    pub sections: Vec<Section>,
 }
 
 impl RaptorHeader {
     fn verify(&self) -> bool {
-        self.magic == MAGIC_VALUE
+        self.magic & MAGIC_MASK == MAGIC_VALUE
+    }
+
+    // Only the first section of a kind counts
+    pub fn section(&self, kind: SectionKind) -> Option<&Section> {
+        self.sections.iter().find(|s| s.kind == kind as u32)
     }
 }
 
@@ -20,47 +69,154 @@ impl fmt::Debug for RaptorHeader {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "RaptorHeader {{
             magic: 0x{:04X}
+            version: {}
+            flags: 0x{:08X}
+            entry: {}
             var_count: {}
+            sections: {:?}
         }}",
             self.magic,
-            self.var_count
+            self.version,
+            self.flags,
+            self.entry,
+            self.var_count,
+            self.sections
         )
     }
 }
 
-pub fn read_header(data: &[u8]) -> RaptorHeader {
+pub fn read_header(data: &[u8]) -> Result<RaptorHeader, LoadError> {
     if data.len() < HEADER_SIZE  {
-        panic!("Invalid header size");
+        return Err(LoadError::InvalidHeaderSize(data.len()));
     }
-    
-    let header = read_header_impl(data);
+
+    let header = read_header_impl(data)?;
     if !header.verify() {
-        panic!("Invalid header magic");
+        return Err(LoadError::InvalidMagic(header.magic));
+    }
+    if header.version > CURRENT_VERSION {
+        return Err(LoadError::UnsupportedVersion(header.version));
+    }
+    if header.flags & REQUIRED_FLAGS_MASK & !KNOWN_FLAGS != 0 {
+        return Err(LoadError::UnsupportedFlags(header.flags));
+    }
+    if header.var_count > MAX_VARS {
+        return Err(LoadError::TooManyVars(header.var_count));
+    }
+    for section in &header.sections {
+        if section.offset as u64 + section.length as u64 > data.len() as u64 {
+            return Err(LoadError::InvalidSection(section.kind));
+        }
+        if SectionKind::from_u32(section.kind).is_none() {
+            info!("Skipping unknown section kind 0x{:02X}", section.kind);
+        }
     }
 
     debug!("Header verified");
-    header
+    Ok(header)
 }
 
-fn read_header_impl(mut data: &[u8]) -> RaptorHeader {
-    let mut header: RaptorHeader = Default::default();
-    
-    header.magic = data.read_u32::<BigEndian>().unwrap();
-    header.var_count = data.read_u32::<BigEndian>().unwrap();
-    
+// data has to be at least HEADER_SIZE long
+fn read_header_impl(mut data: &[u8]) -> Result<RaptorHeader, LoadError> {
+    let len = data.len();
+    let magic = data.read_u32::<BigEndian>().unwrap();
+    let version = match magic as u8 {
+        0 => 1,
+        version => version,
+    };
+
+    let header = if version == 1 {
+        RaptorHeader {
+            magic,
+            version,
+            entry: NO_ENTRY,
+            var_count: data.read_u32::<BigEndian>().unwrap(),
+            ..Default::default()
+        }
+    } else {
+        let mut read_u32 = || data.read_u32::<BigEndian>()
+            .map_err(|_| LoadError::UnexpectedEnd(len));
+        let mut header = RaptorHeader {
+            magic,
+            version,
+            flags: read_u32()?,
+            entry: read_u32()?,
+            var_count: read_u32()?,
+            ..Default::default()
+        };
+        let section_count = read_u32()?;
+        // Don't trust the count for allocating
+        if section_count as u64 * SECTION_ENTRY_SIZE as u64 > len as u64 {
+            return Err(LoadError::UnexpectedEnd(len));
+        }
+        for _ in 0..section_count {
+            header.sections.push(Section {
+                kind: read_u32()?,
+                offset: read_u32()?,
+                length: read_u32()?,
+            });
+        }
+        header
+    };
+
     debug!("Read header: {:#?}", header);
-    header
+    Ok(header)
 }
 
 
 #[cfg(test)]
 mod header_tests {
     use super::*;
+    use assembler::ModuleBuilder;
 
     #[test]
     fn header_size() {
         use std::mem;
 
-        assert_eq!(HEADER_SIZE, mem::size_of::<RaptorHeader>());
+        assert_eq!(HEADER_SIZE, 2 * mem::size_of::<u32>());
+        assert_eq!(HEADER_V2_SIZE, 5 * mem::size_of::<u32>());
+        assert_eq!(SECTION_ENTRY_SIZE, 3 * mem::size_of::<u32>());
+    }
+
+    #[test]
+    fn v1() {
+        let data = ModuleBuilder::new().var_count(3).build();
+        let header = read_header(&data).unwrap();
+
+        assert_eq!(header.version, 1);
+        assert_eq!(header.var_count, 3);
+        assert_eq!(header.entry, NO_ENTRY);
+        assert!(header.sections.is_empty());
+    }
+
+    #[test]
+    fn v2() {
+        let data = ModuleBuilder::new()
+            .version(2)
+            .var_count(3)
+            .flags(0x00010000)
+            .custom_section(0x7F, &[1, 2, 3])
+            .main(&[0x00])
+            .build();
+        let header = read_header(&data).unwrap();
+
+        assert_eq!(header.version, 2);
+        assert_eq!(header.flags, 0x00010000);
+        assert_eq!(header.var_count, 3);
+        assert_eq!(header.entry, NO_ENTRY);
+        let kinds: Vec<u32> = header.sections.iter().map(|s| s.kind).collect();
+        assert_eq!(kinds, vec![SectionKind::CONSTANTS as u32, SectionKind::CODE as u32, 0x7F]);
+        let code = header.section(SectionKind::CODE).unwrap();
+        assert_eq!(&data[code.offset as usize..][..code.length as usize], &[0x00]);
+    }
+
+    #[test]
+    fn unsupported() {
+        let mut data = ModuleBuilder::new().version(2).flags(0x1).build();
+        assert_eq!(read_header(&data).unwrap_err(), LoadError::UnsupportedFlags(0x1));
+
+        data[3] = CURRENT_VERSION + 1;
+        assert_eq!(read_header(&data).unwrap_err(),
+                   LoadError::UnsupportedVersion(CURRENT_VERSION + 1));
     }
 }
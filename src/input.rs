@@ -0,0 +1,77 @@
+// Where READ_INT reads from. Stdin by default, any BufRead with
+// Runtime::set_input.
+
+use std::io::{self, BufRead};
+
+// What READ_INT pushes on top of the value it read
+pub const READ_OK: i32 = 1;
+pub const READ_END: i32 = 0;
+pub const READ_INVALID: i32 = -1;
+
+// Reads the next whitespace separated integer. None at the end of the input,
+// Some(Err(token)) if the next token isn't an i32 (it's consumed anyway).
+pub fn read_int(input: &mut dyn BufRead) -> io::Result<Option<Result<i32, String>>> {
+    let token = match read_token(input)? {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+    let token = String::from_utf8_lossy(&token).into_owned();
+    Ok(Some(token.parse().map_err(|_| token)))
+}
+
+fn read_token(input: &mut dyn BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut token = Vec::new();
+    loop {
+        let (used, done) = {
+            let buf = match input.fill_buf() {
+                Ok(buf) => buf,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if buf.is_empty() {
+                break;
+            }
+            // Skip whitespace up to the token, stop right after it
+            let mut used = 0;
+            let mut done = false;
+            for &byte in buf {
+                if byte.is_ascii_whitespace() {
+                    if !token.is_empty() {
+                        done = true;
+                        break;
+                    }
+                } else {
+                    token.push(byte);
+                }
+                used += 1;
+            }
+            (used, done)
+        };
+        input.consume(used);
+        if done {
+            break;
+        }
+    }
+    Ok(if token.is_empty() {None} else {Some(token)})
+}
+
+
+#[cfg(test)]
+mod input_tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        let mut input = Cursor::new("  12\n-3\tx7 +4 99999999999\n\n");
+        let mut read = || read_int(&mut input).unwrap();
+        assert_eq!(read(), Some(Ok(12)));
+        assert_eq!(read(), Some(Ok(-3)));
+        assert_eq!(read(), Some(Err("x7".to_string())));
+        assert_eq!(read(), Some(Ok(4)));
+        assert_eq!(read(), Some(Err("99999999999".to_string())));
+        assert_eq!(read(), None);
+        assert_eq!(read(), None);
+    }
+}
@@ -1,11 +1,36 @@
-
+// Operands are 4 byte big endian values following the opcode.
+//
+// Binary operations (arithmetic, bitwise and COMP*) pop `a`, the top of the
+// op stack, then `b`, and push `a op b`. So `ICONST 1, ICONST 3, SUB` is 3 - 1.
+// COMP pushes 1, -1 or 0 for a > b, a < b and a == b, COMP_* push 1 or 0.
+// Arithmetic wraps around on overflow, shift amounts are taken mod 32 and
+// DIVIDE/MODULUS by zero are runtime errors.
+//
+// RELJUMP offsets are signed and relative to the last byte of the jump itself,
+// i.e. the target is (offset of the opcode) + 4 + offset. The conditional
+// jumps pop the top of the stack and jump if it's > 0, < 0 or == 0.
+//
+// CALL pops the callee's arguments into its first locals, top of the stack
+// first. RETURN pops the return value, drops whatever the callee left on the
+// stack and pushes the value back for the caller. It fails with a stack
+// underflow if the callee popped more than it pushed. HALT stops the runtime.
+//
+// READ_INT reads the next whitespace separated integer from the input and
+// pushes it, then a status on top: 1 if it read one, 0 at the end of the input
+// and -1 if the next word isn't an integer (the value is 0 for both). Reading
+// a line will come with strings.
+//
+// ARGC pushes the number of command line arguments given to the program (not
+// counting the runtime's own). ARG_INT pops an index, and pushes that argument
+// parsed as an integer then a status, like READ_INT: 1 if it parsed, 0 if
+// there's no such argument and -1 if it isn't an integer.
 enum_from_primitive! {
 #[allow(non_camel_case_types)]
 #[derive(Debug, PartialEq)]
 pub enum Instruction {
     NOP = 0x00,
     HALT = 0x01,
-    ICONST = 0x02,
+    ICONST = 0x02,      // value
     POP = 0x03,
     ADD = 0x1A,
     SUB = 0x1B,
@@ -21,19 +46,22 @@ pub enum Instruction {
     COMP_LT = 0x21,
     COMP_EQ = 0x22,
     COMP_GT = 0x23,
-    RELJUMP = 0x2A,
-    RELJUMP_GT = 0x2B,
-    RELJUMP_LT = 0x2C,
-    RELJUMP_EQ = 0x2D,
-    STORE = 0x80,
-    LOAD = 0x81,
+    RELJUMP = 0x2A,     // offset
+    RELJUMP_GT = 0x2B,  // offset
+    RELJUMP_LT = 0x2C,  // offset
+    RELJUMP_EQ = 0x2D,  // offset
+    STORE = 0x80,       // local index
+    LOAD = 0x81,        // local index
     STOREFIELD = 0x82,
     LOADFIELD = 0x83,
     VECTORSTORE = 0x84,
     VECTORLOAD = 0x85,
-    CALL = 0x90,
+    CALL = 0x90,        // function id
     RETURN = 0x91,
     PRINT = 0xA0,
+    READ_INT = 0xA1,
+    ARGC = 0xA2,
+    ARG_INT = 0xA3,
     DUMP_STACK = 0xFA,
     DUMP_GLOBALS = 0xFB,
 }
@@ -1,10 +1,21 @@
-use num::FromPrimitive;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use header::*;
 use constants::*;
-use instructions::Instruction as Instr;
+use decoder::{decode, Code, Op};
+use debug_info::{read_debug_info, DebugInfo};
+use input::{self, READ_OK, READ_END, READ_INVALID};
+use observer::{Observer, Step};
+use replay::{Event, Replay};
+use watch::{Action, Target, Watchpoint, WatchHit};
+use error::{LoadError, RuntimeError};
 
-#[derive(Debug, Default)]
+#[derive(Default)]
 pub struct Interpreter {
     // File data
     header: RaptorHeader,
@@ -13,9 +24,68 @@ pub struct Interpreter {
     // Rutime stuff
     pub op_stack: Vec<i32>,
     memory: Vec<i32>,
-    pub prog_bytecode: Vec<u8>,
+    pub prog_code: Rc<Code>,
+    // Contents of the CUSTOM sections, for embedders
+    pub custom_sections: Vec<Rc<[u8]>>,
+    // Empty unless the module has a DEBUG section
+    pub debug_info: DebugInfo,
+
+    // Instructions executed so far, and how many we may execute in total
+    pub steps: u64,
+    pub step_limit: u64,
+    // Set from anywhere (a signal handler, another thread) to stop before
+    // the next instruction, see Runtime::interrupt_handle
+    pub interrupt: Arc<AtomicBool>,
+
+    // Program output, stdout if None
+    pub output: Option<Box<dyn Write>>,
+    // Program input, stdin if None
+    pub input: Option<Box<dyn BufRead>>,
+    // Command line arguments for ARGC and ARG_INT
+    pub args: Vec<String>,
+    // Whether input is recorded or replayed
+    pub replay: Replay,
+    pub watchpoints: Vec<Watchpoint>,
+    // The watchpoint that paused the current run, if one did
+    pub watch_hit: Option<WatchHit>,
+
+    #[cfg(feature = "observers")]
+    observers: Vec<Box<dyn Observer>>,
+    // Set while a debugger executes steps again, so observers see each once
+    // and logging watchpoints log each write once
+    pub mute_observers: bool,
+}
+
+impl fmt::Debug for Interpreter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Interpreter")
+            .field("header", &self.header)
+            .field("op_stack", &self.op_stack)
+            .field("memory", &self.memory)
+            .field("steps", &self.steps)
+            .field("step_limit", &self.step_limit)
+            .finish()
+    }
+}
+
+// What the Runtime should do with the call stack after a dispatch
+#[derive(Debug)]
+pub enum Dispatch {
+    // CALL was issued, push this frame
+    Call(StackFrame),
+    // RETURN was issued (with the value it returned) or the frame ran out of ops, pop it
+    Return(Option<i32>),
+    // HALT was issued, stop with the call stack left as is
+    Halt,
+    // The step limit was reached or a watchpoint paused, dispatch again to resume
+    Pause,
+    // Interpreter::interrupt was set, dispatch again to resume
+    Interrupt,
 }
 
+// Id of the frame running the top-level program bytecode
+pub const MAIN_ID: u32 = u32::MAX;
+
 // All of the fields beeing pub is not very good
 // Maybe move this in runtime.rs?
 #[derive(Debug, Default, Clone)]
@@ -24,230 +94,545 @@ pub struct StackFrame {
     pub locals: Vec<i32>,
     // The index of the first op in the op_stack that should be kept
     pub return_addr: usize,
-    pub bytecode: Vec<u8>,
+    // Shared with the FuncConst (or Interpreter for main), never copied
+    pub code: Rc<Code>,
+    // Index of the next op in code.ops
     pub bc_counter: usize
 }
 
 impl Interpreter {
-    pub fn new(mut data: Vec<u8>, debug: bool) -> Interpreter {
+    pub fn new(data: Vec<u8>, debug: bool) -> Result<Interpreter, LoadError> {
         if debug {debug!("Bytecode length: {} bytes", data.len());}
-        let header = read_header(&data);
-        data.drain(..HEADER_SIZE);
-        let const_table: ConstTable = read_const_table(data.as_slice());
+        let header = read_header(&data)?;
+
+        let (const_table, prog_data) = if header.version == 1 {
+            // The const table ends at its END, the program follows it
+            let rest = &data[HEADER_SIZE..];
+            let const_table = read_const_table(rest)?;
+            let prog_start = const_table.bc_counter;
+            (const_table, &rest[prog_start..])
+        } else {
+            let const_data = section_data(&data, header.section(SectionKind::CONSTANTS));
+            (read_const_table(const_data)?,
+             section_data(&data, header.section(SectionKind::CODE)))
+        };
         if debug {
             debug!("Constant table length: {} bytes", const_table.bc_counter);
-            debug!("Bytecode length: {} bytes", data.len());
+            debug!("Bytecode length: {} bytes", prog_data.len());
+        }
+
+        if header.entry != NO_ENTRY {
+            match const_table.funcs.get(header.entry as usize) {
+                Some(func) if func.arg_count == 0 => {},
+                _ => return Err(LoadError::InvalidEntry(header.entry)),
+            }
         }
-        data.drain(..const_table.bc_counter);
+
+        let custom_sections = header.sections.iter()
+            .filter(|s| s.kind == SectionKind::CUSTOM as u32)
+            .map(|s| section_data(&data, Some(s)).into())
+            .collect();
+        let debug_info = match header.section(SectionKind::DEBUG) {
+            Some(section) => read_debug_info(section_data(&data, Some(section)))?,
+            None => Default::default(),
+        };
+
+        let prog_code = Rc::new(decode(prog_data)?);
         let mut i = Interpreter {
-            header: header,
-            const_table: const_table,
+            header,
+            const_table,
             op_stack: Vec::new(),
             memory: Vec::new(),
-            prog_bytecode: data,
+            prog_code,
+            custom_sections,
+            debug_info,
+            steps: 0,
+            step_limit: u64::MAX,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            output: None,
+            input: None,
+            args: Vec::new(),
+            replay: Replay::Off,
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            #[cfg(feature = "observers")]
+            observers: Vec::new(),
+            mute_observers: false,
         };
         i.memory.resize(i.header.var_count as usize, 0);
-        i
+        Ok(i)
+    }
+
+    pub fn header(&self) -> &RaptorHeader {
+        &self.header
+    }
+
+    // The frame execution starts in: the entry point if there's one, main otherwise
+    pub fn entry_frame(&self) -> StackFrame {
+        match self.const_table.funcs.get(self.header.entry as usize) {
+            Some(func_const) => StackFrame::for_function(self.header.entry, func_const),
+            None => StackFrame {
+                id: MAIN_ID,
+                code: Rc::clone(&self.prog_code),
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn globals(&self) -> &[i32] {
+        &self.memory
+    }
+
+    pub fn globals_mut(&mut self) -> &mut [i32] {
+        &mut self.memory
+    }
+
+    // Id of the function called `name`
+    pub fn function_id(&self, name: &str) -> Option<u32> {
+        self.const_table.funcs.iter()
+            .position(|func_const| func_const.name == name)
+            .map(|id| id as u32)
+    }
+
+    pub fn function_name(&self, id: u32) -> &str {
+        if id == MAIN_ID {
+            return "main";
+        }
+        match self.const_table.funcs.get(id as usize) {
+            Some(func_const) => &func_const.name,
+            None => "<unknown>",
+        }
+    }
+
+    // "file:line" of the frame's current instruction, or "function+offset"
+    // if the module has no line info for it
+    pub fn location(&self, frame: &StackFrame) -> String {
+        self.location_at(frame.id, frame.offset())
+    }
+
+    // Same, for the instruction at `offset` in function `func`
+    pub fn location_at(&self, func: u32, offset: usize) -> String {
+        match self.debug_info.location(func, offset) {
+            Some(location) => location,
+            None => format!("{}+{}", self.function_name(func), offset),
+        }
+    }
+
+    #[cfg(feature = "observers")]
+    pub fn add_observer(&mut self, observer: Box<dyn Observer>) {
+        self.observers.push(observer);
+    }
+
+    #[cfg(feature = "observers")]
+    pub fn take_observers(&mut self) -> Vec<Box<dyn Observer>> {
+        mem::take(&mut self.observers)
+    }
+
+    // Calls `f` with every observer
+    #[cfg(feature = "observers")]
+    pub fn notify<F: FnMut(&mut dyn Observer, &Interpreter)>(&mut self, mut f: F) {
+        if self.observers.is_empty() || self.mute_observers {
+            return;
+        }
+        // Observers get to look at the whole interpreter, themselves included
+        let mut observers = mem::take(&mut self.observers);
+        for observer in &mut observers {
+            f(&mut **observer, self);
+        }
+        self.observers = observers;
+    }
+
+    #[cfg(not(feature = "observers"))]
+    #[inline(always)]
+    pub fn notify<F: FnMut(&mut dyn Observer, &Interpreter)>(&mut self, _f: F) {}
+
+    // Writes a line of program output
+    fn output(&mut self, line: &str) -> Result<(), RuntimeError> {
+        let result = match self.output {
+            Some(ref mut out) => writeln!(out, "{}", line),
+            None => writeln!(io::stdout(), "{}", line),
+        };
+        result.map_err(|e| RuntimeError::Output(e.kind()))
+    }
+
+    // Reads an integer for READ_INT, returns it and the status
+    fn read_int(&mut self) -> Result<(i32, i32), RuntimeError> {
+        let step = self.steps;
+        if let Replay::Replay(ref mut recording) = self.replay {
+            let Event::ReadInt { value, status, .. } = recording.next(step)?;
+            return Ok((value, status));
+        }
+        let read = self.read_input()?;
+        if let Replay::Record(ref mut out) = self.replay {
+            Event::ReadInt { step, value: read.0, status: read.1 }.write(out)
+                .map_err(|e| RuntimeError::Record(e.kind()))?;
+        }
+        Ok(read)
+    }
+
+    fn read_input(&mut self) -> Result<(i32, i32), RuntimeError> {
+        let result = match self.input {
+            Some(ref mut input) => input::read_int(input),
+            None => input::read_int(&mut io::stdin().lock()),
+        };
+        match result.map_err(|e| RuntimeError::Input(e.kind()))? {
+            Some(Ok(val)) => Ok((val, READ_OK)),
+            Some(Err(token)) => {
+                debug!("READ_INT: {:?} isn't an integer", token);
+                Ok((0, READ_INVALID))
+            },
+            None => Ok((0, READ_END)),
+        }
+    }
+
+    // Called by every instruction that writes a local or a global, after the
+    // write. True if a watchpoint wants to pause.
+    pub fn check_write(&mut self, target: Target, old: i32, new: i32) -> bool {
+        let mut pause = false;
+        for index in 0..self.watchpoints.len() {
+            let watchpoint = self.watchpoints[index];
+            if !watchpoint.matches(target, new) {
+                continue;
+            }
+            let hit = WatchHit { index, target, step: self.steps, old, new };
+            match watchpoint.action {
+                // Re-executed steps were logged the first time
+                Action::Log if self.mute_observers => {},
+                Action::Log => info!("Watchpoint {}: {} changed from {} to {} at step {}",
+                                     index, target.describe(self), old, new, self.steps),
+                Action::Pause if !pause => {
+                    self.watch_hit = Some(hit);
+                    pause = true;
+                },
+                Action::Pause => {},
+            }
+            self.notify(|o, i| o.on_watch(i, &hit));
+        }
+        pause
+    }
+
+    // Argument `index` parsed for ARG_INT, and the status
+    fn arg_int(&self, index: i32) -> (i32, i32) {
+        let arg = if index < 0 {None} else {self.args.get(index as usize)};
+        match arg.map(|arg| arg.parse()) {
+            Some(Ok(val)) => (val, READ_OK),
+            Some(Err(_)) => (0, READ_INVALID),
+            None => (0, READ_END),
+        }
+    }
+
+    pub fn function(&self, id: u32) -> Option<&FuncConst> {
+        self.const_table.funcs.get(id as usize)
+    }
+
+    // Indexed by function id. Ids the module skipped have empty placeholders.
+    pub fn functions(&self) -> &[FuncConst] {
+        &self.const_table.funcs
+    }
+
+    // One line of a stack trace, like
+    // "fib (id 1), offset 12 (fib.rapt:3), args [n = 2], locals [0]"
+    pub fn describe_frame(&self, frame: &StackFrame) -> String {
+        let offset = frame.offset();
+        let mut line = if frame.id == MAIN_ID {
+            format!("main, offset {}", offset)
+        } else {
+            format!("{} (id {}), offset {}", self.function_name(frame.id), frame.id, offset)
+        };
+        if let Some(location) = self.debug_info.location(frame.id, offset) {
+            line.push_str(&format!(" ({})", location));
+        }
+        if let Some(func_const) = self.function(frame.id) {
+            let locals: Vec<String> = frame.locals.iter().enumerate()
+                .map(|(i, val)| match self.debug_info.local_name(frame.id, i) {
+                    Some(name) => format!("{} = {}", name, val),
+                    None => val.to_string(),
+                })
+                .collect();
+            let (args, locals) = locals.split_at((func_const.arg_count as usize).min(locals.len()));
+            line.push_str(&format!(", args [{}], locals [{}]", args.join(", "), locals.join(", ")));
+        }
+        line
+    }
+
+    // Like "[x = 1, y = 2]", falling back to the bare value for unnamed globals
+    pub fn named_globals(&self) -> String {
+        let globals: Vec<String> = self.memory.iter().enumerate()
+            .map(|(i, val)| match self.debug_info.global_name(i) {
+                Some(name) => format!("{} = {}", name, val),
+                None => val.to_string(),
+            })
+            .collect();
+        format!("[{}]", globals.join(", "))
+    }
+}
+
+fn section_data<'a>(data: &'a [u8], section: Option<&Section>) -> &'a [u8] {
+    match section {
+        // Bounds were checked by read_header
+        Some(s) => &data[s.offset as usize..][..s.length as usize],
+        None => &[],
     }
 }
 
 impl StackFrame {
 
-    fn get_next_4_bytes(&mut self) -> u32 {
-        let val = (self.bytecode[self.bc_counter] as u32) << 24 |
-        (self.bytecode[self.bc_counter + 1] as u32) << 16 |
-        (self.bytecode[self.bc_counter + 2] as u32) << 8 |
-        (self.bytecode[self.bc_counter + 3] as u32);
-        self.bc_counter += 4;
-        debug!("get_next_4_bytes: 0x{:04X}", val);
-        val
+    // A new frame for the function, with all its locals (arguments included) zeroed
+    pub fn for_function(id: u32, func_const: &FuncConst) -> StackFrame {
+        StackFrame {
+            id,
+            locals: vec![0; (func_const.arg_count + func_const.local_count) as usize],
+            code: Rc::clone(&func_const.code),
+            ..Default::default()
+        }
+    }
+
+    // Bytecode offset of the instruction being executed, i.e. the last one
+    // dispatched. For frames further down the call stack, that's their CALL.
+    pub fn offset(&self) -> usize {
+        let index = self.bc_counter.saturating_sub(1);
+        match self.code.offsets.get(index) {
+            Some(&offset) => offset,
+            None => 0,
+        }
     }
 
-    pub fn dispatch(&mut self, inpr: &mut Interpreter, debug: bool) -> Option<StackFrame> {
+    // From the debug info, or "local N"
+    pub fn local_name(&self, inpr: &Interpreter, index: u32) -> String {
+        match inpr.debug_info.local_name(self.id, index as usize) {
+            Some(name) => name.to_string(),
+            None => format!("local {}", index),
+        }
+    }
+
+    pub fn dispatch(&mut self, inpr: &mut Interpreter, debug: bool)
+                    -> Result<Dispatch, RuntimeError> {
         use std::ops::*;
-        use std::cmp::*;
+
+        // Keep our own handle so the ops can be borrowed while self is mutated
+        let code = Rc::clone(&self.code);
 
         // Main loop
-        while self.bc_counter != self.bytecode.len() {
+        while let Some(&op) = code.ops.get(self.bc_counter) {
             // info!("PC: {}", bc_counter);
-
-            // Use FromPrimitive trait to convert a value to its enum
-            let instr = Instr::from_u8(self.bytecode[self.bc_counter]);
-            self.bc_counter += 1;
-
-            if instr.is_none() {
-                warn!("Unimplemented instruction: {:04X}",
-                      self.bytecode[self.bc_counter]);
-                continue;
+            // Past it too, after the limit was lowered or an older state restored
+            if inpr.steps >= inpr.step_limit {
+                return Ok(Dispatch::Pause);
             }
-
-            // We're sure it's Some here, so unpack it
-            let instr = instr.unwrap();
+            if inpr.interrupt.load(Ordering::Relaxed) {
+                return Ok(Dispatch::Interrupt);
+            }
+            let index = self.bc_counter;
+            inpr.steps += 1;
+            self.bc_counter += 1;
+            // Set by the ops that leave this frame, once the step is done
+            let mut exit = None;
+            // Whether a conditional jump was taken
+            let mut branch = None;
 
             if debug {
-                debug!("{:?}", instr);
+                debug!("{}: {:?}", inpr.location(self), op);
             }
 
+            macro_rules! output {
+                ($($arg:tt)*) => {
+                    {
+                        let line = format!($($arg)*);
+                        inpr.output(&line)?
+                    }
+                };
+            }
             macro_rules! push {
                 ( $x:expr ) => {
-                    inpr.op_stack.push($x);
+                    inpr.op_stack.push($x)
                 };
             }
             macro_rules! pop {
                 () => {
-                    inpr.op_stack.pop();
+                    inpr.op_stack.pop().ok_or(RuntimeError::StackUnderflow)?
                 };
             }
             macro_rules! operation {
-                ($op:ident) => ({
-                    let l = pop!().unwrap();
-                    let r = pop!().unwrap();
-                    let val = l.$op(r);
+                ($op:ident) => (operation!(|l: i32, r| l.$op(r)));
+                ($f:expr) => ({
+                    let l = pop!();
+                    let r = pop!();
+                    let val = $f(l, r);
                     push!(val);
                     debug!("Operation: {:?}. Operands: [{}, {}]. Result: {}.",
-                           instr, l, r, val);
+                           op, l, r, val);
                 })
             }
-            macro_rules! reljump {
+            macro_rules! checked_operation {
                 ($op:ident) => ({
-                    let top = pop!().unwrap();
-                    if top.$op(&0) {
-                        reljump!();
-                    } else {
-                        self.get_next_4_bytes();
-                        if debug {debug!("Jump not taken"); }
+                    if inpr.op_stack.len() >= 2 &&
+                        inpr.op_stack[inpr.op_stack.len() - 2] == 0 {
+                        return Err(RuntimeError::DivisionByZero);
                     }
-                });
-                () => ({
-                    let offset = (self.get_next_4_bytes() - 1) as i32;
-                    // Need this if because you can't have negative usizes
-                    if offset > 0 {
-                        if debug {debug!("RELJUMP: {}", offset);}
-                        self.bc_counter += offset as usize;
-                        if offset == 1 {
-                            warn!("RELJUMP 1 is redundant. This is a compiler bug")
-                        }
-                    } else if offset < 0 {
-                        if debug {debug!("RELJUMP: {}", offset);}
-                        self.bc_counter -= (-offset) as usize;
-                    } else {
-                        warn!("Invalid reljump offset: 0");
+                    operation!($op);
+                })
+            }
+            macro_rules! reljump {
+                ($op:ident, $target:expr) => ({
+                    let top = pop!();
+                    let taken = top.$op(&0);
+                    if taken {
+                        reljump!($target);
+                    } else if debug {
+                        debug!("Jump not taken");
                     }
+                    branch = Some(taken);
+                });
+                ($target:expr) => ({
+                    if debug {debug!("RELJUMP to op {}", $target);}
+                    self.bc_counter = $target as usize;
                 });
             }
 
             macro_rules! push_frame {
                 ($id:expr) => ({
-                    let func_const = &inpr.const_table.funcs[$id as usize];
-                    let mut sf = StackFrame {
-                        id: $id,
-                        locals: Vec::new(),
-                        bytecode: func_const.body.clone(),
-                        ..Default::default()
+                    let func_const = match inpr.const_table.funcs.get($id as usize) {
+                        Some(func_const) => func_const,
+                        None => return Err(RuntimeError::UnknownFunction($id)),
                     };
-                    for _ in 0..func_const.arg_count {
-                        sf.locals.push(pop!().unwrap());
+                    let mut sf = StackFrame::for_function($id, func_const);
+                    for i in 0..func_const.arg_count as usize {
+                        sf.locals[i] = pop!();
                     }
                     sf.return_addr = inpr.op_stack.len();
-                    sf.locals.resize(
-                        (func_const.arg_count + func_const.local_count) as usize, 0);
                     if debug {
                         debug!("Pushed new frame: {:?}", sf);
                         debug!("Op stack: {:?}", inpr.op_stack);
                     }
-                    return Some(sf);
+                    Dispatch::Call(sf)
                 });
             }
 
-            match instr {
-                Instr::NOP => {},
-                Instr::HALT => {
-                    println!("HALT issued, stopped execution.");
+            match op {
+                Op::NOP => {},
+                Op::HALT => {
+                    output!("HALT issued, stopped execution.");
                     if debug {
                         debug!("Stack: {:?}", inpr.op_stack);
                         debug!("Memory: {:?}", inpr.memory);
                     }
+                    exit = Some(Dispatch::Halt);
                 },
-                Instr::ICONST => {
-                    let b = self.get_next_4_bytes() as i32;
-                    push!(b);
-                },
-                Instr::POP => { pop!(); },
-                Instr::ADD =>       { operation!(add);    },
-                Instr::SUB =>       { operation!(sub);    },
-                Instr::MULTIPLY =>  { operation!(mul);    },
-                Instr::DIVIDE =>    { operation!(div);    },
-                Instr::MODULUS =>   { operation!(rem);    },
-                Instr::RSHIFT =>    { operation!(shl);    },
-                Instr::LSHIFT =>    { operation!(shr);    },
-                Instr::AND =>       { operation!(bitand); },
-                Instr::OR =>        { operation!(bitor);  },
-                Instr::NOT =>       {
-                    let val = pop!().unwrap();
+                Op::ICONST(val) => { push!(val); },
+                Op::POP => { pop!(); },
+                Op::ADD =>       { operation!(wrapping_add);        },
+                Op::SUB =>       { operation!(wrapping_sub);        },
+                Op::MULTIPLY =>  { operation!(wrapping_mul);        },
+                Op::DIVIDE =>    { checked_operation!(wrapping_div); },
+                Op::MODULUS =>   { checked_operation!(wrapping_rem); },
+                Op::RSHIFT =>    { operation!(|l: i32, r| l.wrapping_shr(r as u32)); },
+                Op::LSHIFT =>    { operation!(|l: i32, r| l.wrapping_shl(r as u32)); },
+                Op::AND =>       { operation!(bitand); },
+                Op::OR =>        { operation!(bitor);  },
+                Op::NOT =>       {
+                    let val = pop!();
                     push!(val.not());
                 },
-                Instr::COMP => {
+                Op::COMP => {
                     let a = pop!(); let b = pop!();
                     push!(if a > b {1} else if a < b {-1} else {0});
                 },
-                Instr::COMP_LT => {
+                Op::COMP_LT => {
                     let a = pop!(); let b = pop!();
                     push!(if a < b {1} else {0});
                 },
-                Instr::COMP_EQ => {
+                Op::COMP_EQ => {
                     let a = pop!(); let b = pop!();
                     push!(if a == b {1} else {0});
                 },
-                Instr::COMP_GT => {
+                Op::COMP_GT => {
                     let a = pop!(); let b = pop!();
                     push!(if a > b {1} else {0});
                 },
-                Instr::RELJUMP => {reljump!();},
-                Instr::RELJUMP_GT => {reljump!(gt);},
-                Instr::RELJUMP_LT => {reljump!(lt);},
-                Instr::RELJUMP_EQ => {reljump!(eq);},
-                Instr::STORE => {
-                    let index = self.get_next_4_bytes() as usize;
-                    let val = pop!().unwrap();
-                    self.locals[index] = val;
-                    debug!("Stored {} into local {}", val, index)
+                Op::RELJUMP(target) => {reljump!(target);},
+                Op::RELJUMP_GT(target) => {reljump!(gt, target);},
+                Op::RELJUMP_LT(target) => {reljump!(lt, target);},
+                Op::RELJUMP_EQ(target) => {reljump!(eq, target);},
+                Op::STORE(index) => {
+                    let val = pop!();
+                    let old = match self.locals.get_mut(index as usize) {
+                        Some(local) => mem::replace(local, val),
+                        None => return Err(RuntimeError::InvalidLocal(index)),
+                    };
+                    if debug {debug!("Stored {} into {}", val, self.local_name(inpr, index));}
+                    if !inpr.watchpoints.is_empty() &&
+                        inpr.check_write(Target::Local(self.id, index as usize), old, val) {
+                        exit = Some(Dispatch::Pause);
+                    }
                 },
-                Instr::LOAD => {
-                    let index = self.get_next_4_bytes() as usize;
-                    let val = self.locals[index];
+                Op::LOAD(index) => {
+                    let val = match self.locals.get(index as usize) {
+                        Some(&val) => val,
+                        None => return Err(RuntimeError::InvalidLocal(index)),
+                    };
                     push!(val);
-                    debug!("Loaded {} from local {}", val, index);
+                    if debug {debug!("Loaded {} from {}", val, self.local_name(inpr, index));}
                     debug!("Op stack: {:?}", inpr.op_stack)
                 },
-                Instr::STOREFIELD => {unimplemented!()},
-                Instr::LOADFIELD => {unimplemented!()},
-                Instr::VECTORSTORE => {unimplemented!()},
-                Instr::VECTORLOAD => {unimplemented!()},
-                Instr::CALL => {
-                    let id: u32 = self.get_next_4_bytes();
-                    debug!("Calling func {}", self.id);
-                    return push_frame!(id);
+                Op::STOREFIELD => {return Err(RuntimeError::Unimplemented("STOREFIELD"))},
+                Op::LOADFIELD => {return Err(RuntimeError::Unimplemented("LOADFIELD"))},
+                Op::VECTORSTORE => {return Err(RuntimeError::Unimplemented("VECTORSTORE"))},
+                Op::VECTORLOAD => {return Err(RuntimeError::Unimplemented("VECTORLOAD"))},
+                Op::CALL(id) => {
+                    debug!("Calling func {}", id);
+                    exit = Some(push_frame!(id));
                 },
-                Instr::RETURN => {
-                    let val = pop!().unwrap();
-                    inpr.op_stack.resize(self.return_addr, 0);
+                Op::RETURN => {
+                    let val = pop!();
+                    // The callee popped into its caller's part of the stack
+                    if inpr.op_stack.len() < self.return_addr {
+                        return Err(RuntimeError::StackUnderflow);
+                    }
+                    inpr.op_stack.truncate(self.return_addr);
                     debug!("Returning {} from func {}", val, self.id);
                     push!(val);
-                    return None;
+                    exit = Some(Dispatch::Return(Some(val)));
                 }
-                Instr::PRINT => {
-                    println!("PRINT: {}", pop!().unwrap());
+                Op::PRINT => {
+                    let val = pop!();
+                    output!("PRINT: {}", val);
+                    inpr.notify(|o, i| o.on_print(i, val));
                 },
-                Instr::DUMP_STACK => {
-                    println!("{:?}", inpr.op_stack);
+                Op::READ_INT => {
+                    let (val, status) = inpr.read_int()?;
+                    push!(val);
+                    push!(status);
+                },
+                Op::ARGC => { push!(inpr.args.len() as i32); },
+                Op::ARG_INT => {
+                    let index = pop!();
+                    let (val, status) = inpr.arg_int(index);
+                    push!(val);
+                    push!(status);
+                },
+                Op::DUMP_STACK => {
+                    match inpr.debug_info.location(self.id, self.offset()) {
+                        Some(location) => output!("{}: {:?}", location, inpr.op_stack),
+                        None => output!("{:?}", inpr.op_stack),
+                    }
+                },
+                Op::DUMP_GLOBALS => {
+                    match inpr.debug_info.location(self.id, self.offset()) {
+                        Some(location) => output!("{}: {}", location, inpr.named_globals()),
+                        None => output!("{}", inpr.named_globals()),
+                    }
                 },
-                Instr::DUMP_GLOBALS => {
-                    println!("{:?}", inpr.memory);},
+            }
+
+            {
+                let frame: &StackFrame = self;
+                inpr.notify(|o, i| o.on_instruction(i, frame, &Step { index, op, branch }));
+            }
+            if let Some(exit) = exit {
+                return Ok(exit);
             }
         }
-        return None;    // Pop the current frame
+        Ok(Dispatch::Return(None))    // Pop the current frame
     }
  
 }
-
-// TODO: Add tests
@@ -0,0 +1,50 @@
+#![allow(clippy::upper_case_acronyms)]
+
+#[macro_use]
+extern crate enum_primitive;
+extern crate num;
+#[macro_use]
+extern crate log;
+extern crate byteorder;
+#[macro_use]
+extern crate json;
+
+pub mod observer;
+pub mod output;
+pub mod input;
+pub mod utils;
+pub mod header;
+pub mod runtime;
+pub mod interpreter;
+pub mod instructions;
+pub mod constants;
+pub mod decoder;
+pub mod debug_info;
+pub mod snapshot;
+pub mod replay;
+pub mod watch;
+pub mod expr;
+pub mod crash;
+pub mod debugger;
+pub mod dap;
+#[cfg(feature = "observers")]
+pub mod tracer;
+#[cfg(feature = "observers")]
+pub mod profiler;
+#[cfg(feature = "observers")]
+pub mod coverage;
+#[cfg(feature = "observers")]
+pub mod stats;
+pub mod assembler;
+pub mod error;
+mod raptor_object;
+
+pub static ACCEPTABLE_EXTENSIONS: [&str; 2] = ["crap", "crapt"];
+
+#[derive(Default, Debug)]
+pub struct Options {
+    pub debug: bool,
+    pub input: String,
+    // Passed to the program, for ARGC and ARG_INT
+    pub args: Vec<String>,
+}
@@ -1,41 +1,68 @@
 extern crate argparse;
 #[macro_use]
-extern crate enum_primitive;
-extern crate num;
-#[macro_use]
 extern crate log;
 extern crate env_logger;
-extern crate byteorder;
-
-mod utils;
-mod header;
-mod runtime;
-mod interpreter;
-mod instructions;
-mod constants;
-mod raptor_object;
+extern crate ctrlc;
+extern crate raptortime;
 
+#[cfg(feature = "observers")]
+use std::cell::RefCell;
 use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+#[cfg(feature = "observers")]
+use std::io::BufWriter;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+#[cfg(feature = "observers")]
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
 use env_logger::LogBuilder;
 use log::{LogRecord, LogLevelFilter};
-use argparse::{ArgumentParser, StoreTrue, Store, Print};
+use argparse::{ArgumentParser, StoreTrue, Store, StoreOption, Collect, List, Print};
 
-use runtime::Runtime;
+use raptortime::{utils, Options};
+use raptortime::runtime::{Runtime, Status};
+use raptortime::error::RuntimeError;
+use raptortime::snapshot::read_snapshot;
+use raptortime::replay::read_recording;
+use raptortime::debugger::Debugger;
+use raptortime::crash::{read_crash_dump, CrashDump, Inspector};
+use raptortime::dap;
+#[cfg(feature = "observers")]
+use raptortime::observer::Observer;
+#[cfg(feature = "observers")]
+use raptortime::tracer::Tracer;
+#[cfg(feature = "observers")]
+use raptortime::profiler::Profiler;
+#[cfg(feature = "observers")]
+use raptortime::coverage::Coverage;
+#[cfg(feature = "observers")]
+use raptortime::stats::Stats;
 
 const DEFAULT_LOG_LEVEL: LogLevelFilter = LogLevelFilter::Debug;
-pub static ACCEPTABLE_EXTENSIONS: [&'static str; 2] = ["crap", "crapt"];
-
-#[derive(Default, Debug)]
-pub struct Options {
-    debug: bool,
-    input: String,
-}
+// Like shells report a process killed by SIGINT
+const INTERRUPTED_EXIT_CODE: i32 = 130;
 
 fn main() {
     let mut options = Options {
         debug: true,
         input: String::new(),
+        args: Vec::new(),
     };
+    let mut trace_on_error = false;
+    let mut entry: Option<String> = None;
+    let mut entry_args: Option<String> = None;
+    let mut exit_code = false;
+    let mut record: Option<String> = None;
+    let mut replay: Option<String> = None;
+    let mut run_debugger = false;
+    let mut debug_on_interrupt = false;
+    let mut dap_server = false;
+    let mut crash_dump: Option<String> = None;
+    let mut inspect: Option<String> = None;
+    let mut checkpoint = Checkpoint { every: 10_000_000, ..Default::default() };
+    let mut tools = Tools { trace_to: u64::MAX, ..Default::default() };
     {   // this block limits the scope of borrows from ap.refer() calls
         let mut ap = ArgumentParser::new();
         ap.set_description("RaptorScript Runtime/Interpreter.");
@@ -45,6 +72,75 @@ fn main() {
         ap.refer(&mut options.input)
             .add_option(&["-i", "--input"], Store,
             "input bytecode file");
+        ap.refer(&mut trace_on_error)
+            .add_option(&["--trace-on-error"], StoreTrue,
+            "also print the Raptor call stack, with arguments and locals, when the program HALTs");
+        ap.refer(&mut entry)
+            .add_option(&["--entry"], StoreOption,
+            "run this function instead of the program, and print what it returns");
+        ap.refer(&mut entry_args)
+            .add_option(&["--args"], StoreOption,
+            "comma-separated integer arguments for the --entry function, like 10,-3 (--args=-3 if the first is negative)");
+        ap.refer(&mut exit_code)
+            .add_option(&["--exit-code"], StoreTrue,
+            "exit with what the --entry function returns instead of printing it");
+        ap.refer(&mut record)
+            .add_option(&["--record"], StoreOption,
+            "record the program's arguments and input to this file");
+        ap.refer(&mut replay)
+            .add_option(&["--replay"], StoreOption,
+            "replay the arguments and input recorded in this file");
+        ap.refer(&mut run_debugger)
+            .add_option(&["--debugger"], StoreTrue,
+            "run the program in the interactive debugger, which can also step backwards");
+        ap.refer(&mut debug_on_interrupt)
+            .add_option(&["--debug-on-interrupt"], StoreTrue,
+            "on Ctrl-C, go into the interactive debugger instead of exiting");
+        ap.refer(&mut dap_server)
+            .add_option(&["--dap"], StoreTrue,
+            "serve the Debug Adapter Protocol on stdin and stdout, for editors (the program is given by the launch request)");
+        ap.refer(&mut crash_dump)
+            .add_option(&["--crash-dump"], StoreOption,
+            "if the program fails, write its state to this file for --inspect");
+        ap.refer(&mut inspect)
+            .add_option(&["--inspect"], StoreOption,
+            "look at a crash dump interactively instead of running anything");
+        ap.refer(&mut checkpoint.path)
+            .add_option(&["--checkpoint"], StoreOption,
+            "save the program's state to this file every --checkpoint-every steps");
+        ap.refer(&mut checkpoint.every)
+            .add_option(&["--checkpoint-every"], Store,
+            "steps between checkpoints (default 10000000)");
+        ap.refer(&mut checkpoint.resume)
+            .add_option(&["--resume"], StoreOption,
+            "resume from a checkpoint instead of loading an input file");
+        ap.refer(&mut tools.trace)
+            .add_option(&["--trace"], StoreOption,
+            "write a JSON record per executed instruction to this file");
+        ap.refer(&mut tools.trace_functions)
+            .add_option(&["--trace-function"], Collect,
+            "only trace this function (\"main\" for main), can be repeated");
+        ap.refer(&mut tools.trace_from)
+            .add_option(&["--trace-from"], Store,
+            "first step to trace");
+        ap.refer(&mut tools.trace_to)
+            .add_option(&["--trace-to"], Store,
+            "last step to trace");
+        ap.refer(&mut tools.profile)
+            .add_option(&["--profile"], StoreTrue,
+            "print instruction counts and time spent per function");
+        ap.refer(&mut tools.profile_stacks)
+            .add_option(&["--profile-stacks"], StoreOption,
+            "profile, and write collapsed stacks for flamegraph tools to this file");
+        ap.refer(&mut tools.coverage)
+            .add_option(&["--coverage"], StoreOption,
+            "write a coverage report to this file");
+        ap.refer(&mut tools.stats)
+            .add_option(&["--stats"], StoreTrue,
+            "print an opcode histogram, call counts and stack depths at exit");
+        ap.refer(&mut options.args)
+            .add_argument("args", List,
+            "arguments for the program, after a -- if any of them start with -");
         ap.add_option(&["-v", "--version"],
             Print(env!("CARGO_PKG_VERSION").to_string()),
             "show version");
@@ -64,20 +160,373 @@ fn main() {
     }
     builder.init().unwrap();
 
+    // Flags that would be ignored otherwise, exiting like argparse does
+    let conflict = if entry.is_none() && exit_code {
+        Some("--exit-code needs --entry")
+    } else if entry.is_none() && entry_args.is_some() {
+        Some("--args needs --entry")
+    } else if entry.is_some() && checkpoint.resume.is_some() {
+        Some("--entry can't be used with --resume")
+    } else {
+        None
+    };
+    if let Some(conflict) = conflict {
+        error!("{}", conflict);
+        process::exit(2);
+    }
+    let entry_args = match entry_args.as_ref().map_or(Ok(Vec::new()), |args| parse_args(args)) {
+        Ok(args) => args,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(2);
+        }
+    };
+
+    if dap_server {
+        let mut server = dap::Server::new(Box::new(io::stdout()), options.debug);
+        if let Err(e) = server.run(BufReader::new(io::stdin())) {
+            error!("Debug adapter connection failed: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(ref path) = inspect {
+        let inspector = fs::read(path).map_err(|e| e.to_string())
+            .and_then(|data| read_crash_dump(&data).map_err(|e| e.to_string()))
+            .and_then(|dump| Inspector::new(dump).map_err(|e| e.to_string()));
+        let mut inspector = match inspector {
+            Ok(inspector) => inspector,
+            Err(e) => {
+                error!("Couldn't read crash dump {}: {}", path, e);
+                process::exit(1);
+            }
+        };
+        if let Err(e) = inspector.repl(&mut |line| io::stdin().read_line(line), &mut io::stdout()) {
+            error!("Inspector I/O error: {}", e);
+        }
+        return;
+    }
+
     // Parse input, start runtime
-    if !options.input.is_empty() {
-        if utils::should_open(&options.input) {
-            let data = utils::try_open_file(&options.input, options.debug);
-            let mut runtime = Runtime::new(data, options);
-            runtime.run();
-
-        } else {
-            warn!("Invalid input file extension. Accepted formats are .crapt and .crap");
-            return;
+    let mut runtime = if let Some(ref path) = checkpoint.resume {
+        let snapshot = match fs::read(path).map_err(|e| e.to_string())
+            .and_then(|data| read_snapshot(&data).map_err(|e| e.to_string())) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                error!("Couldn't read checkpoint {}: {}", path, e);
+                process::exit(1);
+            }
+        };
+        match Runtime::restore(&snapshot, options) {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                error!("Couldn't resume from {}: {}", path, e);
+                process::exit(1);
+            }
         }
-    } else {
+    } else if options.input.is_empty() {
         warn!("No input file given. Use -h or --help for help.");
         return;
+    } else if !utils::should_open(&options.input) {
+        warn!("Invalid input file extension. Accepted formats are .crapt and .crap");
+        return;
+    } else {
+        let data = utils::try_open_file(&options.input, options.debug);
+        let mut runtime = match Runtime::new(data, options) {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                error!("Couldn't load module: {}", e);
+                process::exit(1);
+            }
+        };
+        if let Some(ref name) = entry {
+            let entered = match runtime.interpreter().function_id(name) {
+                Some(id) => runtime.enter(id, &entry_args),
+                None => {
+                    error!("No function named {}", name);
+                    process::exit(1);
+                }
+            };
+            if let Err(e) = entered {
+                error!("Couldn't enter {}: {}", name, e);
+                process::exit(1);
+            }
+        }
+        runtime
+    };
+    if let Some(ref path) = replay {
+        let recording = match fs::read(path).map_err(|e| e.to_string())
+            .and_then(|data| read_recording(&data).map_err(|e| e.to_string())) {
+            Ok(recording) => recording,
+            Err(e) => {
+                error!("Couldn't read recording {}: {}", path, e);
+                process::exit(1);
+            }
+        };
+        runtime.replay(recording);
+    }
+    if let Some(ref path) = record {
+        let recorded = File::create(path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| runtime.record(Box::new(file)).map_err(|e| e.to_string()));
+        if let Err(e) = recorded {
+            error!("Couldn't create recording {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+    let observers = tools.install(&mut runtime);
+    handle_interrupts(&runtime);
+
+    if run_debugger {
+        // Not holding the stdin lock, the program reads from stdin too
+        let mut debugger = Debugger::new(runtime);
+        if let Err(e) = debugger.repl(&mut |line| io::stdin().read_line(line), &mut io::stdout()) {
+            error!("Debugger I/O error: {}", e);
+        }
+        tools.finish(observers, &debugger.runtime);
+        return;
+    }
+
+    // A panic is a bug in here, but it still gets a stack trace and a crash dump
+    let result = panic::catch_unwind(AssertUnwindSafe(|| checkpoint.run(&mut runtime)));
+    if let Ok(Ok(Status::Interrupted)) = result {
+        print_interrupted(&runtime);
+        if debug_on_interrupt {
+            let mut debugger = Debugger::new(runtime);
+            if let Err(e) = debugger.repl(&mut |line| io::stdin().read_line(line), &mut io::stdout()) {
+                error!("Debugger I/O error: {}", e);
+            }
+            tools.finish(observers, &debugger.runtime);
+            return;
+        }
+    }
+    tools.finish(observers, &runtime);
+    match result {
+        Ok(Ok(Status::Interrupted)) => process::exit(INTERRUPTED_EXIT_CODE),
+        Ok(Ok(Status::Halted)) if trace_on_error => {
+            match runtime.location() {
+                Some(location) => error!("Halted at {}", location),
+                None => error!("Halted"),
+            }
+            print_stack_trace(&runtime);
+        },
+        Ok(Ok(_)) => {
+            if let (Some(_), Some(value)) = (&entry, runtime.return_value()) {
+                if exit_code {
+                    process::exit(value);
+                }
+                println!("{}", value);
+            }
+        },
+        Ok(Err(e)) => {
+            match runtime.location() {
+                Some(location) => error!("{}: Runtime error: {}", location, e),
+                None => error!("Runtime error: {}", e),
+            }
+            print_stack_trace(&runtime);
+            write_crash_dump(&crash_dump, &runtime, &format!("Runtime error: {}", e));
+            process::exit(1);
+        },
+        Err(_) => {
+            error!("Interpreter panicked");
+            print_stack_trace(&runtime);
+            write_crash_dump(&crash_dump, &runtime, "Interpreter panicked");
+            process::exit(101);
+        },
+    }
+}
+
+// The --args list, like "10,-3"
+fn parse_args(list: &str) -> Result<Vec<i32>, String> {
+    if list.is_empty() {
+        return Ok(Vec::new());
+    }
+    list.split(',')
+        .map(|arg| arg.trim().parse().map_err(|_| format!("Invalid --args value {}", arg)))
+        .collect()
+}
+
+// Ctrl-C stops the program before its next instruction. A second one exits
+// right away, in case it's stuck reading input.
+fn handle_interrupts(runtime: &Runtime) {
+    let interrupt = runtime.interrupt_handle();
+    let installed = ctrlc::set_handler(move || {
+        if interrupt.swap(true, Ordering::Relaxed) {
+            process::exit(INTERRUPTED_EXIT_CODE);
+        }
+    });
+    if let Err(e) = installed {
+        warn!("Couldn't handle Ctrl-C: {}", e);
+    }
+}
+
+fn print_interrupted(runtime: &Runtime) {
+    match runtime.location() {
+        Some(location) => error!("Interrupted at {} after {} steps", location, runtime.steps()),
+        None => error!("Interrupted after {} steps", runtime.steps()),
+    }
+    print_stack_trace(runtime);
+    let interpreter = runtime.interpreter();
+    error!("Op stack: {:?}", interpreter.op_stack);
+    error!("Globals: {}", interpreter.named_globals());
+}
+
+fn write_crash_dump(path: &Option<String>, runtime: &Runtime, error: &str) {
+    if let Some(ref path) = *path {
+        match fs::write(path, CrashDump::new(runtime, error).to_bytes()) {
+            Ok(()) => error!("Crash dump written to {}, see it with --inspect", path),
+            Err(e) => error!("Couldn't write crash dump {}: {}", path, e),
+        }
+    }
+}
+
+fn print_stack_trace(runtime: &Runtime) {
+    error!("Raptor stack trace:");
+    for line in runtime.stack_trace() {
+        error!("    {}", line);
+    }
+}
+
+#[derive(Default)]
+struct Checkpoint {
+    path: Option<String>,
+    every: u64,
+    resume: Option<String>,
+}
+
+impl Checkpoint {
+    // Runs the program, saving a snapshot every `every` steps if there's a path
+    fn run(&self, runtime: &mut Runtime) -> Result<Status, RuntimeError> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return runtime.run(),
+        };
+        loop {
+            let limit = runtime.steps().saturating_add(self.every.max(1));
+            runtime.set_step_limit(Some(limit));
+            let status = runtime.run()?;
+            if status != Status::Paused {
+                return Ok(status);
+            }
+            // Write the whole thing aside first, so a crash can't leave half a checkpoint
+            let temp = format!("{}.tmp", path);
+            let written = fs::write(&temp, runtime.snapshot().to_bytes())
+                .and_then(|_| fs::rename(&temp, path));
+            match written {
+                Ok(()) => debug!("Checkpoint after {} steps", runtime.steps()),
+                Err(e) => error!("Couldn't write checkpoint {}: {}", path, e),
+            }
+        }
+    }
+}
+
+// Command line options for the tools built on observers
+#[derive(Default)]
+struct Tools {
+    trace: Option<String>,
+    trace_functions: Vec<String>,
+    trace_from: u64,
+    trace_to: u64,
+    profile: bool,
+    profile_stacks: Option<String>,
+    coverage: Option<String>,
+    stats: bool,
+}
+
+// Handles on the observers Tools installed, to report from after running
+#[cfg(feature = "observers")]
+#[derive(Default)]
+struct Observers {
+    tracer: Option<Rc<RefCell<Tracer>>>,
+    profiler: Option<Rc<RefCell<Profiler>>>,
+    coverage: Option<Rc<RefCell<Coverage>>>,
+    stats: Option<Rc<RefCell<Stats>>>,
+}
+
+#[cfg(feature = "observers")]
+fn add<T: Observer + 'static>(runtime: &mut Runtime, observer: T) -> Option<Rc<RefCell<T>>> {
+    let observer = Rc::new(RefCell::new(observer));
+    runtime.add_observer(Box::new(Rc::clone(&observer)));
+    Some(observer)
+}
+
+#[cfg(feature = "observers")]
+impl Tools {
+    fn install(&self, runtime: &mut Runtime) -> Observers {
+        let mut observers = Observers::default();
+        if let Some(ref path) = self.trace {
+            let file = match File::create(path) {
+                Ok(file) => file,
+                Err(e) => {
+                    error!("Couldn't create trace file {}: {}", path, e);
+                    process::exit(1);
+                }
+            };
+            let tracer = self.trace_functions.iter()
+                .fold(Tracer::new(Box::new(BufWriter::new(file))), |t, f| t.function(f))
+                .steps(self.trace_from, self.trace_to);
+            observers.tracer = add(runtime, tracer);
+        }
+        if self.stats {
+            observers.stats = add(runtime, Stats::new());
+        }
+        if self.coverage.is_some() {
+            let coverage = Coverage::new(runtime.interpreter());
+            observers.coverage = add(runtime, coverage);
+        }
+        // Last, so it's as close to running as it gets
+        if self.profile || self.profile_stacks.is_some() {
+            observers.profiler = add(runtime, Profiler::new());
+        }
+        observers
+    }
+
+    fn finish(&self, observers: Observers, runtime: &Runtime) {
+        let interpreter = runtime.interpreter();
+        if let Some(tracer) = observers.tracer {
+            if let Err(e) = tracer.borrow_mut().finish() {
+                error!("Couldn't write the trace: {}", e);
+            }
+        }
+        if let Some(profiler) = observers.profiler {
+            let profiler = profiler.borrow();
+            if self.profile {
+                eprint!("{}", profiler.report(interpreter));
+            }
+            if let Some(ref path) = self.profile_stacks {
+                let written = File::create(path).and_then(|file| {
+                    let mut out = BufWriter::new(file);
+                    profiler.write_collapsed(interpreter, &mut out)
+                });
+                if let Err(e) = written {
+                    error!("Couldn't write collapsed stacks to {}: {}", path, e);
+                }
+            }
+        }
+        if let Some(stats) = observers.stats {
+            eprint!("{}", stats.borrow().report(interpreter));
+        }
+        if let (Some(coverage), Some(ref path)) = (observers.coverage, &self.coverage) {
+            if let Err(e) = fs::write(path, coverage.borrow().report(interpreter)) {
+                error!("Couldn't write the coverage report to {}: {}", path, e);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "observers"))]
+struct Observers;
+
+#[cfg(not(feature = "observers"))]
+impl Tools {
+    fn install(&self, _runtime: &mut Runtime) -> Observers {
+        if self.trace.is_some() || self.profile || self.profile_stacks.is_some() ||
+            self.coverage.is_some() || self.stats {
+            warn!("Tracing, profiling, coverage and stats need the \"observers\" feature");
+        }
+        Observers
     }
 
+    fn finish(&self, _observers: Observers, _runtime: &Runtime) {}
 }
@@ -0,0 +1,180 @@
+// Hooks for tools that watch a program run: tracers, profilers, debuggers.
+//
+// Observers are called with the interpreter, so they can look at the op stack,
+// globals, function names and debug info, but not change anything. They can
+// only be added with the "observers" cargo feature (on by default). Without it
+// Interpreter::notify does nothing and the hooks compile away.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use decoder::Op;
+use error::RuntimeError;
+use interpreter::{Interpreter, StackFrame};
+use runtime::Status;
+use watch::WatchHit;
+
+// An executed instruction
+#[derive(Debug, Clone, Copy)]
+pub struct Step {
+    // Index of the op in the frame's code
+    pub index: usize,
+    pub op: Op,
+    // Whether a RELJUMP_GT/LT/EQ jumped
+    pub branch: Option<bool>,
+}
+
+#[allow(unused_variables)]
+pub trait Observer {
+    // After every instruction that didn't fail. `frame` is the frame it ran in
+    // (for a CALL, the caller) and inpr.steps already counts it.
+    fn on_instruction(&mut self, inpr: &Interpreter, frame: &StackFrame, step: &Step) {}
+
+    // The outermost frame (main, or the function given to Runtime::enter) is
+    // about to run its first instruction, it's the only one on the stack
+    fn on_enter(&mut self, inpr: &Interpreter, call_stack: &[StackFrame]) {}
+
+    // A frame was pushed for a CALL, the callee is the last one
+    fn on_call(&mut self, inpr: &Interpreter, call_stack: &[StackFrame]) {}
+
+    // The last frame is about to be popped. `value` is what RETURN returned,
+    // None if the frame ran out of ops.
+    fn on_return(&mut self, inpr: &Interpreter, call_stack: &[StackFrame], value: Option<i32>) {}
+
+    fn on_print(&mut self, inpr: &Interpreter, value: i32) {}
+
+    // A watchpoint matched a write, whatever its action
+    fn on_watch(&mut self, inpr: &Interpreter, hit: &WatchHit) {}
+
+    // run() failed, the call stack is left as it was
+    fn on_error(&mut self, inpr: &Interpreter, call_stack: &[StackFrame], error: &RuntimeError) {}
+
+    // run() returned Ok
+    fn on_stop(&mut self, inpr: &Interpreter, call_stack: &[StackFrame], status: &Status) {}
+}
+
+// So embedders can keep a handle on an observer they gave to the Runtime
+impl<T: Observer> Observer for Rc<RefCell<T>> {
+    fn on_instruction(&mut self, inpr: &Interpreter, frame: &StackFrame, step: &Step) {
+        self.borrow_mut().on_instruction(inpr, frame, step)
+    }
+
+    fn on_enter(&mut self, inpr: &Interpreter, call_stack: &[StackFrame]) {
+        self.borrow_mut().on_enter(inpr, call_stack)
+    }
+
+    fn on_call(&mut self, inpr: &Interpreter, call_stack: &[StackFrame]) {
+        self.borrow_mut().on_call(inpr, call_stack)
+    }
+
+    fn on_return(&mut self, inpr: &Interpreter, call_stack: &[StackFrame], value: Option<i32>) {
+        self.borrow_mut().on_return(inpr, call_stack, value)
+    }
+
+    fn on_print(&mut self, inpr: &Interpreter, value: i32) {
+        self.borrow_mut().on_print(inpr, value)
+    }
+
+    fn on_watch(&mut self, inpr: &Interpreter, hit: &WatchHit) {
+        self.borrow_mut().on_watch(inpr, hit)
+    }
+
+    fn on_error(&mut self, inpr: &Interpreter, call_stack: &[StackFrame], error: &RuntimeError) {
+        self.borrow_mut().on_error(inpr, call_stack, error)
+    }
+
+    fn on_stop(&mut self, inpr: &Interpreter, call_stack: &[StackFrame], status: &Status) {
+        self.borrow_mut().on_stop(inpr, call_stack, status)
+    }
+}
+
+
+#[cfg(all(test, feature = "observers"))]
+mod observer_tests {
+    use super::*;
+    use Options;
+    use assembler::{Assembler, ModuleBuilder};
+    use instructions::Instruction as Instr;
+    use runtime::Runtime;
+
+    #[derive(Default)]
+    struct Events(Vec<String>);
+
+    impl Observer for Events {
+        fn on_instruction(&mut self, inpr: &Interpreter, frame: &StackFrame, step: &Step) {
+            self.0.push(format!("{} {:?} {:?} {:?}",
+                                inpr.steps, frame.id, step.op.instruction(), step.branch));
+        }
+        fn on_enter(&mut self, _inpr: &Interpreter, call_stack: &[StackFrame]) {
+            self.0.push(format!("enter {}", call_stack[0].id));
+        }
+        fn on_call(&mut self, _inpr: &Interpreter, call_stack: &[StackFrame]) {
+            self.0.push(format!("call {}", call_stack.len()));
+        }
+        fn on_return(&mut self, _inpr: &Interpreter, call_stack: &[StackFrame], value: Option<i32>) {
+            self.0.push(format!("return {} {:?}", call_stack.len(), value));
+        }
+        fn on_print(&mut self, _inpr: &Interpreter, value: i32) {
+            self.0.push(format!("print {}", value));
+        }
+        fn on_error(&mut self, _inpr: &Interpreter, call_stack: &[StackFrame], error: &RuntimeError) {
+            self.0.push(format!("error {} {:?}", call_stack.len(), error));
+        }
+        fn on_stop(&mut self, _inpr: &Interpreter, call_stack: &[StackFrame], status: &Status) {
+            self.0.push(format!("stop {} {:?}", call_stack.len(), status));
+        }
+    }
+
+    fn events(main: &[u8]) -> Vec<String> {
+        let f = Assembler::new().op_with(Instr::LOAD, 0).jump(Instr::RELJUMP_EQ, "end")
+            .iconst(5).op(Instr::RETURN)
+            .label("end")
+            .finish();
+        let data = ModuleBuilder::new().func(0, "f", 1, 0, &f).main(main).build();
+        let events = Rc::new(RefCell::new(Events::default()));
+        let mut runtime = Runtime::new(data, Options::default()).unwrap();
+        runtime.add_observer(Box::new(Rc::clone(&events)));
+        let _ = runtime.run();
+        let events = events.borrow().0.clone();
+        events
+    }
+
+    #[test]
+    fn events_in_order() {
+        let main = Assembler::new()
+            .iconst(1).op_with(Instr::CALL, 0).op(Instr::PRINT)
+            .iconst(0).op_with(Instr::CALL, 0)
+            .finish();
+        assert_eq!(events(&main), vec![
+            "enter 4294967295",
+            "1 4294967295 ICONST None",
+            "2 4294967295 CALL None",
+            "call 2",
+            "3 0 LOAD None",
+            "4 0 RELJUMP_EQ Some(false)",
+            "5 0 ICONST None",
+            "6 0 RETURN None",
+            "return 2 Some(5)",
+            "print 5",
+            "7 4294967295 PRINT None",
+            "8 4294967295 ICONST None",
+            "9 4294967295 CALL None",
+            "call 2",
+            "10 0 LOAD None",
+            "11 0 RELJUMP_EQ Some(true)",
+            "return 2 None",
+            "return 1 None",
+            "stop 0 Finished",
+        ]);
+    }
+
+    #[test]
+    fn errors() {
+        let main = Assembler::new().iconst(1).op(Instr::ADD).finish();
+        assert_eq!(events(&main), vec![
+            "enter 4294967295",
+            "1 4294967295 ICONST None",
+            "error 1 StackUnderflow",
+        ]);
+    }
+}
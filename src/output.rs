@@ -0,0 +1,39 @@
+// Where program output (PRINT, DUMP_STACK, DUMP_GLOBALS, HALT) goes.
+// Stdout by default, any Write with Runtime::set_output. Diagnostics go
+// through the log crate, never here.
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+// An output sink that keeps everything written to it, for tests and
+// embedders that want the output as a string. Clones share the buffer.
+#[derive(Debug, Clone, Default)]
+pub struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Capture {
+    pub fn new() -> Capture {
+        Default::default()
+    }
+
+    // Everything written so far. Output is always valid UTF-8.
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+
+    // Same, for when it's used for something that isn't text
+    pub fn bytes(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
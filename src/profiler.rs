@@ -0,0 +1,198 @@
+// Instruction count and wall time profiler.
+//
+// As an observer it sees every call and return, and charges the instructions
+// and time since the last one to the call stack they ran in. Self counts go to
+// the innermost function, inclusive ones to every function on the stack
+// (once, so recursion isn't counted twice). Add it right before running: time
+// is wall time since the last event, including any pauses between run()s.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use interpreter::{Interpreter, StackFrame};
+use observer::Observer;
+use error::RuntimeError;
+use runtime::Status;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FunctionProfile {
+    pub self_instrs: u64,
+    pub incl_instrs: u64,
+    pub self_time: Duration,
+    pub incl_time: Duration,
+}
+
+#[derive(Debug)]
+pub struct Profiler {
+    // By function id, MAIN_ID for main
+    pub functions: HashMap<u32, FunctionProfile>,
+    // Self instructions by call stack (function ids, outermost first)
+    pub stacks: HashMap<Vec<u32>, u64>,
+    // Steps and time at the last call/return
+    last_steps: u64,
+    last_time: Instant,
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler {
+            functions: HashMap::new(),
+            stacks: HashMap::new(),
+            last_steps: 0,
+            last_time: Instant::now(),
+        }
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Default::default()
+    }
+
+    // Charges everything since the last event to the innermost frame of `call_stack`
+    fn segment(&mut self, inpr: &Interpreter, call_stack: &[StackFrame]) {
+        let now = Instant::now();
+        let instrs = inpr.steps - self.last_steps;
+        let time = now - self.last_time;
+        self.last_steps = inpr.steps;
+        self.last_time = now;
+        self.record(call_stack, instrs, time);
+    }
+
+    // The innermost frame of `call_stack` ran `instrs` instructions in `time`
+    fn record(&mut self, call_stack: &[StackFrame], instrs: u64, time: Duration) {
+        let ids: Vec<u32> = call_stack.iter().map(|frame| frame.id).collect();
+        let (&top, _) = match ids.split_last() {
+            Some(split) => split,
+            None => return,
+        };
+
+        {
+            let profile = self.functions.entry(top).or_default();
+            profile.self_instrs += instrs;
+            profile.self_time += time;
+        }
+        for (i, id) in ids.iter().enumerate() {
+            if ids[..i].contains(id) {
+                continue;
+            }
+            let profile = self.functions.entry(*id).or_default();
+            profile.incl_instrs += instrs;
+            profile.incl_time += time;
+        }
+        if instrs != 0 {
+            *self.stacks.entry(ids).or_insert(0) += instrs;
+        }
+    }
+
+    // Summary table, hottest (by self instructions) first
+    pub fn report(&self, interpreter: &Interpreter) -> String {
+        let mut functions: Vec<(&u32, &FunctionProfile)> = self.functions.iter().collect();
+        functions.sort_by(|a, b| b.1.self_instrs.cmp(&a.1.self_instrs).then(a.0.cmp(b.0)));
+
+        let mut report = format!("{:<24} {:>14} {:>14} {:>12} {:>12}\n",
+                                 "function", "self instrs", "incl instrs", "self ms", "incl ms");
+        for (&id, profile) in functions {
+            report.push_str(&format!("{:<24} {:>14} {:>14} {:>12.3} {:>12.3}\n",
+                                     interpreter.function_name(id),
+                                     profile.self_instrs, profile.incl_instrs,
+                                     millis(profile.self_time), millis(profile.incl_time)));
+        }
+        report
+    }
+
+    // Collapsed stacks ("main;fib;fib 1234" per line), weighted by self
+    // instructions, for flamegraph.pl, inferno and friends
+    pub fn write_collapsed(&self, interpreter: &Interpreter, out: &mut dyn Write) -> io::Result<()> {
+        let mut stacks: Vec<String> = self.stacks.iter()
+            .map(|(ids, instrs)| {
+                let names: Vec<String> = ids.iter()
+                    .map(|&id| interpreter.function_name(id).replace([';', ' '], "_"))
+                    .collect();
+                format!("{} {}", names.join(";"), instrs)
+            })
+            .collect();
+        stacks.sort();
+        for line in stacks {
+            writeln!(out, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl Observer for Profiler {
+    fn on_call(&mut self, inpr: &Interpreter, call_stack: &[StackFrame]) {
+        // Up to the CALL, the caller was running
+        self.segment(inpr, &call_stack[..call_stack.len() - 1]);
+    }
+
+    fn on_return(&mut self, inpr: &Interpreter, call_stack: &[StackFrame], _value: Option<i32>) {
+        self.segment(inpr, call_stack);
+    }
+
+    fn on_error(&mut self, inpr: &Interpreter, call_stack: &[StackFrame], _error: &RuntimeError) {
+        self.segment(inpr, call_stack);
+    }
+
+    fn on_stop(&mut self, inpr: &Interpreter, call_stack: &[StackFrame], _status: &Status) {
+        self.segment(inpr, call_stack);
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+
+#[cfg(test)]
+mod profiler_tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use Options;
+    use assembler::{Assembler, ModuleBuilder};
+    use instructions::Instruction as Instr;
+    use interpreter::MAIN_ID;
+    use runtime::Runtime;
+
+    #[test]
+    fn attribution() {
+        // main calls f twice, f calls g once
+        let g = Assembler::new().iconst(1).op(Instr::RETURN).finish();
+        let f = Assembler::new().op_with(Instr::CALL, 1).op(Instr::RETURN).finish();
+        let main = Assembler::new()
+            .op_with(Instr::CALL, 0).op_with(Instr::CALL, 0).op(Instr::ADD)
+            .finish();
+        let data = ModuleBuilder::new()
+            .func(0, "f", 0, 0, &f)
+            .func(1, "g", 0, 0, &g)
+            .main(&main)
+            .build();
+
+        let profiler = Rc::new(RefCell::new(Profiler::new()));
+        let mut runtime = Runtime::new(data, Options::default()).unwrap();
+        runtime.add_observer(Box::new(Rc::clone(&profiler)));
+        runtime.run().unwrap();
+        let profiler = profiler.borrow();
+
+        let instrs = |id| {
+            let profile = profiler.functions[&id];
+            (profile.self_instrs, profile.incl_instrs)
+        };
+        assert_eq!(instrs(MAIN_ID), (3, 11));
+        assert_eq!(instrs(0), (4, 8));
+        assert_eq!(instrs(1), (4, 4));
+
+        let mut collapsed = Vec::new();
+        profiler.write_collapsed(runtime.interpreter(), &mut collapsed).unwrap();
+        assert_eq!(String::from_utf8(collapsed).unwrap(), "main 3\nmain;f 4\nmain;f;g 4\n");
+
+        let report = profiler.report(runtime.interpreter());
+        let names: Vec<&str> = report.lines().skip(1)
+            .map(|l| l.split_whitespace().next().unwrap())
+            .collect();
+        assert_eq!(names, vec!["f", "g", "main"]);
+    }
+}
@@ -0,0 +1,195 @@
+// Recording of everything that enters a program from outside the VM, and
+// replaying it so a run can be reproduced exactly, instruction for
+// instruction, without the host it first ran on.
+//
+// A recording starts with the magic "RRPL", a version byte and the program's
+// arguments (count, count * (count, count * byte)), followed by one record per
+// event, each starting with an EventKind byte:
+//   READ_INT  step (u64), value, status
+// Everything is big endian, counts and unlabeled fields are u32 (values are
+// i32). Events are written as they happen, so a crashed run still leaves a
+// recording of everything up to the crash.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use num::FromPrimitive;
+
+use error::{LoadError, RuntimeError};
+
+pub const REPLAY_MAGIC: u32 = 0x5252504C;
+pub const REPLAY_VERSION: u8 = 1;
+
+enum_from_primitive! {
+#[allow(non_camel_case_types)]
+#[derive(Debug, PartialEq)]
+pub enum EventKind {
+    READ_INT = 0x01,
+}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    // READ_INT at `step` (counting itself) pushed `value` and `status`
+    ReadInt { step: u64, value: i32, status: i32 },
+}
+
+impl Event {
+    pub fn step(&self) -> u64 {
+        match *self {
+            Event::ReadInt { step, .. } => step,
+        }
+    }
+
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        // One write per record, so it's never cut in half by an error
+        let mut record = Vec::new();
+        match *self {
+            Event::ReadInt { step, value, status } => {
+                record.write_u8(EventKind::READ_INT as u8)?;
+                record.write_u64::<BigEndian>(step)?;
+                record.write_i32::<BigEndian>(value)?;
+                record.write_i32::<BigEndian>(status)?;
+            },
+        }
+        out.write_all(&record)?;
+        out.flush()
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Recording {
+    pub args: Vec<String>,
+    // Not replayed yet, in order
+    pub events: VecDeque<Event>,
+}
+
+impl Recording {
+    // The next event, which has to happen at `step`
+    pub fn next(&mut self, step: u64) -> Result<Event, RuntimeError> {
+        match self.events.front() {
+            Some(event) if event.step() == step => {},
+            _ => return Err(RuntimeError::ReplayDiverged(step)),
+        }
+        Ok(self.events.pop_front().unwrap())
+    }
+
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        write_header(out, &self.args)?;
+        for event in &self.events {
+            event.write(out)?;
+        }
+        Ok(())
+    }
+}
+
+// What happens to values entering the VM
+#[derive(Default)]
+pub enum Replay {
+    #[default]
+    Off,
+    // Log them here
+    Record(Box<dyn Write>),
+    // Take them from the recording instead of the host
+    Replay(Recording),
+}
+
+pub fn write_header(out: &mut dyn Write, args: &[String]) -> io::Result<()> {
+    let mut header = Vec::new();
+    header.write_u32::<BigEndian>(REPLAY_MAGIC)?;
+    header.write_u8(REPLAY_VERSION)?;
+    header.write_u32::<BigEndian>(args.len() as u32)?;
+    for arg in args {
+        header.write_u32::<BigEndian>(arg.len() as u32)?;
+        header.extend_from_slice(arg.as_bytes());
+    }
+    out.write_all(&header)?;
+    out.flush()
+}
+
+pub fn read_recording(data: &[u8]) -> Result<Recording, LoadError> {
+    let mut rest = data;
+    let len = data.len();
+    macro_rules! read {
+        ($f:ident) => ({
+            let pos = len - rest.len();
+            rest.$f::<BigEndian>().map_err(|_| LoadError::UnexpectedEnd(pos))?
+        })
+    }
+
+    let magic = read!(read_u32);
+    if magic != REPLAY_MAGIC {
+        return Err(LoadError::InvalidMagic(magic));
+    }
+    let version = rest.read_u8().map_err(|_| LoadError::UnexpectedEnd(4))?;
+    if version != REPLAY_VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+
+    let mut recording = Recording::default();
+    let arg_count = read!(read_u32);
+    // Don't trust the count for allocating
+    if arg_count as u64 * 4 > rest.len() as u64 {
+        return Err(LoadError::UnexpectedEnd(len));
+    }
+    for _ in 0..arg_count {
+        let pos = len - rest.len();
+        let arg_len = read!(read_u32) as usize;
+        if arg_len > rest.len() {
+            return Err(LoadError::UnexpectedEnd(len));
+        }
+        let (arg, after) = rest.split_at(arg_len);
+        rest = after;
+        let arg = String::from_utf8(arg.to_vec()).map_err(|_| LoadError::InvalidString(pos))?;
+        recording.args.push(arg);
+    }
+
+    while !rest.is_empty() {
+        let pos = len - rest.len();
+        let byte = rest.read_u8().unwrap();
+        let event = match EventKind::from_u8(byte) {
+            Some(EventKind::READ_INT) => Event::ReadInt {
+                step: read!(read_u64),
+                value: read!(read_i32),
+                status: read!(read_i32),
+            },
+            // Events can't be skipped without knowing their size
+            None => return Err(LoadError::InvalidEvent(pos)),
+        };
+        recording.events.push_back(event);
+    }
+    Ok(recording)
+}
+
+
+#[cfg(test)]
+mod replay_tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut recording = Recording {
+            args: vec!["1".to_string(), "".to_string()],
+            ..Default::default()
+        };
+        recording.events.push_back(Event::ReadInt { step: 3, value: -7, status: 1 });
+        recording.events.push_back(Event::ReadInt { step: 9, value: 0, status: 0 });
+        let mut data = Vec::new();
+        recording.write(&mut data).unwrap();
+        assert_eq!(read_recording(&data), Ok(recording.clone()));
+
+        // 18 bytes of header, 17 per event. Cut between two events, it's just
+        // a shorter recording.
+        for len in 0..data.len() {
+            if len == 18 || len == 35 {
+                continue;
+            }
+            assert!(read_recording(&data[..len]).is_err(), "{}", len);
+        }
+
+        assert_eq!(recording.next(2), Err(RuntimeError::ReplayDiverged(2)));
+        assert_eq!(recording.next(3), Ok(Event::ReadInt { step: 3, value: -7, status: 1 }));
+        assert_eq!(recording.next(9).unwrap().step(), 9);
+        assert_eq!(recording.next(10), Err(RuntimeError::ReplayDiverged(10)));
+    }
+}
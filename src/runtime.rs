@@ -1,52 +1,363 @@
-use interpreter::{Interpreter, StackFrame};
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use interpreter::{Interpreter, StackFrame, Dispatch, MAIN_ID};
+use snapshot::Snapshot;
+use replay::{self, Recording, Replay};
+use watch::{Watchpoint, WatchHit};
+use output::Capture;
+#[cfg(feature = "observers")]
+use observer::Observer;
 use raptor_object::RaptorObject;
+use error::{LoadError, RuntimeError, MAX_CALL_DEPTH, MAX_STACK_LOCALS};
+
+// How a call to Runtime::run ended
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    // The program ran to its end
+    Finished,
+    // HALT was issued
+    Halted,
+    // The step limit was reached, run() again to resume
+    Paused,
+    // Stopped before an instruction because the interrupt handle was set,
+    // run() again to resume
+    Interrupted,
+}
 
 #[derive(Debug, Default)]
 pub struct Runtime {
+    // The module as it was loaded, for snapshots
+    module: Rc<[u8]>,
     interpreter: Interpreter,
     call_stack: Vec<StackFrame>,
+    // Locals of all the frames in call_stack, kept under MAX_STACK_LOCALS
+    stack_locals: usize,
+    // The outermost frame was pushed by new() or enter() and observers
+    // weren't told yet
+    entered: bool,
     options: ::Options,
+    // What the outermost frame returned
+    return_value: Option<i32>,
+    #[allow(dead_code)] // Unused until objects are implemented
     memory: Vec<RaptorObject>
 }
 
 impl Runtime {
-    pub fn new(data: Vec<u8>, options: ::Options) -> Runtime {
+    pub fn new(data: Vec<u8>, options: ::Options) -> Result<Runtime, LoadError> {
         let mut r = Runtime {
-            interpreter: Interpreter::new(data, options.debug),
+            module: data.as_slice().into(),
+            interpreter: Interpreter::new(data, options.debug)?,
             call_stack: Vec::new(),
-            options: options,
+            stack_locals: 0,
+            entered: true,
+            options,
+            return_value: None,
             memory: vec![RaptorObject::new()],
         };
-        let prog_bc = r.interpreter.prog_bytecode.clone();
-        r.call_stack.push(
-            StackFrame {
-                bytecode: prog_bc,
-                ..Default::default()
-            });
-        r
-    }
-    pub fn run(&mut self) {
+        r.interpreter.args = r.options.args.clone();
+        let frame = r.interpreter.entry_frame();
+        r.stack_locals = frame.locals.len();
+        r.call_stack.push(frame);
+        Ok(r)
+    }
+
+    // Everything needed to resume from here with Runtime::restore
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            module: Rc::clone(&self.module),
+            steps: self.interpreter.steps,
+            op_stack: self.interpreter.op_stack.clone(),
+            globals: self.interpreter.globals().to_vec(),
+            args: self.interpreter.args.clone(),
+            return_value: self.return_value,
+            call_stack: self.call_stack.clone(),
+        }
+    }
+
+    // Loads the snapshot's module and puts the runtime back in the state it
+    // was saved in. The program's arguments come from the snapshot, not `options`.
+    pub fn restore(snapshot: &Snapshot, options: ::Options) -> Result<Runtime, LoadError> {
+        let mut r = Runtime::new(snapshot.module.to_vec(), options)?;
+        r.interpreter.args = snapshot.args.clone();
+        r.rewind(snapshot)?;
+        Ok(r)
+    }
+
+    // Puts the runtime back in the state of a snapshot of the same module.
+    // Unlike restore() it keeps the output, input, observers and arguments.
+    pub fn rewind(&mut self, snapshot: &Snapshot) -> Result<(), LoadError> {
+        if snapshot.module != self.module {
+            return Err(LoadError::InvalidSnapshot("module"));
+        }
+        if snapshot.globals.len() != self.interpreter.globals().len() {
+            return Err(LoadError::InvalidSnapshot("globals"));
+        }
+
+        let mut call_stack = Vec::new();
+        let mut stack_locals = 0;
+        for frame in &snapshot.call_stack {
+            let (code, local_count) = if frame.id == MAIN_ID {
+                (Rc::clone(&self.interpreter.prog_code), 0)
+            } else {
+                match self.interpreter.function(frame.id) {
+                    Some(func_const) => (Rc::clone(&func_const.code),
+                                         func_const.arg_count + func_const.local_count),
+                    None => return Err(LoadError::InvalidSnapshot("function id")),
+                }
+            };
+            stack_locals += frame.locals.len();
+            if frame.locals.len() != local_count as usize || stack_locals > MAX_STACK_LOCALS {
+                return Err(LoadError::InvalidSnapshot("locals"));
+            }
+            if frame.bc_counter > code.ops.len() {
+                return Err(LoadError::InvalidSnapshot("bytecode counter"));
+            }
+            if frame.return_addr > snapshot.op_stack.len() {
+                return Err(LoadError::InvalidSnapshot("return address"));
+            }
+            call_stack.push(StackFrame { code, ..frame.clone() });
+        }
+
+        self.call_stack = call_stack;
+        self.stack_locals = stack_locals;
+        self.entered = false;
+        self.interpreter.globals_mut().copy_from_slice(&snapshot.globals);
+        self.interpreter.op_stack = snapshot.op_stack.clone();
+        self.interpreter.steps = snapshot.steps;
+        self.interpreter.watch_hit = None;
+        self.return_value = snapshot.return_value;
+        Ok(())
+    }
+
+    pub fn interpreter(&self) -> &Interpreter {
+        &self.interpreter
+    }
+
+    // For debuggers and other tools that change the program's state
+    pub fn interpreter_mut(&mut self) -> &mut Interpreter {
+        &mut self.interpreter
+    }
+
+    // Innermost frame last. Empty once the program finished; after a HALT,
+    // an error or any other stop the frames are still there to look at.
+    pub fn call_stack(&self) -> &[StackFrame] {
+        &self.call_stack
+    }
+
+    // Makes the next run() call function `id` with `args` (first argument
+    // first), instead of running whatever was left to run
+    pub fn enter(&mut self, id: u32, args: &[i32]) -> Result<(), RuntimeError> {
+        let mut frame = {
+            let func_const = match self.interpreter.function(id) {
+                Some(func_const) => func_const,
+                None => return Err(RuntimeError::UnknownFunction(id)),
+            };
+            if func_const.arg_count as usize != args.len() {
+                return Err(RuntimeError::WrongArgCount(id, func_const.arg_count, args.len()));
+            }
+            StackFrame::for_function(id, func_const)
+        };
+        frame.locals[..args.len()].copy_from_slice(args);
+        frame.return_addr = self.interpreter.op_stack.len();
+        self.stack_locals = frame.locals.len();
+        self.call_stack = vec![frame];
+        self.entered = true;
+        self.return_value = None;
+        Ok(())
+    }
+
+    // What the outermost frame returned with RETURN, once run() finished.
+    // None if it ran to its end without one, or didn't finish.
+    pub fn return_value(&self) -> Option<i32> {
+        self.return_value
+    }
+
+    // Stop (with Status::Paused) once `limit` instructions were executed in total
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.interpreter.step_limit = limit.unwrap_or(u64::MAX);
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.interpreter.steps
+    }
+
+    // Where PRINT, DUMP_STACK, DUMP_GLOBALS and HALT write to, instead of stdout
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.interpreter.output = Some(output);
+    }
+
+    // Sends program output to a buffer, and returns a handle to read it
+    pub fn capture_output(&mut self) -> Capture {
+        let capture = Capture::new();
+        self.set_output(Box::new(capture.clone()));
+        capture
+    }
+
+    // Where READ_INT reads from, instead of stdin
+    pub fn set_input(&mut self, input: Box<dyn BufRead>) {
+        self.interpreter.input = Some(input);
+    }
+
+    // Logs the program's arguments and everything it reads to `out`, for replay
+    pub fn record(&mut self, mut out: Box<dyn Write>) -> Result<(), RuntimeError> {
+        replay::write_header(&mut out, &self.interpreter.args)
+            .map_err(|e| RuntimeError::Record(e.kind()))?;
+        self.interpreter.replay = Replay::Record(out);
+        Ok(())
+    }
+
+    // Runs with the arguments and input of a recorded run instead of the real
+    // ones. run() fails with ReplayDiverged if the program reads at a step
+    // the recording didn't, or with ReplayUnfinished if the program ends before
+    // it read everything the recording has.
+    pub fn replay(&mut self, recording: Recording) {
+        self.interpreter.args = recording.args.clone();
+        self.interpreter.replay = Replay::Replay(recording);
+    }
+
+    // Setting it makes run() stop with Status::Interrupted before the next
+    // instruction, from a signal handler or another thread. It's cleared when
+    // run() stops for it.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.interpreter.interrupt)
+    }
+
+    // Returns its index, for WatchHit::index
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.interpreter.watchpoints.push(watchpoint);
+        self.interpreter.watchpoints.len() - 1
+    }
+
+    // The watchpoint that paused the last run() or step(), if one did
+    pub fn watch_hit(&self) -> Option<WatchHit> {
+        self.interpreter.watch_hit
+    }
+
+    // Observers are called in the order they were added, see observer.rs
+    #[cfg(feature = "observers")]
+    pub fn add_observer(&mut self, observer: Box<dyn Observer>) {
+        self.interpreter.add_observer(observer);
+    }
+
+    #[cfg(feature = "observers")]
+    pub fn take_observers(&mut self) -> Vec<Box<dyn Observer>> {
+        self.interpreter.take_observers()
+    }
+
+    // Where the innermost frame is, see Interpreter::location.
+    // After an error that's the instruction that failed.
+    pub fn location(&self) -> Option<String> {
+        self.call_stack.last().map(|frame| self.interpreter.location(frame))
+    }
+
+    // The Raptor call stack, innermost frame first, one line per frame.
+    // After run() returns an error the frames are still there to walk.
+    pub fn stack_trace(&self) -> Vec<String> {
+        self.call_stack.iter().rev().enumerate()
+            .map(|(i, frame)| format!("#{} {}", i, self.interpreter.describe_frame(frame)))
+            .collect()
+    }
+
+    pub fn run(&mut self) -> Result<Status, RuntimeError> {
         debug!("Running...");
 
+        let result = self.run_frames();
+        {
+            let call_stack = &self.call_stack;
+            match result {
+                Ok(ref status) =>
+                    self.interpreter.notify(|o, i| o.on_stop(i, call_stack, status)),
+                Err(ref e) =>
+                    self.interpreter.notify(|o, i| o.on_error(i, call_stack, e)),
+            }
+        }
+        result
+    }
+
+    // Executes a single instruction, leaving any frames that ran out of ops on
+    // the way. Observers don't get on_stop or on_error.
+    pub fn step(&mut self) -> Result<Status, RuntimeError> {
+        let limit = self.interpreter.step_limit;
+        if self.interpreter.steps < limit {
+            self.interpreter.step_limit = self.interpreter.steps + 1;
+        }
+        let result = self.run_frames();
+        self.interpreter.step_limit = limit;
+        result
+    }
+
+    fn run_frames(&mut self) -> Result<Status, RuntimeError> {
         let debug = self.options.debug;
+        self.interpreter.watch_hit = None;
+        if self.entered {
+            self.entered = false;
+            let call_stack = &self.call_stack;
+            self.interpreter.notify(|o, i| o.on_enter(i, call_stack));
+        }
 
-        while self.call_stack.len() > 0 {
+        while !self.call_stack.is_empty() {
             let dispatch_result = {
                 let ln = self.call_stack.len();
-                let mut last_frame = &mut self.call_stack[ln-1];
-                last_frame.dispatch(&mut self.interpreter, debug)
+                let last_frame = &mut self.call_stack[ln-1];
+                last_frame.dispatch(&mut self.interpreter, debug)?
             };
             // Push the new StackFrame, if CALL was issued
             match dispatch_result {
-                None => {
+                Dispatch::Return(value) => {
+                    {
+                        let call_stack = &self.call_stack;
+                        self.interpreter.notify(|o, i| o.on_return(i, call_stack, value));
+                    }
                     debug!("Popped a frame. Current frame: {:?}",
                            self.call_stack[self.call_stack.len()-1]);
                     debug!("Op stack: {:?}", self.interpreter.op_stack);
-                    self.call_stack.pop();
+                    if let Some(frame) = self.call_stack.pop() {
+                        self.stack_locals -= frame.locals.len();
+                    }
+                    if self.call_stack.is_empty() {
+                        self.return_value = value;
+                    }
+                },
+                Dispatch::Call(frm) => {
+                    if self.call_stack.len() >= MAX_CALL_DEPTH
+                        || self.stack_locals + frm.locals.len() > MAX_STACK_LOCALS {
+                        return Err(RuntimeError::CallStackOverflow);
+                    }
+                    self.stack_locals += frm.locals.len();
+                    self.call_stack.push(frm);
+                    let call_stack = &self.call_stack;
+                    self.interpreter.notify(|o, i| o.on_call(i, call_stack));
+                },
+                Dispatch::Halt => {
+                    debug!("Halted");
+                    self.check_replayed()?;
+                    return Ok(Status::Halted);
+                },
+                Dispatch::Pause => {
+                    debug!("Paused after {} steps", self.interpreter.steps);
+                    return Ok(Status::Paused);
+                },
+                Dispatch::Interrupt => {
+                    debug!("Interrupted after {} steps", self.interpreter.steps);
+                    self.interpreter.interrupt.store(false, Ordering::Relaxed);
+                    return Ok(Status::Interrupted);
                 },
-                Some(frm) => {self.call_stack.push(frm);},
             }
         }
+        self.check_replayed()?;
+        Ok(Status::Finished)
+    }
+
+    // Once the program ended, a replay that didn't use up its recording diverged
+    fn check_replayed(&self) -> Result<(), RuntimeError> {
+        match self.interpreter.replay {
+            Replay::Replay(ref recording) if !recording.events.is_empty() =>
+                Err(RuntimeError::ReplayUnfinished(recording.events.len())),
+            _ => Ok(()),
+        }
     }
 }
 
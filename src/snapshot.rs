@@ -0,0 +1,205 @@
+// Everything needed to resume a runtime later, possibly in another process.
+//
+// The file starts with the magic "RSNP" and a version byte, followed by
+//   module        count, count * byte      the module as it was loaded
+//   steps         u64
+//   op stack      count, count * i32
+//   globals       count, count * i32
+//   args          count, count * (count, count * byte)
+//   return value  u8 (0 for none), i32
+//   call stack    count, count * frame, outermost first
+// and each frame is
+//   id, return addr, bc counter, count, count * i32 (locals)
+// Everything is big endian, counts and unlabeled fields are u32. The heap
+// isn't saved, there's nothing on it until objects are implemented. Neither
+// is how far input or output got: a restored runtime reads from whatever
+// input it's given.
+
+use std::io::{self, Write};
+use std::rc::Rc;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use interpreter::StackFrame;
+use error::LoadError;
+
+pub const SNAPSHOT_MAGIC: u32 = 0x52534E50;
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+#[derive(Debug, Default, Clone)]
+pub struct Snapshot {
+    pub module: Rc<[u8]>,
+    pub steps: u64,
+    pub op_stack: Vec<i32>,
+    pub globals: Vec<i32>,
+    pub args: Vec<String>,
+    pub return_value: Option<i32>,
+    // The frames' code isn't saved, Runtime::restore finds it by id
+    pub call_stack: Vec<StackFrame>,
+}
+
+impl Snapshot {
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_u32::<BigEndian>(SNAPSHOT_MAGIC)?;
+        out.write_u8(SNAPSHOT_VERSION)?;
+        write_bytes(out, &self.module)?;
+        out.write_u64::<BigEndian>(self.steps)?;
+        write_i32s(out, &self.op_stack)?;
+        write_i32s(out, &self.globals)?;
+        out.write_u32::<BigEndian>(self.args.len() as u32)?;
+        for arg in &self.args {
+            write_bytes(out, arg.as_bytes())?;
+        }
+        out.write_u8(self.return_value.is_some() as u8)?;
+        out.write_i32::<BigEndian>(self.return_value.unwrap_or(0))?;
+        out.write_u32::<BigEndian>(self.call_stack.len() as u32)?;
+        for frame in &self.call_stack {
+            out.write_u32::<BigEndian>(frame.id)?;
+            out.write_u32::<BigEndian>(frame.return_addr as u32)?;
+            out.write_u32::<BigEndian>(frame.bc_counter as u32)?;
+            write_i32s(out, &frame.locals)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        self.write(&mut data).unwrap();
+        data
+    }
+}
+
+fn write_bytes(out: &mut dyn Write, bytes: &[u8]) -> io::Result<()> {
+    out.write_u32::<BigEndian>(bytes.len() as u32)?;
+    out.write_all(bytes)
+}
+
+fn write_i32s(out: &mut dyn Write, values: &[i32]) -> io::Result<()> {
+    out.write_u32::<BigEndian>(values.len() as u32)?;
+    for &value in values {
+        out.write_i32::<BigEndian>(value)?;
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    len: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn pos(&self) -> usize {
+        self.len - self.data.len()
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        let pos = self.pos();
+        self.data.read_u8().map_err(|_| LoadError::UnexpectedEnd(pos))
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        let pos = self.pos();
+        self.data.read_u32::<BigEndian>().map_err(|_| LoadError::UnexpectedEnd(pos))
+    }
+
+    fn u64(&mut self) -> Result<u64, LoadError> {
+        let pos = self.pos();
+        self.data.read_u64::<BigEndian>().map_err(|_| LoadError::UnexpectedEnd(pos))
+    }
+
+    fn i32(&mut self) -> Result<i32, LoadError> {
+        Ok(self.u32()? as i32)
+    }
+
+    // Counts come from the file, don't preallocate with them
+    fn count(&mut self, item_size: usize) -> Result<usize, LoadError> {
+        let count = self.u32()? as usize;
+        if count as u64 * item_size as u64 > self.data.len() as u64 {
+            return Err(LoadError::UnexpectedEnd(self.len));
+        }
+        Ok(count)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], LoadError> {
+        let count = self.count(1)?;
+        let (bytes, rest) = self.data.split_at(count);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn i32s(&mut self) -> Result<Vec<i32>, LoadError> {
+        let count = self.count(4)?;
+        (0..count).map(|_| self.i32()).collect()
+    }
+}
+
+pub fn read_snapshot(data: &[u8]) -> Result<Snapshot, LoadError> {
+    let mut reader = Reader { data, len: data.len() };
+
+    let magic = reader.u32()?;
+    if magic != SNAPSHOT_MAGIC {
+        return Err(LoadError::InvalidMagic(magic));
+    }
+    let version = reader.u8()?;
+    if version != SNAPSHOT_VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+
+    let module = reader.bytes()?.into();
+    let steps = reader.u64()?;
+    let op_stack = reader.i32s()?;
+    let globals = reader.i32s()?;
+    let mut args = Vec::new();
+    for _ in 0..reader.count(4)? {
+        let pos = reader.pos();
+        let arg = String::from_utf8(reader.bytes()?.to_vec())
+            .map_err(|_| LoadError::InvalidString(pos))?;
+        args.push(arg);
+    }
+    let return_value = match (reader.u8()?, reader.i32()?) {
+        (0, _) => None,
+        (_, value) => Some(value),
+    };
+    let mut call_stack = Vec::new();
+    for _ in 0..reader.count(16)? {
+        call_stack.push(StackFrame {
+            id: reader.u32()?,
+            return_addr: reader.u32()? as usize,
+            bc_counter: reader.u32()? as usize,
+            locals: reader.i32s()?,
+            ..Default::default()
+        });
+    }
+
+    Ok(Snapshot { module, steps, op_stack, globals, args, return_value, call_stack })
+}
+
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let snapshot = Snapshot {
+            module: vec![1, 2, 3].into(),
+            steps: 1 << 40,
+            op_stack: vec![-1, 2],
+            globals: vec![3],
+            args: vec!["x".to_string(), "-4".to_string()],
+            return_value: Some(-5),
+            call_stack: vec![
+                StackFrame { id: 7, locals: vec![8, 9], return_addr: 1, bc_counter: 10,
+                             ..Default::default() },
+            ],
+        };
+        let data = snapshot.to_bytes();
+        let read = read_snapshot(&data).unwrap();
+        assert_eq!(read.to_bytes(), data);
+        assert_eq!(read.call_stack[0].locals, vec![8, 9]);
+        assert_eq!(read.return_value, Some(-5));
+
+        for len in 0..data.len() {
+            assert!(read_snapshot(&data[..len]).is_err(), "{}", len);
+        }
+    }
+}
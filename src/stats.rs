@@ -0,0 +1,195 @@
+// Execution statistics: an opcode histogram, calls per function and how
+// deep the op stack and call stack got. Add it before running, or the
+// counters only cover what ran after.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+use num::FromPrimitive;
+
+use decoder::Op;
+use instructions::Instruction;
+use interpreter::{Interpreter, StackFrame, MAIN_ID};
+use observer::{Observer, Step};
+use error::RuntimeError;
+use runtime::Status;
+
+#[derive(Debug)]
+pub struct Stats {
+    // Executed instructions by opcode
+    pub op_counts: [u64; 256],
+    // Calls by callee id, CALLs and the function a run entered
+    pub calls: BTreeMap<u32, u64>,
+    pub max_op_stack: usize,
+    pub max_call_stack: usize,
+    pub instructions: u64,
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats {
+            op_counts: [0; 256],
+            calls: BTreeMap::new(),
+            max_op_stack: 0,
+            max_call_stack: 0,
+            instructions: 0,
+        }
+    }
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Default::default()
+    }
+
+    // `op` was executed, leaving `op_stack` values on the op stack
+    pub fn record(&mut self, op: Op, op_stack: usize) {
+        self.instructions += 1;
+        self.op_counts[op.instruction() as usize] += 1;
+        if let Op::CALL(id) = op {
+            self.record_call(id);
+        }
+        if op_stack > self.max_op_stack {
+            self.max_op_stack = op_stack;
+        }
+    }
+
+    fn record_call(&mut self, id: u32) {
+        *self.calls.entry(id).or_insert(0) += 1;
+    }
+
+    fn record_call_stack(&mut self, depth: usize) {
+        if depth > self.max_call_stack {
+            self.max_call_stack = depth;
+        }
+    }
+
+    pub fn count(&self, instr: Instruction) -> u64 {
+        self.op_counts[instr as usize]
+    }
+
+    // Instructions that were executed and their counts, most frequent first
+    pub fn histogram(&self) -> Vec<(Instruction, u64)> {
+        let mut histogram: Vec<(Instruction, u64)> = self.op_counts.iter().enumerate()
+            .filter(|&(_, &count)| count != 0)
+            .filter_map(|(opcode, &count)| Instruction::from_usize(opcode).map(|i| (i, count)))
+            .collect();
+        histogram.sort_by_key(|&(_, count)| Reverse(count));
+        histogram
+    }
+
+    pub fn report(&self, interpreter: &Interpreter) -> String {
+        let mut report = format!("instructions executed: {}\n", self.instructions);
+        report.push_str(&format!("max op stack depth: {}\n", self.max_op_stack));
+        report.push_str(&format!("max call stack depth: {}\n", self.max_call_stack));
+
+        report.push_str("calls:\n");
+        let mut calls: Vec<(&u32, &u64)> = self.calls.iter().collect();
+        calls.sort_by(|a, b| b.1.cmp(a.1));
+        for (&id, count) in calls {
+            let name = format!("{} (id {})", interpreter.function_name(id), id);
+            report.push_str(&format!("  {:<24} {:>14}\n", name, count));
+        }
+
+        report.push_str("opcodes:\n");
+        for (instr, count) in self.histogram() {
+            report.push_str(&format!("  {:<24} {:>14}\n", format!("{:?}", instr), count));
+        }
+        report
+    }
+}
+
+
+impl Observer for Stats {
+    fn on_instruction(&mut self, inpr: &Interpreter, _frame: &StackFrame, step: &Step) {
+        self.record(step.op, inpr.op_stack.len());
+    }
+
+    fn on_enter(&mut self, _inpr: &Interpreter, call_stack: &[StackFrame]) {
+        // main isn't a function, only an entry point given with --entry counts
+        if call_stack[0].id != MAIN_ID {
+            self.record_call(call_stack[0].id);
+        }
+        self.record_call_stack(call_stack.len());
+    }
+
+    fn on_call(&mut self, _inpr: &Interpreter, call_stack: &[StackFrame]) {
+        self.record_call_stack(call_stack.len());
+    }
+
+    fn on_return(&mut self, _inpr: &Interpreter, call_stack: &[StackFrame], _value: Option<i32>) {
+        self.record_call_stack(call_stack.len());
+    }
+
+    fn on_error(&mut self, _inpr: &Interpreter, call_stack: &[StackFrame], _error: &RuntimeError) {
+        self.record_call_stack(call_stack.len());
+    }
+
+    fn on_stop(&mut self, _inpr: &Interpreter, call_stack: &[StackFrame], _status: &Status) {
+        self.record_call_stack(call_stack.len());
+    }
+}
+
+#[cfg(test)]
+mod stats_tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use Options;
+    use assembler::{Assembler, ModuleBuilder};
+    use instructions::Instruction as Instr;
+    use runtime::Runtime;
+
+    #[test]
+    fn counters() {
+        // main calls f(1, 2), f calls g twice
+        let g = Assembler::new().iconst(1).op(Instr::RETURN).finish();
+        let f = Assembler::new()
+            .op_with(Instr::CALL, 1).op_with(Instr::CALL, 1).op(Instr::ADD).op(Instr::RETURN)
+            .finish();
+        let main = Assembler::new()
+            .iconst(1).iconst(2).op_with(Instr::CALL, 0).op(Instr::POP)
+            .finish();
+        let data = ModuleBuilder::new()
+            .func(0, "f", 2, 0, &f)
+            .func(1, "g", 0, 0, &g)
+            .main(&main)
+            .build();
+
+        let stats = Rc::new(RefCell::new(Stats::new()));
+        let mut runtime = Runtime::new(data, Options::default()).unwrap();
+        runtime.add_observer(Box::new(Rc::clone(&stats)));
+        runtime.run().unwrap();
+        let stats = stats.borrow();
+
+        assert_eq!(stats.instructions, 12);
+        assert_eq!(stats.instructions, runtime.steps());
+        assert_eq!(stats.count(Instr::CALL), 3);
+        assert_eq!(stats.count(Instr::ICONST), 4);
+        assert_eq!(stats.calls.iter().collect::<Vec<_>>(), vec![(&0, &1), (&1, &2)]);
+        assert_eq!(stats.max_op_stack, 2);
+        assert_eq!(stats.max_call_stack, 3);
+        assert_eq!(stats.histogram()[0], (Instr::ICONST, 4));
+    }
+
+    #[test]
+    fn without_calls() {
+        let g = Assembler::new().iconst(1).op(Instr::RETURN).finish();
+        let main = Assembler::new().iconst(1).op(Instr::POP).finish();
+        let data = ModuleBuilder::new().func(0, "g", 0, 0, &g).main(&main).build();
+
+        let stats = Rc::new(RefCell::new(Stats::new()));
+        let mut runtime = Runtime::new(data, Options::default()).unwrap();
+        runtime.add_observer(Box::new(Rc::clone(&stats)));
+        runtime.run().unwrap();
+        assert_eq!(stats.borrow().max_call_stack, 1);
+        assert!(stats.borrow().calls.is_empty());
+
+        // Entering g counts as a call to it
+        runtime.enter(0, &[]).unwrap();
+        runtime.run().unwrap();
+        assert_eq!(stats.borrow().calls.iter().collect::<Vec<_>>(), vec![(&0, &1)]);
+        assert_eq!(stats.borrow().max_call_stack, 1);
+    }
+}
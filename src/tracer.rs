@@ -0,0 +1,169 @@
+// Execution tracer: one JSON record per executed instruction, one per line.
+//   {"step":3,"func":0,"offset":10,"op":"ADD","operands":[],"top":5}
+// `step` counts from 1, `func` is MAIN_ID for main, jump operands are
+// bytecode offsets of the target and `top` is null when the op stack is empty.
+
+use std::fmt;
+use std::io::{self, Write};
+
+use decoder::{Code, Op};
+use interpreter::{Interpreter, StackFrame};
+use observer::{Observer, Step};
+
+pub struct Tracer {
+    out: Box<dyn Write>,
+    // Only trace these functions ("main" for main), all of them if empty
+    functions: Vec<String>,
+    // Inclusive range of steps to trace
+    first_step: u64,
+    last_step: u64,
+    // The first write error, tracing stops after it
+    error: Option<io::Error>,
+    // Whether the function of the last traced step passed the filter
+    last_function: Option<(u32, bool)>,
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tracer {{ functions: {:?}, steps: {}..={} }}",
+               self.functions, self.first_step, self.last_step)
+    }
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>) -> Tracer {
+        Tracer {
+            out,
+            functions: Vec::new(),
+            first_step: 0,
+            last_step: u64::MAX,
+            error: None,
+            last_function: None,
+        }
+    }
+
+    pub fn function(mut self, name: &str) -> Tracer {
+        self.functions.push(name.to_string());
+        self
+    }
+
+    pub fn steps(mut self, first: u64, last: u64) -> Tracer {
+        self.first_step = first;
+        self.last_step = last;
+        self
+    }
+
+    // Whether instructions of the function called `name` get traced
+    fn traces(&self, name: &str) -> bool {
+        self.functions.is_empty() || self.functions.iter().any(|f| f == name)
+    }
+
+    // Called after the op at `index` in `code` was executed as step `step`
+    fn record(&mut self, step: u64, func: u32, code: &Code, index: usize, top: Option<&i32>) {
+        if step < self.first_step || step > self.last_step || self.error.is_some() {
+            return;
+        }
+        let op = code.ops[index];
+        let operand = match op {
+            Op::ICONST(val) => Some(val as i64),
+            Op::STORE(index) | Op::LOAD(index) | Op::CALL(index) => Some(index as i64),
+            Op::RELJUMP(target) | Op::RELJUMP_GT(target) |
+            Op::RELJUMP_LT(target) | Op::RELJUMP_EQ(target) =>
+                Some(code.offset(target as usize) as i64),
+            _ => None,
+        };
+        let operands = match operand {
+            Some(val) => val.to_string(),
+            None => String::new(),
+        };
+        let top = match top {
+            Some(val) => val.to_string(),
+            None => "null".to_string(),
+        };
+        let result = writeln!(self.out,
+            "{{\"step\":{},\"func\":{},\"offset\":{},\"op\":\"{:?}\",\"operands\":[{}],\"top\":{}}}",
+            step, func, code.offset(index), op.instruction(), operands, top);
+        if let Err(e) = result {
+            self.error = Some(e);
+        }
+    }
+
+    // Flushes the output, and reports the first error if writing ever failed
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        }
+    }
+}
+
+
+impl Observer for Tracer {
+    fn on_instruction(&mut self, inpr: &Interpreter, frame: &StackFrame, step: &Step) {
+        let traced = match self.last_function {
+            Some((id, traced)) if id == frame.id => traced,
+            _ => {
+                let traced = self.traces(inpr.function_name(frame.id));
+                self.last_function = Some((frame.id, traced));
+                traced
+            }
+        };
+        if traced {
+            self.record(inpr.steps, frame.id, &frame.code, step.index, inpr.op_stack.last());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tracer_tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use Options;
+    use assembler::{Assembler, ModuleBuilder};
+    use instructions::Instruction as Instr;
+    use output::Capture;
+    use runtime::Runtime;
+
+    fn trace(tracer: fn(Tracer) -> Tracer) -> Vec<String> {
+        let f = Assembler::new()
+            .op_with(Instr::LOAD, 0).jump(Instr::RELJUMP_EQ, "end")
+            .iconst(1).op(Instr::RETURN)
+            .label("end")
+            .finish();
+        let main = Assembler::new().iconst(0).op_with(Instr::CALL, 0).iconst(7).finish();
+        let data = ModuleBuilder::new().func(0, "f", 1, 0, &f).main(&main).build();
+
+        let out = Capture::new();
+        let tracer = Rc::new(RefCell::new(tracer(Tracer::new(Box::new(out.clone())))));
+        let mut runtime = Runtime::new(data, Options::default()).unwrap();
+        runtime.add_observer(Box::new(Rc::clone(&tracer)));
+        runtime.run().unwrap();
+        tracer.borrow_mut().finish().unwrap();
+
+        out.contents().lines().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn records() {
+        assert_eq!(trace(|t| t), vec![
+            r#"{"step":1,"func":4294967295,"offset":0,"op":"ICONST","operands":[0],"top":0}"#,
+            r#"{"step":2,"func":4294967295,"offset":5,"op":"CALL","operands":[0],"top":null}"#,
+            r#"{"step":3,"func":0,"offset":0,"op":"LOAD","operands":[0],"top":0}"#,
+            r#"{"step":4,"func":0,"offset":5,"op":"RELJUMP_EQ","operands":[16],"top":null}"#,
+            r#"{"step":5,"func":4294967295,"offset":10,"op":"ICONST","operands":[7],"top":7}"#,
+        ]);
+    }
+
+    #[test]
+    fn filters() {
+        let lines = trace(|t| t.function("f"));
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|l| l.contains(r#""func":0"#)));
+
+        let lines = trace(|t| t.function("main").steps(2, 5));
+        let steps: Vec<&str> = lines.iter().map(|l| &l[..9]).collect();
+        assert_eq!(steps, vec![r#"{"step":2"#, r#"{"step":5"#]);
+    }
+}
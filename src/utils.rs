@@ -1,7 +1,6 @@
 use std::io;
 use std::io::Read;
 use std::fs::File;
-use std::error::Error;
 use std::path::Path;
 
 pub fn should_open<P: AsRef<Path>>(path: P) -> bool {
@@ -11,10 +10,13 @@ pub fn should_open<P: AsRef<Path>>(path: P) -> bool {
     }
     let file = path; // We know it's a file now
     
-    let mut ext_iter = ::ACCEPTABLE_EXTENSIONS.into_iter();
-    let extension = file.extension().unwrap();
+    let mut ext_iter = ::ACCEPTABLE_EXTENSIONS.iter();
+    let extension = match file.extension() {
+        Some(extension) => extension,
+        None => return false,
+    };
 
-    return ext_iter.any(|&e| e == extension);
+    ext_iter.any(|&e| e == extension)
 }
 
 // Wrapper for open_file
@@ -25,8 +27,7 @@ pub fn try_open_file<P: AsRef<Path>>(file_path: P, debug: bool) -> Vec<u8> {
     // Call open_file and handle Result
     match open_file(&file_path) {
         Err(why) => 
-            panic!("Couldn't open file {}: {}", file_display,
-                why.description()),
+            panic!("Couldn't open file {}: {}", file_display, why),
         Ok(data) => {
             if debug {
                 debug!("Read {} bytes from file: {}.", data.len(), file_display);
@@ -37,15 +38,15 @@ pub fn try_open_file<P: AsRef<Path>>(file_path: P, debug: bool) -> Vec<u8> {
 }
 
 fn open_file<P: AsRef<Path>>(file_path: P) -> io::Result<Vec<u8>> {
-    // try! to open the file
-    let mut file = try!(File::open(file_path));
+    // try to open the file
+    let mut file = File::open(file_path)?;
 
     // create the buffer
     let mut file_buffer: Vec<u8> = Vec::new();
 
-    // try! to read the data
-    try!(file.read_to_end(&mut file_buffer));
+    // try to read the data
+    file.read_to_end(&mut file_buffer)?;
 
     // no panic! issued so we're good
-    return Ok(file_buffer);
+    Ok(file_buffer)
 }
@@ -0,0 +1,209 @@
+// Watchpoints: pause or log when the program writes a global, or a local of
+// some function, optionally only if the new value passes a condition.
+//
+// Writes are checked by the instructions that do them (STORE for locals, and
+// any instruction that stores a global has to call Interpreter::check_write
+// too), so watchpoints cost nothing until one is set. A pausing watchpoint
+// stops run() right after the write with Status::Paused, and
+// Runtime::watch_hit tells which one it was. Logged writes go to the log at
+// info level. Every hit, pausing or logged, goes to Observer::on_watch.
+
+use std::fmt;
+
+use interpreter::Interpreter;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    // Index into the globals
+    Global(usize),
+    // Local index in every frame of a function (by id)
+    Local(u32, usize),
+}
+
+impl Target {
+    // Like "count" or "f.acc", with "global 3" or "f.local 1" for unnamed ones
+    pub fn describe(&self, inpr: &Interpreter) -> String {
+        match *self {
+            Target::Global(index) => match inpr.debug_info.global_name(index) {
+                Some(name) => name.to_string(),
+                None => format!("global {}", index),
+            },
+            Target::Local(func, index) => match inpr.debug_info.local_name(func, index) {
+                Some(name) => format!("{}.{}", inpr.function_name(func), name),
+                None => format!("{}.local {}", inpr.function_name(func), index),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compare {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+const COMPARE_OPS: [(&str, Compare); 6] = [
+    ("==", Compare::Eq), ("!=", Compare::Ne), ("<=", Compare::Le),
+    (">=", Compare::Ge), ("<", Compare::Lt), (">", Compare::Gt),
+];
+
+// On the value written, like "< 0"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Condition {
+    pub compare: Compare,
+    pub value: i32,
+}
+
+impl Condition {
+    pub fn holds(&self, value: i32) -> bool {
+        match self.compare {
+            Compare::Eq => value == self.value,
+            Compare::Ne => value != self.value,
+            Compare::Lt => value < self.value,
+            Compare::Le => value <= self.value,
+            Compare::Gt => value > self.value,
+            Compare::Ge => value >= self.value,
+        }
+    }
+
+    // "<0", "== 5" and so on
+    pub fn parse(condition: &str) -> Option<Condition> {
+        let condition = condition.trim();
+        let &(op, compare) = COMPARE_OPS.iter().find(|&&(op, _)| condition.starts_with(op))?;
+        let value = condition[op.len()..].trim().parse().ok()?;
+        Some(Condition { compare, value })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let &(op, _) = COMPARE_OPS.iter().find(|&&(_, c)| c == self.compare).unwrap();
+        write!(f, "{} {}", op, self.value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Pause,
+    Log,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub target: Target,
+    // Any write if None
+    pub condition: Option<Condition>,
+    pub action: Action,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, target: Target, value: i32) -> bool {
+        self.target == target && self.condition.map_or(true, |c| c.holds(value))
+    }
+}
+
+// A write a watchpoint matched
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchHit {
+    // Index of the watchpoint
+    pub index: usize,
+    pub target: Target,
+    // The writing instruction's step, counting it
+    pub step: u64,
+    pub old: i32,
+    pub new: i32,
+}
+
+
+#[cfg(test)]
+mod watch_tests {
+    use super::*;
+    use Options;
+    use assembler::{Assembler, ModuleBuilder};
+    use instructions::Instruction as Instr;
+    use runtime::{Runtime, Status};
+
+    #[test]
+    fn conditions() {
+        let negative = Condition::parse("<0").unwrap();
+        assert!(negative.holds(-1) && !negative.holds(0));
+        assert_eq!(Condition::parse(" >= 10 ").unwrap().to_string(), ">= 10");
+        assert_eq!(Condition::parse("!=-3"), Some(Condition { compare: Compare::Ne, value: -3 }));
+        assert_eq!(Condition::parse("= 3"), None);
+        assert_eq!(Condition::parse("<"), None);
+    }
+
+    // Counts local 0 of main's callee down from 3
+    fn runtime() -> Runtime {
+        let f = Assembler::new()
+            .label("loop")
+            .op_with(Instr::LOAD, 0).jump(Instr::RELJUMP_EQ, "end")
+            .iconst(1).op_with(Instr::LOAD, 0).op(Instr::SUB).op_with(Instr::STORE, 0)
+            .jump(Instr::RELJUMP, "loop")
+            .label("end")
+            .finish();
+        let main = Assembler::new().iconst(3).op_with(Instr::CALL, 0).finish();
+        let data = ModuleBuilder::new().func(0, "f", 1, 0, &f).main(&main).build();
+        Runtime::new(data, Options::default()).unwrap()
+    }
+
+    #[test]
+    fn pause() {
+        let mut runtime = runtime();
+        runtime.add_watchpoint(Watchpoint {
+            target: Target::Local(0, 0),
+            condition: Condition::parse("<2"),
+            action: Action::Pause,
+        });
+        let mut hits = Vec::new();
+        while runtime.run() == Ok(Status::Paused) {
+            let hit = runtime.watch_hit().unwrap();
+            assert_eq!(runtime.call_stack().last().unwrap().locals[0], hit.new);
+            hits.push((hit.step, hit.old, hit.new));
+        }
+        assert_eq!(hits, vec![(15, 2, 1), (22, 1, 0)]);
+        assert_eq!(runtime.watch_hit(), None);
+        assert_eq!(Target::Local(0, 0).describe(runtime.interpreter()), "f.local 0");
+
+        // Other functions' locals are other targets
+        let mut runtime = self::runtime();
+        runtime.add_watchpoint(Watchpoint {
+            target: Target::Local(1, 0),
+            condition: None,
+            action: Action::Pause,
+        });
+        assert_eq!(runtime.run(), Ok(Status::Finished));
+    }
+
+    #[cfg(feature = "observers")]
+    #[test]
+    fn log() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        use observer::Observer;
+
+        struct Hits(Vec<WatchHit>);
+        impl Observer for Hits {
+            fn on_watch(&mut self, _inpr: &Interpreter, hit: &WatchHit) {
+                self.0.push(*hit);
+            }
+        }
+
+        let mut runtime = runtime();
+        let hits = Rc::new(RefCell::new(Hits(Vec::new())));
+        runtime.add_observer(Box::new(Rc::clone(&hits)));
+        runtime.add_watchpoint(Watchpoint {
+            target: Target::Local(0, 0),
+            condition: None,
+            action: Action::Log,
+        });
+        assert_eq!(runtime.run(), Ok(Status::Finished));
+        let news: Vec<i32> = hits.borrow().0.iter().map(|hit| hit.new).collect();
+        assert_eq!(news, vec![2, 1, 0]);
+        assert_eq!(hits.borrow().0[0].target, Target::Local(0, 0));
+    }
+}
@@ -0,0 +1,41 @@
+// Program arguments, read with ARGC and ARG_INT
+extern crate raptortime;
+
+mod common;
+
+use raptortime::Options;
+use raptortime::runtime::{Runtime, Status};
+use raptortime::assembler::{Assembler, ModuleBuilder};
+use raptortime::instructions::Instruction as Instr;
+
+use common::run_binary;
+
+#[test]
+fn args() {
+    let main = Assembler::new()
+        .op(Instr::ARGC)
+        .iconst(0).op(Instr::ARG_INT)
+        .iconst(1).op(Instr::ARG_INT)
+        .iconst(2).op(Instr::ARG_INT)
+        .iconst(-1).op(Instr::ARG_INT)
+        .finish();
+    let module = ModuleBuilder::new().main(&main).build();
+    let options = Options {
+        args: vec!["-12".to_string(), "x".to_string()],
+        ..Default::default()
+    };
+    let mut runtime = Runtime::new(module, options).unwrap();
+    assert_eq!(runtime.run(), Ok(Status::Finished));
+    // the count, then value and status: parsed, not a number, missing, missing
+    assert_eq!(runtime.interpreter().op_stack, vec![2, -12, 1, 0, -1, 0, 0, 0, 0]);
+}
+
+#[test]
+fn cli_args() {
+    let main = Assembler::new()
+        .op(Instr::ARGC).op(Instr::PRINT)
+        .iconst(1).op(Instr::ARG_INT).op(Instr::POP).op(Instr::PRINT)
+        .finish();
+    let module = ModuleBuilder::new().main(&main).build();
+    assert_eq!(run_binary("args", &module, &["--", "4", "-5"]), "PRINT: 2\nPRINT: -5\n");
+}
@@ -0,0 +1,27 @@
+// Helpers shared by the integration tests, each of which uses some of them
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::process::{self, Command, Output};
+
+// Runs a module through the CLI with `args`. `name` keeps the module files of
+// tests running at the same time apart.
+pub fn run_cli(name: &str, module: &[u8], args: &[&str]) -> Output {
+    let path = env::temp_dir().join(format!("raptortime-{}-{}.crapt", name, process::id()));
+    fs::write(&path, module).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_raptortime"))
+        .arg("-i").arg(&path)
+        .args(args)
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+    output
+}
+
+// Same, for a run that must succeed, and returns what it printed
+pub fn run_binary(name: &str, module: &[u8], args: &[&str]) -> String {
+    let output = run_cli(name, module, args);
+    assert!(output.status.success(), "{} exited with {}", name, output.status);
+    String::from_utf8(output.stdout).unwrap()
+}
@@ -0,0 +1,348 @@
+// Conformance tests: what every instruction does to the op stack, locals,
+// globals and the program's output. See instructions.rs for the conventions.
+extern crate raptortime;
+
+use raptortime::Options;
+use raptortime::runtime::{Runtime, Status};
+use raptortime::error::RuntimeError;
+use raptortime::interpreter::MAIN_ID;
+use raptortime::assembler::{Assembler, ModuleBuilder};
+use raptortime::instructions::Instruction as Instr;
+
+const VAR_COUNT: u32 = 2;
+const LOCAL_COUNT: u32 = 4;
+
+// Emits a test case's code
+type Emit = fn(&mut Assembler);
+
+struct Case {
+    name: &'static str,
+    code: Emit,
+    stack: &'static [i32],
+    locals: &'static [i32],
+}
+
+fn function_module(code: &[u8], callee: &[u8]) -> Vec<u8> {
+    let mut body = Assembler::new();
+    body.bytes(code).op(Instr::HALT);
+    let main = Assembler::new().op_with(Instr::CALL, 0).finish();
+    ModuleBuilder::new()
+        .var_count(VAR_COUNT)
+        .func(0, "case", 0, LOCAL_COUNT, &body.finish())
+        .func(1, "callee", 2, 1, callee)
+        .main(&main)
+        .build()
+}
+
+// Runs `code` followed by a HALT inside function 0, called from main,
+// and returns the runtime so its state can be inspected
+fn run_in_function(code: &[u8], callee: &[u8]) -> Runtime {
+    let mut runtime = Runtime::new(function_module(code, callee), Options::default()).unwrap();
+    assert_eq!(runtime.run(), Ok(Status::Halted));
+    runtime
+}
+
+// Same, for code that's supposed to fail
+fn run_error(code: &[u8]) -> RuntimeError {
+    let mut runtime = Runtime::new(function_module(code, &[]), Options::default()).unwrap();
+    runtime.run().unwrap_err()
+}
+
+// Runs a module and returns its output
+fn run_output(module: Vec<u8>) -> String {
+    let mut runtime = Runtime::new(module, Options::default()).unwrap();
+    let output = runtime.capture_output();
+    runtime.run().unwrap();
+    output.contents()
+}
+
+#[test]
+fn instructions() {
+    let cases = [
+        Case { name: "NOP", code: |a| { a.op(Instr::NOP); },
+               stack: &[], locals: &[0, 0, 0, 0] },
+        Case { name: "ICONST", code: |a| { a.iconst(-5); },
+               stack: &[-5], locals: &[0, 0, 0, 0] },
+        Case { name: "POP", code: |a| { a.iconst(1).iconst(2).op(Instr::POP); },
+               stack: &[1], locals: &[0, 0, 0, 0] },
+        Case { name: "ADD", code: |a| { a.iconst(2).iconst(3).op(Instr::ADD); },
+               stack: &[5], locals: &[0, 0, 0, 0] },
+        Case { name: "ADD wraps around",
+               code: |a| { a.iconst(1).iconst(i32::MAX).op(Instr::ADD); },
+               stack: &[i32::MIN], locals: &[0, 0, 0, 0] },
+        // Top of the stack is the left operand
+        Case { name: "SUB", code: |a| { a.iconst(2).iconst(7).op(Instr::SUB); },
+               stack: &[5], locals: &[0, 0, 0, 0] },
+        Case { name: "MULTIPLY", code: |a| { a.iconst(3).iconst(-4).op(Instr::MULTIPLY); },
+               stack: &[-12], locals: &[0, 0, 0, 0] },
+        Case { name: "DIVIDE", code: |a| { a.iconst(2).iconst(7).op(Instr::DIVIDE); },
+               stack: &[3], locals: &[0, 0, 0, 0] },
+        Case { name: "DIVIDE rounds towards zero",
+               code: |a| { a.iconst(2).iconst(-7).op(Instr::DIVIDE); },
+               stack: &[-3], locals: &[0, 0, 0, 0] },
+        Case { name: "DIVIDE wraps around",
+               code: |a| { a.iconst(-1).iconst(i32::MIN).op(Instr::DIVIDE); },
+               stack: &[i32::MIN], locals: &[0, 0, 0, 0] },
+        Case { name: "MODULUS", code: |a| { a.iconst(3).iconst(7).op(Instr::MODULUS); },
+               stack: &[1], locals: &[0, 0, 0, 0] },
+        Case { name: "MODULUS takes the sign of the dividend",
+               code: |a| { a.iconst(3).iconst(-7).op(Instr::MODULUS); },
+               stack: &[-1], locals: &[0, 0, 0, 0] },
+        Case { name: "AND", code: |a| { a.iconst(6).iconst(3).op(Instr::AND); },
+               stack: &[2], locals: &[0, 0, 0, 0] },
+        Case { name: "OR", code: |a| { a.iconst(6).iconst(3).op(Instr::OR); },
+               stack: &[7], locals: &[0, 0, 0, 0] },
+        Case { name: "NOT is bitwise", code: |a| { a.iconst(0).op(Instr::NOT); },
+               stack: &[-1], locals: &[0, 0, 0, 0] },
+        Case { name: "RSHIFT", code: |a| { a.iconst(2).iconst(16).op(Instr::RSHIFT); },
+               stack: &[4], locals: &[0, 0, 0, 0] },
+        Case { name: "RSHIFT is arithmetic",
+               code: |a| { a.iconst(2).iconst(-16).op(Instr::RSHIFT); },
+               stack: &[-4], locals: &[0, 0, 0, 0] },
+        Case { name: "RSHIFT amount is taken mod 32",
+               code: |a| { a.iconst(33).iconst(16).op(Instr::RSHIFT); },
+               stack: &[8], locals: &[0, 0, 0, 0] },
+        Case { name: "LSHIFT", code: |a| { a.iconst(2).iconst(3).op(Instr::LSHIFT); },
+               stack: &[12], locals: &[0, 0, 0, 0] },
+        Case { name: "COMP greater", code: |a| { a.iconst(1).iconst(2).op(Instr::COMP); },
+               stack: &[1], locals: &[0, 0, 0, 0] },
+        Case { name: "COMP less", code: |a| { a.iconst(2).iconst(1).op(Instr::COMP); },
+               stack: &[-1], locals: &[0, 0, 0, 0] },
+        Case { name: "COMP equal", code: |a| { a.iconst(2).iconst(2).op(Instr::COMP); },
+               stack: &[0], locals: &[0, 0, 0, 0] },
+        Case { name: "COMP_LT true", code: |a| { a.iconst(2).iconst(1).op(Instr::COMP_LT); },
+               stack: &[1], locals: &[0, 0, 0, 0] },
+        Case { name: "COMP_LT false", code: |a| { a.iconst(1).iconst(2).op(Instr::COMP_LT); },
+               stack: &[0], locals: &[0, 0, 0, 0] },
+        Case { name: "COMP_EQ true", code: |a| { a.iconst(2).iconst(2).op(Instr::COMP_EQ); },
+               stack: &[1], locals: &[0, 0, 0, 0] },
+        Case { name: "COMP_EQ false", code: |a| { a.iconst(1).iconst(2).op(Instr::COMP_EQ); },
+               stack: &[0], locals: &[0, 0, 0, 0] },
+        Case { name: "COMP_GT true", code: |a| { a.iconst(1).iconst(2).op(Instr::COMP_GT); },
+               stack: &[1], locals: &[0, 0, 0, 0] },
+        Case { name: "COMP_GT false", code: |a| { a.iconst(2).iconst(1).op(Instr::COMP_GT); },
+               stack: &[0], locals: &[0, 0, 0, 0] },
+        // Offset 6 from the last byte of the jump (offset 4) skips the first ICONST
+        Case { name: "RELJUMP forward",
+               code: |a| { a.bytes(&[0x2A, 0, 0, 0, 6]).iconst(1).iconst(2); },
+               stack: &[2], locals: &[0, 0, 0, 0] },
+        // Counts local 0 down from 2, jumping back to the POP once
+        Case { name: "RELJUMP backward",
+               code: |a| {
+                   a.iconst(7).iconst(8).iconst(2).op_with(Instr::STORE, 0)
+                       .jump(Instr::RELJUMP, "loop")
+                       .label("top").op(Instr::POP)
+                       .label("loop")
+                       .iconst(1).op_with(Instr::LOAD, 0).op(Instr::SUB)
+                       .op_with(Instr::STORE, 0).op_with(Instr::LOAD, 0)
+                       .jump(Instr::RELJUMP_GT, "top");
+               },
+               stack: &[7], locals: &[0, 0, 0, 0] },
+        Case { name: "RELJUMP_GT taken",
+               code: |a| { a.iconst(1).jump(Instr::RELJUMP_GT, "end").iconst(9).label("end"); },
+               stack: &[], locals: &[0, 0, 0, 0] },
+        Case { name: "RELJUMP_GT not taken",
+               code: |a| { a.iconst(0).jump(Instr::RELJUMP_GT, "end").iconst(9).label("end"); },
+               stack: &[9], locals: &[0, 0, 0, 0] },
+        Case { name: "RELJUMP_LT taken",
+               code: |a| { a.iconst(-1).jump(Instr::RELJUMP_LT, "end").iconst(9).label("end"); },
+               stack: &[], locals: &[0, 0, 0, 0] },
+        Case { name: "RELJUMP_LT not taken",
+               code: |a| { a.iconst(0).jump(Instr::RELJUMP_LT, "end").iconst(9).label("end"); },
+               stack: &[9], locals: &[0, 0, 0, 0] },
+        Case { name: "RELJUMP_EQ taken",
+               code: |a| { a.iconst(0).jump(Instr::RELJUMP_EQ, "end").iconst(9).label("end"); },
+               stack: &[], locals: &[0, 0, 0, 0] },
+        Case { name: "RELJUMP_EQ not taken",
+               code: |a| { a.iconst(1).jump(Instr::RELJUMP_EQ, "end").iconst(9).label("end"); },
+               stack: &[9], locals: &[0, 0, 0, 0] },
+        Case { name: "STORE", code: |a| { a.iconst(1).iconst(9).op_with(Instr::STORE, 2); },
+               stack: &[1], locals: &[0, 0, 9, 0] },
+        Case { name: "LOAD",
+               code: |a| { a.iconst(9).op_with(Instr::STORE, 3)
+                           .op_with(Instr::LOAD, 3).op_with(Instr::LOAD, 0); },
+               stack: &[9, 0], locals: &[0, 0, 0, 9] },
+        Case { name: "HALT stops right away",
+               code: |a| { a.iconst(1).op(Instr::HALT).iconst(2); },
+               stack: &[1], locals: &[0, 0, 0, 0] },
+    ];
+
+    for case in &cases {
+        let mut code = Assembler::new();
+        (case.code)(&mut code);
+        let runtime = run_in_function(&code.finish(), &[]);
+
+        let frame = runtime.call_stack().last().unwrap();
+        assert_eq!(runtime.interpreter().op_stack, case.stack, "{}: op stack", case.name);
+        assert_eq!(frame.locals, case.locals, "{}: locals", case.name);
+        assert_eq!(runtime.interpreter().globals(), &[0, 0], "{}: globals", case.name);
+    }
+}
+
+#[test]
+fn call() {
+    // The callee halts right away so its frame can be looked at
+    let code = Assembler::new().iconst(1).iconst(10).iconst(3).op_with(Instr::CALL, 1).finish();
+    let callee = Assembler::new().op(Instr::HALT).finish();
+    let runtime = run_in_function(&code, &callee);
+
+    assert_eq!(runtime.call_stack().len(), 3);
+    let frame = runtime.call_stack().last().unwrap();
+    assert_eq!(frame.id, 1);
+    // Arguments are popped into locals top first, followed by the callee's own locals
+    assert_eq!(frame.locals, vec![3, 10, 0]);
+    assert_eq!(frame.return_addr, 1);
+    assert_eq!(runtime.interpreter().op_stack, vec![1]);
+}
+
+#[test]
+fn return_() {
+    let code = Assembler::new().iconst(1).iconst(10).iconst(3).op_with(Instr::CALL, 1).finish();
+    let callee = Assembler::new()
+        .iconst(5).op_with(Instr::LOAD, 1).op(Instr::RETURN)
+        .iconst(6)
+        .finish();
+    let runtime = run_in_function(&code, &callee);
+
+    // Back in the caller, with whatever the callee left on the stack dropped
+    assert_eq!(runtime.call_stack().len(), 2);
+    assert_eq!(runtime.interpreter().op_stack, vec![1, 10]);
+}
+
+#[test]
+fn return_below_frame() {
+    // The callee pops the 1 its caller left under the arguments
+    let code = Assembler::new().iconst(1).iconst(10).iconst(3).op_with(Instr::CALL, 1).finish();
+    let callee = Assembler::new().op(Instr::POP).iconst(5).op(Instr::RETURN).finish();
+    let mut runtime = Runtime::new(function_module(&code, &callee), Options::default()).unwrap();
+    assert_eq!(runtime.run(), Err(RuntimeError::StackUnderflow));
+}
+
+#[test]
+fn return_at_end_of_body() {
+    let code = Assembler::new().iconst(1).iconst(10).iconst(3).op_with(Instr::CALL, 1).finish();
+    let callee = Assembler::new().iconst(5).finish();
+    let runtime = run_in_function(&code, &callee);
+
+    // Running off the end pops the frame but leaves the stack alone
+    assert_eq!(runtime.call_stack().len(), 2);
+    assert_eq!(runtime.interpreter().op_stack, vec![1, 5]);
+}
+
+#[test]
+fn program_end() {
+    let main = Assembler::new().iconst(1).finish();
+    let mut runtime = Runtime::new(ModuleBuilder::new().main(&main).build(),
+                                   Options::default()).unwrap();
+    assert_eq!(runtime.run(), Ok(Status::Finished));
+
+    assert!(runtime.call_stack().is_empty());
+    assert_eq!(runtime.interpreter().op_stack, vec![1]);
+}
+
+#[test]
+fn errors() {
+    let cases: [(Emit, RuntimeError); 10] = [
+        (|a| { a.op(Instr::STOREFIELD); }, RuntimeError::Unimplemented("STOREFIELD")),
+        (|a| { a.op(Instr::LOADFIELD); }, RuntimeError::Unimplemented("LOADFIELD")),
+        (|a| { a.op(Instr::VECTORSTORE); }, RuntimeError::Unimplemented("VECTORSTORE")),
+        (|a| { a.op(Instr::VECTORLOAD); }, RuntimeError::Unimplemented("VECTORLOAD")),
+        (|a| { a.iconst(1).op(Instr::ADD); }, RuntimeError::StackUnderflow),
+        (|a| { a.op(Instr::RETURN); }, RuntimeError::StackUnderflow),
+        (|a| { a.iconst(0).iconst(1).op(Instr::DIVIDE); }, RuntimeError::DivisionByZero),
+        (|a| { a.iconst(0).iconst(1).op(Instr::MODULUS); }, RuntimeError::DivisionByZero),
+        (|a| { a.op_with(Instr::LOAD, LOCAL_COUNT); }, RuntimeError::InvalidLocal(LOCAL_COUNT)),
+        (|a| { a.op_with(Instr::CALL, 7); }, RuntimeError::UnknownFunction(7)),
+    ];
+
+    for &(code, ref error) in &cases {
+        let mut a = Assembler::new();
+        code(&mut a);
+        assert_eq!(&run_error(&a.finish()), error);
+    }
+}
+
+#[test]
+fn infinite_recursion() {
+    let code = Assembler::new().op_with(Instr::CALL, 0).finish();
+    assert_eq!(run_error(&code), RuntimeError::CallStackOverflow);
+}
+
+#[test]
+fn recursion_with_many_locals() {
+    // Fails after 16 frames, well before MAX_CALL_DEPTH
+    let body = Assembler::new().op_with(Instr::CALL, 0).finish();
+    let main = Assembler::new().op_with(Instr::CALL, 0).finish();
+    let module = ModuleBuilder::new().func(0, "deep", 0, 0xFFFF, &body).main(&main).build();
+    let mut runtime = Runtime::new(module, Options::default()).unwrap();
+    assert_eq!(runtime.run(), Err(RuntimeError::CallStackOverflow));
+    assert_eq!(runtime.call_stack().len(), 17);
+}
+
+#[test]
+fn step_limit() {
+    let main = Assembler::new().iconst(1).iconst(2).iconst(3).finish();
+    let mut runtime = Runtime::new(ModuleBuilder::new().main(&main).build(),
+                                   Options::default()).unwrap();
+    runtime.set_step_limit(Some(2));
+    assert_eq!(runtime.run(), Ok(Status::Paused));
+    assert_eq!(runtime.interpreter().op_stack, vec![1, 2]);
+
+    // Lowering the limit below the steps already taken pauses right away
+    runtime.set_step_limit(Some(1));
+    assert_eq!(runtime.run(), Ok(Status::Paused));
+    assert_eq!(runtime.steps(), 2);
+
+    runtime.set_step_limit(None);
+    assert_eq!(runtime.run(), Ok(Status::Finished));
+    assert_eq!(runtime.interpreter().op_stack, vec![1, 2, 3]);
+    assert_eq!(runtime.steps(), 3);
+}
+
+#[test]
+fn output() {
+    struct OutputCase {
+        name: &'static str,
+        code: Emit,
+        output: &'static str,
+    }
+    let cases = [
+        OutputCase { name: "PRINT", code: |a| { a.iconst(42).op(Instr::PRINT); },
+                     output: "PRINT: 42\n" },
+        OutputCase { name: "PRINT pops",
+                     code: |a| { a.iconst(1).iconst(2).op(Instr::PRINT).op(Instr::PRINT); },
+                     output: "PRINT: 2\nPRINT: 1\n" },
+        OutputCase { name: "DUMP_STACK",
+                     code: |a| { a.iconst(1).iconst(2).op(Instr::DUMP_STACK); },
+                     output: "[1, 2]\n" },
+        OutputCase { name: "DUMP_GLOBALS", code: |a| { a.op(Instr::DUMP_GLOBALS); },
+                     output: "[0, 0]\n" },
+        OutputCase { name: "HALT",
+                     code: |a| { a.op(Instr::HALT).iconst(1).op(Instr::PRINT); },
+                     output: "HALT issued, stopped execution.\n" },
+    ];
+
+    for case in &cases {
+        let mut main = Assembler::new();
+        (case.code)(&mut main);
+        let module = ModuleBuilder::new().var_count(VAR_COUNT).main(&main.finish()).build();
+        assert_eq!(run_output(module), case.output, "{}", case.name);
+    }
+}
+
+#[test]
+fn output_with_debug_info() {
+    let mut main = Assembler::new();
+    main.line(1).iconst(1).iconst(2).op(Instr::DUMP_STACK)
+        .line(2).op(Instr::DUMP_GLOBALS);
+    let module = ModuleBuilder::new()
+        .version(2)
+        .var_count(VAR_COUNT)
+        .main(&main.finish())
+        .debug_file("dump.rapt")
+        .debug_lines(MAIN_ID, main.lines())
+        .debug_globals(&["x"])
+        .build();
+    assert_eq!(run_output(module), "dump.rapt:1: [1, 2]\ndump.rapt:2: [x = 0, 0]\n");
+}
+
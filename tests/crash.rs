@@ -0,0 +1,75 @@
+// A program failing with --crash-dump, and the dump looked at with --inspect
+extern crate raptortime;
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::{self, Command, Stdio};
+
+use raptortime::crash::{module_hash, read_crash_dump};
+use raptortime::assembler::{Assembler, ModuleBuilder};
+use raptortime::instructions::Instruction as Instr;
+
+#[test]
+fn cli_crash_dump() {
+    // f(n) with n = 2 divides by n - 2
+    let f = Assembler::new()
+        .iconst(2).op_with(Instr::LOAD, 0).op(Instr::SUB).op_with(Instr::LOAD, 0).op(Instr::DIVIDE)
+        .finish();
+    let main = Assembler::new().iconst(2).op_with(Instr::CALL, 0).op(Instr::PRINT).finish();
+    let module = ModuleBuilder::new()
+        .version(2)
+        .func(0, "f", 1, 0, &f)
+        .main(&main)
+        .debug_locals(0, &["n"])
+        .build();
+    let dir = env::temp_dir();
+    let input = dir.join(format!("raptortime-crash-{}.crapt", process::id()));
+    let dump = dir.join(format!("raptortime-crash-{}.dump", process::id()));
+    fs::write(&input, &module).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_raptortime"))
+        .arg("-i").arg(&input).arg("--crash-dump").arg(&dump)
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+    let read = read_crash_dump(&fs::read(&dump).unwrap()).unwrap();
+    assert_eq!(read.module_hash, module_hash(&module));
+    assert_eq!(read.error, "Runtime error: Division by zero");
+    assert_eq!(read.snapshot.call_stack.len(), 2);
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_raptortime"))
+        .arg("--inspect").arg(&dump)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child.stdin.as_mut().unwrap().write_all(b"stack\np n - 2\nq\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), format!("\
+f+16: Runtime error: Division by zero
+after 7 steps, module {:016x}
+#0 f (id 0), offset 16, args [n = 2], locals []
+#1 main, offset 5
+(dump) frame [0, 2]
+whole [0, 2]
+(dump) 0
+(dump) ", module_hash(&module)));
+
+    // Programs that don't fail don't leave one
+    fs::remove_file(&dump).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_raptortime"))
+        .arg("-i").arg(&input).arg("--crash-dump").arg(&dump).arg("--entry").arg("f")
+        .arg("--args").arg("3")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(!dump.exists());
+
+    fs::remove_file(&input).unwrap();
+}
@@ -0,0 +1,356 @@
+// A debug session as an editor would drive it
+#[macro_use]
+extern crate json;
+extern crate raptortime;
+
+use std::env;
+use std::fs;
+use std::io::{BufReader, Cursor, Write};
+use std::path::PathBuf;
+use std::process::{self, Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use json::JsonValue;
+
+use raptortime::dap::{read_message, write_message, Server};
+use raptortime::output::Capture;
+use raptortime::assembler::{Assembler, ModuleBuilder};
+use raptortime::instructions::Instruction as Instr;
+
+// f(n) sums n..1 into acc by looping, main prints f(its first argument)
+fn module() -> Vec<u8> {
+    let mut f = Assembler::new();
+    f.line(2).iconst(0).op_with(Instr::STORE, 1)
+        .label("loop")
+        .line(3).op_with(Instr::LOAD, 0).jump(Instr::RELJUMP_EQ, "end")
+        .line(4).op_with(Instr::LOAD, 0).op_with(Instr::LOAD, 1).op(Instr::ADD)
+        .op_with(Instr::STORE, 1)
+        .line(5).iconst(1).op_with(Instr::LOAD, 0).op(Instr::SUB).op_with(Instr::STORE, 0)
+        .jump(Instr::RELJUMP, "loop")
+        .label("end")
+        .line(6).op_with(Instr::LOAD, 1).op(Instr::RETURN);
+    let main = Assembler::new()
+        .iconst(0).op(Instr::ARG_INT).op(Instr::POP).op_with(Instr::CALL, 0).op(Instr::PRINT)
+        .finish();
+    ModuleBuilder::new()
+        .version(2)
+        .func(0, "f", 1, 1, &f.finish())
+        .main(&main)
+        .debug_file("sum.rapt")
+        .debug_lines(0, f.lines())
+        .debug_locals(0, &["n", "acc"])
+        .build()
+}
+
+// Runs the requests, returns the response to each and the events after it.
+// They're all sent up front, so a pause or disconnect would interrupt
+// whatever runs before it; the end of the input ends the session instead.
+fn session(requests: Vec<(&str, JsonValue)>) -> Vec<(JsonValue, Vec<JsonValue>)> {
+    let mut input = Vec::new();
+    for (seq, (command, arguments)) in requests.into_iter().enumerate() {
+        let request = object! {
+            seq: seq + 1, type: "request", command: command, arguments: arguments,
+        };
+        write_message(&mut input, &request).unwrap();
+    }
+    let output = Capture::new();
+    Server::new(Box::new(output.clone()), false).run(Cursor::new(input)).unwrap();
+
+    let mut replies: Vec<(JsonValue, Vec<JsonValue>)> = Vec::new();
+    let mut output = Cursor::new(output.bytes());
+    while let Some(message) = read_message(&mut output).unwrap() {
+        if message["type"] == "response" {
+            assert_eq!(message["request_seq"], replies.len() + 1);
+            replies.push((message, Vec::new()));
+        } else {
+            replies.last_mut().unwrap().1.push(message);
+        }
+    }
+    replies
+}
+
+fn variables(reply: &(JsonValue, Vec<JsonValue>)) -> Vec<String> {
+    reply.0["body"]["variables"].members()
+        .map(|v| format!("{} = {}", v["name"], v["value"]))
+        .collect()
+}
+
+fn lines(reply: &(JsonValue, Vec<JsonValue>)) -> Vec<String> {
+    reply.0["body"]["stackFrames"].members()
+        .map(|frame| format!("{} {}", frame["name"], frame["line"]))
+        .collect()
+}
+
+fn stopped(reply: &(JsonValue, Vec<JsonValue>)) -> String {
+    assert!(reply.0["success"].as_bool().unwrap(), "{}", reply.0);
+    let event = reply.1.iter().find(|e| e["event"] == "stopped").expect("stopped event");
+    event["body"]["reason"].to_string()
+}
+
+#[test]
+fn debug_session() {
+    let dir = env::temp_dir();
+    let program = dir.join(format!("raptortime-dap-{}.crapt", process::id()));
+    fs::write(&program, module()).unwrap();
+    let source = dir.join("sum.rapt");
+
+    let locals = object! { variablesReference: 4 };
+    let replies = session(vec![
+        ("initialize", object! { adapterID: "raptortime" }),
+        ("launch", object! { program: program.to_str(), args: ["3"] }),
+        ("setBreakpoints", object! {
+            source: object! { path: source.to_str() },
+            breakpoints: [object! { line: 5 }, object! { line: 9 }],
+        }),
+        ("configurationDone", JsonValue::new_object()),
+        ("stackTrace", object! { threadId: 1 }),
+        ("scopes", object! { frameId: 2 }),
+        ("variables", locals.clone()),
+        ("next", object! { threadId: 1 }),
+        ("stackTrace", object! { threadId: 1 }),
+        ("stepBack", object! { threadId: 1 }),
+        ("stackTrace", object! { threadId: 1 }),
+        ("variables", locals.clone()),
+        ("continue", object! { threadId: 1 }),
+        ("variables", locals.clone()),
+        ("reverseContinue", object! { threadId: 1 }),
+        ("variables", locals.clone()),
+        ("setBreakpoints", object! { source: object! { path: source.to_str() }, breakpoints: [] }),
+        ("stepOut", object! { threadId: 1 }),
+        ("stackTrace", object! { threadId: 1 }),
+        ("variables", object! { variablesReference: 2 }),
+        ("setInstructionBreakpoints", object! {
+            breakpoints: [object! { instructionReference: "main+12" },
+                          object! { instructionReference: "main+10", offset: 2 },
+                          object! { instructionReference: "main+11" }],
+        }),
+        ("continue", object! { threadId: 1 }),
+    ]);
+    assert_eq!(replies.len(), 22);
+
+    assert_eq!(replies[0].0["body"]["supportsStepBack"], true);
+    assert_eq!(replies[1].1[0]["event"], "initialized");
+    let breakpoints = &replies[2].0["body"]["breakpoints"];
+    assert_eq!(breakpoints[0]["verified"], true);
+    assert_eq!(breakpoints[1]["verified"], false);
+
+    assert_eq!(stopped(&replies[3]), "breakpoint");
+    assert_eq!(lines(&replies[4]), vec!["f 5", "main 0"]);
+    assert_eq!(replies[4].0["body"]["stackFrames"][0]["source"]["path"], source.to_str().unwrap());
+    assert_eq!(replies[4].0["body"]["stackFrames"][1]["instructionPointerReference"], "main+7");
+    assert_eq!(replies[5].0["body"]["scopes"][0]["variablesReference"], 4);
+    assert_eq!(variables(&replies[6]), vec!["n = 3", "acc = 3"]);
+
+    // Over the rest of line 5, and back to its start
+    assert_eq!(stopped(&replies[7]), "step");
+    assert_eq!(lines(&replies[8]), vec!["f 3", "main 0"]);
+    assert_eq!(stopped(&replies[9]), "step");
+    assert_eq!(lines(&replies[10]), vec!["f 5", "main 0"]);
+    assert_eq!(variables(&replies[11]), vec!["n = 3", "acc = 3"]);
+
+    assert_eq!(stopped(&replies[12]), "breakpoint");
+    assert_eq!(variables(&replies[13]), vec!["n = 2", "acc = 5"]);
+    assert_eq!(stopped(&replies[14]), "breakpoint");
+    assert_eq!(variables(&replies[15]), vec!["n = 3", "acc = 3"]);
+
+    assert_eq!(stopped(&replies[17]), "step");
+    assert_eq!(lines(&replies[18]), vec!["main 0"]);
+    assert_eq!(variables(&replies[19]), vec!["[0] = 6"]);
+
+    // Both at the PRINT, which the program is already about to execute
+    let breakpoints = &replies[20].0["body"]["breakpoints"];
+    assert_eq!(breakpoints[0]["verified"], true);
+    assert_eq!(breakpoints[1]["instructionReference"], "main+12");
+    assert_eq!(breakpoints[1]["verified"], true);
+    // In the middle of the CALL
+    assert_eq!(breakpoints[2]["verified"], false);
+
+    let events: Vec<String> = replies[21].1.iter().map(|e| e["event"].to_string()).collect();
+    assert_eq!(events, vec!["output", "exited", "terminated"]);
+    assert_eq!(replies[21].1[0]["body"]["output"], "PRINT: 6\n");
+    assert_eq!(replies[21].1[1]["body"]["exitCode"], 0);
+
+    fs::remove_file(&program).unwrap();
+}
+
+#[test]
+fn conditional_breakpoints() {
+    let dir = env::temp_dir();
+    let program = dir.join(format!("raptortime-dap-conditions-{}.crapt", process::id()));
+    fs::write(&program, module()).unwrap();
+    let source = dir.join("sum.rapt");
+
+    let replies = session(vec![
+        ("launch", object! { program: program.to_str(), args: ["5"] }),
+        ("setBreakpoints", object! {
+            source: object! { path: source.to_str() },
+            breakpoints: [object! { line: 4, condition: "n == 2 || hits == 4" },
+                          object! { line: 5, condition: "acc >" }],
+        }),
+        ("configurationDone", JsonValue::new_object()),
+        ("variables", object! { variablesReference: 4 }),
+        ("continue", object! { threadId: 1 }),
+    ]);
+    let breakpoints = &replies[1].0["body"]["breakpoints"];
+    assert_eq!(breakpoints[0]["verified"], true);
+    assert_eq!(breakpoints[1]["verified"], false);
+    assert_eq!(breakpoints[1]["message"], "Unexpected end of condition");
+
+    assert_eq!(stopped(&replies[2]), "breakpoint");
+    assert_eq!(variables(&replies[3]), vec!["n = 2", "acc = 12"]);
+    let events: Vec<String> = replies[4].1.iter().map(|e| e["event"].to_string()).collect();
+    assert_eq!(events, vec!["output", "exited", "terminated"]);
+
+    fs::remove_file(&program).unwrap();
+}
+
+#[test]
+fn errors() {
+    let replies = session(vec![
+        ("stackTrace", object! { threadId: 1 }),
+        ("launch", object! { program: "/nonexistent.crapt" }),
+        ("evaluate", object! { expression: "1" }),
+    ]);
+    assert_eq!(replies[0].0["success"], false);
+    assert_eq!(replies[0].0["message"], "No program launched");
+    assert_eq!(replies[1].0["success"], false);
+    assert!(replies[1].1.is_empty());
+    assert_eq!(replies[2].0["message"], "Unsupported request evaluate");
+}
+
+#[test]
+fn cli_dap() {
+    let mut input = Vec::new();
+    write_message(&mut input, &object! { seq: 1, type: "request", command: "initialize" }).unwrap();
+    write_message(&mut input, &object! { seq: 2, type: "request", command: "disconnect" }).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_raptortime"))
+        .arg("--dap")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child.stdin.as_mut().unwrap().write_all(&input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let mut stdout = Cursor::new(output.stdout);
+    let initialized = read_message(&mut stdout).unwrap().unwrap();
+    assert_eq!(initialized["command"], "initialize");
+    assert_eq!(initialized["body"]["supportsInstructionBreakpoints"], true);
+    let disconnected = read_message(&mut stdout).unwrap().unwrap();
+    assert_eq!(disconnected["request_seq"], 2);
+    assert!(read_message(&mut stdout).unwrap().is_none());
+}
+
+// A --dap process running a program that loops forever, with the client
+// waiting for each answer like an editor would
+struct Client {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    program: PathBuf,
+    seq: usize,
+}
+
+impl Client {
+    // Launches the loop and leaves it running
+    fn looping(name: &str) -> Client {
+        // main calls f, which never returns
+        let f = Assembler::new()
+            .label("loop").iconst(1).op(Instr::POP).jump(Instr::RELJUMP, "loop")
+            .finish();
+        let main = Assembler::new().op_with(Instr::CALL, 0).finish();
+        let module = ModuleBuilder::new().func(0, "f", 0, 0, &f).main(&main).build();
+        let program = env::temp_dir()
+            .join(format!("raptortime-dap-{}-{}.crapt", name, process::id()));
+        fs::write(&program, module).unwrap();
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_raptortime"))
+            .arg("--dap")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut client = Client { child, stdin, stdout, program, seq: 0 };
+        client.send("initialize", JsonValue::new_object());
+        client.send("launch", object! { program: client.program.to_str() });
+        client.send("configurationDone", JsonValue::new_object());
+        thread::sleep(Duration::from_millis(100));
+        client
+    }
+
+    // Returns the request's seq
+    fn send(&mut self, command: &str, arguments: JsonValue) -> usize {
+        self.seq += 1;
+        let request = object! {
+            seq: self.seq, type: "request", command: command, arguments: arguments,
+        };
+        write_message(&mut self.stdin, &request).unwrap();
+        self.stdin.flush().unwrap();
+        self.seq
+    }
+
+    fn recv(&mut self) -> JsonValue {
+        read_message(&mut self.stdout).unwrap().unwrap()
+    }
+
+    // Every message up to the response to `seq`, and that response
+    fn until_response(&mut self, seq: usize) -> (Vec<JsonValue>, JsonValue) {
+        let mut messages = Vec::new();
+        loop {
+            let message = self.recv();
+            if message["type"] == "response" && message["request_seq"] == seq {
+                return (messages, message);
+            }
+            messages.push(message);
+        }
+    }
+
+    fn disconnect(mut self) {
+        let seq = self.send("disconnect", JsonValue::new_object());
+        let (_, response) = self.until_response(seq);
+        assert_eq!(response["success"], true);
+        assert!(self.child.wait().unwrap().success());
+        fs::remove_file(&self.program).unwrap();
+    }
+}
+
+fn events(messages: &[JsonValue]) -> Vec<String> {
+    messages.iter().filter(|m| m["type"] == "event").map(|m| m["event"].to_string()).collect()
+}
+
+#[test]
+fn cli_pause() {
+    let mut client = Client::looping("pause");
+    let seq = client.send("pause", object! { threadId: 1 });
+    // configurationDone only answers once the program stops
+    let (messages, response) = client.until_response(seq);
+    assert_eq!(response["success"], true);
+    assert_eq!(events(&messages), vec!["initialized", "stopped"]);
+    let stopped = messages.iter().find(|m| m["event"] == "stopped").unwrap();
+    assert_eq!(stopped["body"]["reason"], "pause");
+
+    let seq = client.send("stackTrace", object! { threadId: 1 });
+    let (_, trace) = client.until_response(seq);
+    assert_eq!(trace["body"]["stackFrames"][0]["name"], "f");
+    client.disconnect();
+}
+
+#[test]
+fn cli_terminate() {
+    let mut client = Client::looping("terminate");
+    let seq = client.send("terminate", JsonValue::new_object());
+    let (messages, response) = client.until_response(seq);
+    assert_eq!(response["success"], true);
+    // Ended, not paused
+    assert_eq!(events(&messages), vec!["initialized"]);
+    let terminated = client.recv();
+    assert_eq!(terminated["event"], "terminated");
+    client.disconnect();
+}
@@ -0,0 +1,50 @@
+// The debugger on the command line, sharing stdin with the program
+extern crate raptortime;
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::{self, Command, Stdio};
+
+use raptortime::assembler::{Assembler, ModuleBuilder};
+use raptortime::instructions::Instruction as Instr;
+
+#[test]
+fn cli_debugger() {
+    let main = Assembler::new()
+        .op(Instr::READ_INT).op(Instr::POP).iconst(2).op(Instr::MULTIPLY).op(Instr::PRINT)
+        .finish();
+    let input = env::temp_dir().join(format!("raptortime-debugger-{}.crapt", process::id()));
+    fs::write(&input, ModuleBuilder::new().main(&main).build()).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_raptortime"))
+        .arg("-i").arg(&input).arg("--debugger")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // The program reads 21 when READ_INT executes, between the commands, and
+    // leaves the newline after it as an empty command
+    child.stdin.as_mut().unwrap()
+        .write_all(b"break main+7\nc\n21\np\nrs 3\nc\nc\nq\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "\
+step 0: main+0: READ_INT
+(rdb) Breakpoint 0 at main+7
+(rdb) Breakpoint 0
+step 3: main+7: MULTIPLY
+(rdb) (rdb) main, offset 2
+stack [21, 2]
+globals []
+(rdb) step 0: main+0: READ_INT
+(rdb) Breakpoint 0
+step 3: main+7: MULTIPLY
+(rdb) PRINT: 42
+The program finished
+step 5: the program ended
+(rdb) ");
+
+    fs::remove_file(&input).unwrap();
+}
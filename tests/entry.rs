@@ -0,0 +1,72 @@
+// Running a single function with --entry, and Runtime::enter
+extern crate raptortime;
+
+mod common;
+
+use raptortime::Options;
+use raptortime::runtime::{Runtime, Status};
+use raptortime::error::RuntimeError;
+use raptortime::assembler::{Assembler, ModuleBuilder};
+use raptortime::instructions::Instruction as Instr;
+
+use common::{run_binary, run_cli};
+
+// sub(a, b) = a - b, main prints something so we know it didn't run
+fn entry_module() -> Vec<u8> {
+    let sub = Assembler::new()
+        .op_with(Instr::LOAD, 1).op_with(Instr::LOAD, 0).op(Instr::SUB).op(Instr::RETURN)
+        .finish();
+    let main = Assembler::new().iconst(1).op(Instr::PRINT).finish();
+    ModuleBuilder::new().func(0, "sub", 2, 0, &sub).main(&main).build()
+}
+
+#[test]
+fn enter() {
+    let mut runtime = Runtime::new(entry_module(), Options::default()).unwrap();
+    let output = runtime.capture_output();
+    let id = runtime.interpreter().function_id("sub").unwrap();
+    assert_eq!(runtime.enter(id, &[1]), Err(RuntimeError::WrongArgCount(0, 2, 1)));
+    assert_eq!(runtime.enter(1, &[]), Err(RuntimeError::UnknownFunction(1)));
+    runtime.enter(id, &[10, 3]).unwrap();
+    assert_eq!(runtime.run(), Ok(Status::Finished));
+    assert_eq!(runtime.return_value(), Some(7));
+    assert_eq!(output.contents(), "");
+}
+
+#[test]
+fn cli_entry() {
+    let module = entry_module();
+    assert_eq!(run_binary("entry", &module, &["--entry", "sub", "--args", "10,3"]), "7\n");
+    let output = run_cli("exit_code", &module,
+                         &["--entry", "sub", "--exit-code", "--args", "5,2"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(output.stdout, b"");
+    let output = run_cli("no_entry", &module, &["--entry", "add"]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn cli_negative_args() {
+    let module = entry_module();
+    assert_eq!(run_binary("negative", &module, &["--entry", "sub", "--args", "10,-3"]), "13
+");
+    assert_eq!(run_binary("first_negative", &module, &["--entry", "sub", "--args=-3, 4"]), "-7
+");
+    let output = run_cli("bad_args", &module, &["--entry", "sub", "--args", "1,x"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+// Flags that would be ignored are usage errors
+#[test]
+fn cli_conflicts() {
+    let module = entry_module();
+    for (name, args) in [
+        ("exit_code_alone", &["--exit-code"][..]),
+        ("args_alone", &["--args", "1,2"][..]),
+        ("entry_resume", &["--entry", "sub", "--resume", "nonexistent.snapshot"][..]),
+    ] {
+        let output = run_cli(name, &module, args);
+        assert_eq!(output.status.code(), Some(2), "{}", name);
+        assert_eq!(output.stdout, b"", "{}", name);
+    }
+}
@@ -0,0 +1,41 @@
+// READ_INT with the input given by the embedder
+extern crate raptortime;
+
+use std::io;
+
+use raptortime::Options;
+use raptortime::runtime::{Runtime, Status};
+use raptortime::assembler::{Assembler, ModuleBuilder};
+use raptortime::instructions::Instruction as Instr;
+
+#[test]
+fn input() {
+    let main = Assembler::new()
+        .op(Instr::READ_INT).op(Instr::READ_INT).op(Instr::READ_INT).op(Instr::READ_INT)
+        .finish();
+    let module = ModuleBuilder::new().main(&main).build();
+    let mut runtime = Runtime::new(module, Options::default()).unwrap();
+    runtime.set_input(Box::new(io::Cursor::new(" 7\n-2 x")));
+    assert_eq!(runtime.run(), Ok(Status::Finished));
+    // value, then status: read, read, not a number, end of input
+    assert_eq!(runtime.interpreter().op_stack, vec![7, 1, -2, 1, 0, -1, 0, 0]);
+}
+
+#[test]
+fn filter() {
+    // Sums integers until the end of the input
+    let main = Assembler::new()
+        .iconst(0)
+        .label("loop")
+        .op(Instr::READ_INT).jump(Instr::RELJUMP_EQ, "end")
+        .op(Instr::ADD).jump(Instr::RELJUMP, "loop")
+        .label("end")
+        .op(Instr::POP).op(Instr::PRINT)
+        .finish();
+    let module = ModuleBuilder::new().main(&main).build();
+    let mut runtime = Runtime::new(module, Options::default()).unwrap();
+    runtime.set_input(Box::new("1 2 3\n4\n".as_bytes()));
+    let output = runtime.capture_output();
+    assert_eq!(runtime.run(), Ok(Status::Finished));
+    assert_eq!(output.contents(), "PRINT: 10\n");
+}
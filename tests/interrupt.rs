@@ -0,0 +1,114 @@
+// Stopping a running program with the interrupt handle, and with Ctrl-C on
+// the command line
+extern crate raptortime;
+
+#[cfg(unix)]
+use std::{env, fs};
+#[cfg(unix)]
+use std::io::{BufRead, BufReader, Read, Write};
+#[cfg(unix)]
+use std::process::{self, Child, Command, Stdio};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use raptortime::Options;
+use raptortime::runtime::{Runtime, Status};
+use raptortime::assembler::{Assembler, ModuleBuilder};
+use raptortime::instructions::Instruction as Instr;
+
+#[test]
+fn interrupt_handle() {
+    let main = Assembler::new().iconst(1).iconst(2).finish();
+    let mut runtime = Runtime::new(ModuleBuilder::new().main(&main).build(),
+                                   Options::default()).unwrap();
+    let interrupt = runtime.interrupt_handle();
+    interrupt.store(true, Ordering::Relaxed);
+    assert_eq!(runtime.run(), Ok(Status::Interrupted));
+    assert_eq!(runtime.steps(), 0);
+    assert!(!interrupt.load(Ordering::Relaxed));
+    assert_eq!(runtime.run(), Ok(Status::Finished));
+    assert_eq!(runtime.interpreter().op_stack, vec![1, 2]);
+
+    // From another thread, in a loop that never ends
+    let main = Assembler::new().label("loop").iconst(1).op(Instr::POP).jump(Instr::RELJUMP, "loop")
+        .finish();
+    let mut runtime = Runtime::new(ModuleBuilder::new().main(&main).build(),
+                                   Options::default()).unwrap();
+    // Plenty for 20ms, but a missed interrupt fails instead of hanging
+    runtime.set_step_limit(Some(500_000_000));
+    let interrupt = runtime.interrupt_handle();
+    let thread = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(20));
+        interrupt.store(true, Ordering::Relaxed);
+    });
+    assert_eq!(runtime.run(), Ok(Status::Interrupted));
+    thread.join().unwrap();
+    assert!(runtime.steps() > 0);
+    assert_eq!(runtime.call_stack().len(), 1);
+}
+
+// Waits for the program's first line of output, then interrupts it
+#[cfg(unix)]
+fn interrupt(child: &mut Child) -> String {
+    let mut line = String::new();
+    BufReader::new(child.stdout.as_mut().unwrap()).read_line(&mut line).unwrap();
+    let killed = Command::new("kill").arg("-INT").arg(child.id().to_string()).status().unwrap();
+    assert!(killed.success());
+    line
+}
+
+#[test]
+#[cfg(unix)]
+fn cli_interrupt() {
+    // f prints 5, then counts its local up forever
+    let f = Assembler::new()
+        .iconst(5).op(Instr::PRINT)
+        .label("loop")
+        .iconst(1).op_with(Instr::LOAD, 0).op(Instr::ADD).op_with(Instr::STORE, 0)
+        .jump(Instr::RELJUMP, "loop")
+        .finish();
+    let main = Assembler::new().op_with(Instr::CALL, 0).finish();
+    let module = ModuleBuilder::new().version(2).func(0, "f", 0, 1, &f).main(&main)
+        .debug_locals(0, &["count"]).build();
+    let input = env::temp_dir().join(format!("raptortime-interrupt-{}.crapt", process::id()));
+    fs::write(&input, module).unwrap();
+
+    // Only errors, a full stderr pipe would block the program
+    let mut child = Command::new(env!("CARGO_BIN_EXE_raptortime"))
+        .arg("-i").arg(&input)
+        .env("RUST_LOG", "error")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    assert_eq!(interrupt(&mut child), "PRINT: 5\n");
+    let status = child.wait().unwrap();
+    assert_eq!(status.code(), Some(130));
+    let mut stderr = String::new();
+    child.stderr.take().unwrap().read_to_string(&mut stderr).unwrap();
+    assert!(stderr.contains("Interrupted at f+"), "{}", stderr);
+    assert!(stderr.contains("#0 f (id 0), offset "), "{}", stderr);
+    assert!(stderr.contains("locals [count = "), "{}", stderr);
+    assert!(stderr.contains("#1 main, offset 0"), "{}", stderr);
+    assert!(stderr.contains("Op stack: ["), "{}", stderr);
+
+    // The debugger takes over where it stopped
+    let mut child = Command::new(env!("CARGO_BIN_EXE_raptortime"))
+        .arg("-i").arg(&input).arg("--debug-on-interrupt")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child.stdin.as_mut().unwrap().write_all(b"bt\nq\n").unwrap();
+    interrupt(&mut child);
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("step "), "{}", stdout);
+    assert!(stdout.contains("(rdb) #0 f (id 0)"), "{}", stdout);
+    assert!(stdout.contains("#1 main, offset 0\n(rdb) "), "{}", stdout);
+
+    fs::remove_file(&input).unwrap();
+}
@@ -0,0 +1,145 @@
+// Loading must fail cleanly, never panic, whatever bytes we get
+extern crate raptortime;
+
+use raptortime::Options;
+use raptortime::runtime::{Runtime, Status};
+use raptortime::error::{LoadError, RuntimeError};
+use raptortime::interpreter::MAIN_ID;
+use raptortime::assembler::{Assembler, ModuleBuilder};
+use raptortime::instructions::Instruction as Instr;
+
+fn module(version: u8) -> Vec<u8> {
+    let f = Assembler::new()
+        .op_with(Instr::LOAD, 0).jump(Instr::RELJUMP_EQ, "end")
+        .iconst(1).op(Instr::PRINT)
+        .label("end")
+        .iconst(0).op(Instr::RETURN)
+        .finish();
+    let main = Assembler::new().iconst(3).op_with(Instr::CALL, 0).op(Instr::POP).finish();
+    ModuleBuilder::new().version(version).var_count(1).func(0, "f", 1, 1, &f).main(&main).build()
+}
+
+fn load(data: &[u8]) -> Result<Runtime, LoadError> {
+    Runtime::new(data.to_vec(), Options::default())
+}
+
+#[test]
+fn valid() {
+    for version in 1..3 {
+        let mut runtime = load(&module(version)).unwrap();
+        assert_eq!(runtime.run(), Ok(Status::Finished));
+        assert_eq!(runtime.interpreter().header().version, version);
+        assert_eq!(runtime.interpreter().globals(), &[0]);
+    }
+}
+
+#[test]
+fn v2_sections() {
+    let main = Assembler::new().iconst(1).finish();
+    let data = ModuleBuilder::new()
+        .version(2)
+        .custom_section(0x04, &[1, 2, 3])
+        .custom_section(0x7F, &[4, 5])
+        .main(&main)
+        .build();
+    let mut runtime = load(&data).unwrap();
+    assert_eq!(runtime.run(), Ok(Status::Finished));
+    assert_eq!(runtime.interpreter().op_stack, vec![1]);
+    // Unknown kinds are skipped, custom ones kept for embedders
+    assert_eq!(runtime.interpreter().custom_sections.len(), 1);
+    assert_eq!(&*runtime.interpreter().custom_sections[0], &[1, 2, 3]);
+}
+
+#[test]
+fn v2_entry() {
+    let f = Assembler::new().iconst(7).op(Instr::HALT).finish();
+    let main = Assembler::new().iconst(1).finish();
+    let mut builder = ModuleBuilder::new();
+    builder.version(2).func(0, "start", 0, 0, &f).func(1, "args", 1, 0, &f).main(&main);
+
+    let mut runtime = load(&builder.entry(0).build()).unwrap();
+    assert_eq!(runtime.run(), Ok(Status::Halted));
+    assert_eq!(runtime.interpreter().op_stack, vec![7]);
+    assert_eq!(runtime.call_stack().len(), 1);
+
+    assert_eq!(load(&builder.entry(1).build()).unwrap_err(), LoadError::InvalidEntry(1));
+    assert_eq!(load(&builder.entry(2).build()).unwrap_err(), LoadError::InvalidEntry(2));
+}
+
+#[test]
+fn debug_info() {
+    let mut f = Assembler::new();
+    f.line(2).op_with(Instr::LOAD, 0).op_with(Instr::LOAD, 1)
+        .line(3).op(Instr::DIVIDE).op(Instr::RETURN);
+    let mut main = Assembler::new();
+    main.line(7).iconst(1).iconst(0).line(8).op_with(Instr::CALL, 0);
+    let data = ModuleBuilder::new()
+        .version(2)
+        .func(0, "div", 2, 0, &f.finish())
+        .main(&main.finish())
+        .debug_file("div.rapt")
+        .debug_lines(0, f.lines())
+        .debug_lines(MAIN_ID, main.lines())
+        .debug_locals(0, &["a", "b"])
+        .build();
+
+    let mut runtime = load(&data).unwrap();
+    assert_eq!(runtime.interpreter().debug_info.local_name(0, 1), Some("b"));
+    assert_eq!(runtime.run(), Err(RuntimeError::DivisionByZero));
+    assert_eq!(runtime.location(), Some("div.rapt:3".to_string()));
+    let caller = &runtime.call_stack()[0];
+    assert_eq!(runtime.interpreter().location(caller), "div.rapt:8");
+
+    // Without debug info we still know where we are
+    let data = module(2);
+    let mut runtime = load(&data).unwrap();
+    runtime.set_step_limit(Some(2));
+    assert_eq!(runtime.run(), Ok(Status::Paused));
+    assert_eq!(runtime.location(), Some("f+0".to_string()));
+}
+
+#[test]
+fn v2_section_out_of_bounds() {
+    let mut data = ModuleBuilder::new().version(2).main(&[0x00]).build();
+    // Low byte of the length of the code section, the second entry in the table
+    data[20 + 12 + 11] = 2;
+    assert_eq!(load(&data).unwrap_err(), LoadError::InvalidSection(0x01));
+}
+
+#[test]
+fn header() {
+    assert_eq!(load(&[0x5A, 0xB7]).unwrap_err(), LoadError::InvalidHeaderSize(2));
+    assert_eq!(load(&[0, 0, 0, 0, 0, 0, 0, 0]).unwrap_err(), LoadError::InvalidMagic(0));
+    assert_eq!(load(&[0x5A, 0xB7, 0x05, 0x00, 0xFF, 0xFF, 0xFF, 0xFF]).unwrap_err(),
+               LoadError::TooManyVars(0xFFFFFFFF));
+}
+
+#[test]
+fn truncated() {
+    for version in 1..3 {
+        let data = module(version);
+        for len in 0..data.len() {
+            let _ = load(&data[..len]);
+        }
+    }
+}
+
+#[test]
+fn corrupted() {
+    for version in 1..3 {
+        corrupt(&module(version));
+    }
+}
+
+fn corrupt(data: &[u8]) {
+    for i in 0..data.len() {
+        for &byte in &[0x00, 0x01, 0x7F, 0x80, 0xED, 0xF0, 0xFF] {
+            let mut data = data.to_vec();
+            data[i] = byte;
+            if let Ok(mut runtime) = load(&data) {
+                runtime.set_step_limit(Some(1000));
+                let _ = runtime.run();
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+// Where program output goes: a writer given by the embedder, or stdout
+extern crate raptortime;
+
+mod common;
+
+use std::io::{self, Write};
+
+use raptortime::Options;
+use raptortime::runtime::Runtime;
+use raptortime::error::RuntimeError;
+use raptortime::assembler::{Assembler, ModuleBuilder};
+use raptortime::instructions::Instruction as Instr;
+
+use common::run_binary;
+
+#[test]
+fn output_error() {
+    struct Closed;
+    impl Write for Closed {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let main = Assembler::new().iconst(1).op(Instr::PRINT).finish();
+    let module = ModuleBuilder::new().main(&main).build();
+    let mut runtime = Runtime::new(module, Options::default()).unwrap();
+    runtime.set_output(Box::new(Closed));
+    assert_eq!(runtime.run(), Err(RuntimeError::Output(io::ErrorKind::BrokenPipe)));
+}
+
+// Program output goes to stdout when running from the command line
+#[test]
+fn cli_output() {
+    let main = Assembler::new()
+        .iconst(1).iconst(2).op(Instr::DUMP_STACK).op(Instr::PRINT).op(Instr::HALT)
+        .finish();
+    let module = ModuleBuilder::new().main(&main).build();
+    assert_eq!(run_binary("output", &module, &[]),
+               "[1, 2]\nPRINT: 2\nHALT issued, stopped execution.\n");
+}
@@ -0,0 +1,125 @@
+// A replayed run must do exactly what the recorded one did, whatever the
+// host gives it the second time
+extern crate raptortime;
+
+use std::env;
+use std::fs;
+use std::io::{self, Cursor};
+use std::process::{self, Command, Stdio};
+
+use raptortime::Options;
+use raptortime::runtime::{Runtime, Status};
+use raptortime::error::RuntimeError;
+use raptortime::output::Capture;
+use raptortime::replay::read_recording;
+use raptortime::assembler::{Assembler, ModuleBuilder};
+use raptortime::instructions::Instruction as Instr;
+#[cfg(feature = "observers")]
+use raptortime::tracer::Tracer;
+
+// Prints the sum of its integer input, times its first argument
+fn module() -> Vec<u8> {
+    let main = Assembler::new()
+        .iconst(0)
+        .label("loop")
+        .op(Instr::READ_INT).jump(Instr::RELJUMP_EQ, "end")
+        .op(Instr::ADD).jump(Instr::RELJUMP, "loop")
+        .label("end")
+        .op(Instr::POP)
+        .iconst(0).op(Instr::ARG_INT).op(Instr::POP).op(Instr::MULTIPLY).op(Instr::PRINT)
+        .finish();
+    ModuleBuilder::new().main(&main).build()
+}
+
+fn options(args: &[&str]) -> Options {
+    Options { args: args.iter().map(|arg| arg.to_string()).collect(), ..Default::default() }
+}
+
+// Output, and the trace if we can trace
+fn run(mut runtime: Runtime) -> (String, String) {
+    let output = runtime.capture_output();
+    let trace = Capture::new();
+    #[cfg(feature = "observers")]
+    runtime.add_observer(Box::new(Tracer::new(Box::new(trace.clone()))));
+    assert_eq!(runtime.run(), Ok(Status::Finished));
+    (output.contents(), trace.contents())
+}
+
+#[test]
+fn replay() {
+    let recording = Capture::new();
+    let mut runtime = Runtime::new(module(), options(&["3"])).unwrap();
+    runtime.set_input(Box::new(Cursor::new("1 2\nx 4")));
+    runtime.record(Box::new(recording.clone())).unwrap();
+    let recorded = run(runtime);
+    assert_eq!(recorded.0, "PRINT: 21\n");
+
+    let recording = read_recording(&recording.bytes()).unwrap();
+    assert_eq!(recording.args, vec!["3"]);
+    assert_eq!(recording.events.len(), 5);
+
+    // Different arguments and no input at all
+    let mut runtime = Runtime::new(module(), options(&["5"])).unwrap();
+    runtime.set_input(Box::new(io::empty()));
+    runtime.replay(recording);
+    assert_eq!(run(runtime), recorded);
+}
+
+#[test]
+fn diverged() {
+    let recording = Capture::new();
+    let mut runtime = Runtime::new(module(), Options::default()).unwrap();
+    runtime.set_input(Box::new(Cursor::new("1")));
+    runtime.record(Box::new(recording.clone())).unwrap();
+    runtime.run().unwrap();
+    let recorded = recording.bytes();
+    let recording = read_recording(&recorded).unwrap();
+
+    // Reads one step later than the recorded run did
+    let main = Assembler::new().op(Instr::NOP).op(Instr::NOP).op(Instr::READ_INT).finish();
+    let module = ModuleBuilder::new().main(&main).build();
+    let mut runtime = Runtime::new(module, Options::default()).unwrap();
+    runtime.replay(recording);
+    assert_eq!(runtime.run(), Err(RuntimeError::ReplayDiverged(3)));
+
+    // Ends without reading what was recorded
+    let main = Assembler::new().op(Instr::NOP).finish();
+    let mut runtime = Runtime::new(ModuleBuilder::new().main(&main).build(), Options::default())
+        .unwrap();
+    runtime.replay(read_recording(&recorded).unwrap());
+    assert_eq!(runtime.run(), Err(RuntimeError::ReplayUnfinished(2)));
+    let main = Assembler::new().op(Instr::HALT).finish();
+    let mut runtime = Runtime::new(ModuleBuilder::new().main(&main).build(), Options::default())
+        .unwrap();
+    runtime.replay(read_recording(&recorded).unwrap());
+    assert_eq!(runtime.run(), Err(RuntimeError::ReplayUnfinished(2)));
+}
+
+#[test]
+fn cli_record_replay() {
+    let dir = env::temp_dir();
+    let input = dir.join(format!("raptortime-replay-{}.crapt", process::id()));
+    let recording = dir.join(format!("raptortime-replay-{}.rec", process::id()));
+    fs::write(&input, module()).unwrap();
+
+    let run = |args: &[&str], stdin: &[u8]| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_raptortime"))
+            .arg("-i").arg(&input)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        io::Write::write_all(child.stdin.as_mut().unwrap(), stdin).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let recorded = run(&["--record", recording.to_str().unwrap(), "--", "2"], b"5 6\n");
+    assert_eq!(recorded, "PRINT: 22\n");
+    assert_eq!(run(&["--replay", recording.to_str().unwrap()], b""), recorded);
+
+    fs::remove_file(&input).unwrap();
+    fs::remove_file(&recording).unwrap();
+}
@@ -0,0 +1,110 @@
+// Snapshots taken at any step must resume to the same result as a run
+// that was never interrupted
+extern crate raptortime;
+
+use std::env;
+use std::fs;
+use std::process::{self, Command};
+
+use raptortime::Options;
+use raptortime::runtime::{Runtime, Status};
+use raptortime::error::LoadError;
+use raptortime::snapshot::read_snapshot;
+use raptortime::assembler::{Assembler, ModuleBuilder};
+use raptortime::instructions::Instruction as Instr;
+
+// main prints f(3), f(n) prints n and returns n + f(n - 1)
+fn module() -> Vec<u8> {
+    let f = Assembler::new()
+        .op_with(Instr::LOAD, 0).jump(Instr::RELJUMP_EQ, "base")
+        .op_with(Instr::LOAD, 0).op(Instr::PRINT)
+        .iconst(1).op_with(Instr::LOAD, 0).op(Instr::SUB)
+        .op_with(Instr::CALL, 0)
+        .op_with(Instr::LOAD, 0).op(Instr::ADD).op(Instr::RETURN)
+        .label("base")
+        .iconst(0).op(Instr::RETURN)
+        .finish();
+    let main = Assembler::new()
+        .iconst(3).op_with(Instr::CALL, 0).op(Instr::PRINT).op(Instr::DUMP_GLOBALS)
+        .finish();
+    ModuleBuilder::new().var_count(2).func(0, "f", 1, 1, &f).main(&main).build()
+}
+
+#[test]
+fn resume_at_every_step() {
+    let mut runtime = Runtime::new(module(), Options::default()).unwrap();
+    let expected = runtime.capture_output();
+    assert_eq!(runtime.run(), Ok(Status::Finished));
+    let steps = runtime.steps();
+
+    for pause in 0..steps + 1 {
+        let mut first = Runtime::new(module(), Options::default()).unwrap();
+        let output = first.capture_output();
+        first.set_step_limit(Some(pause));
+        first.run().unwrap();
+        let data = first.snapshot().to_bytes();
+
+        let snapshot = read_snapshot(&data).unwrap();
+        let mut second = Runtime::restore(&snapshot, Options::default()).unwrap();
+        let rest = second.capture_output();
+        assert_eq!(second.steps(), pause);
+        assert_eq!(second.run(), Ok(Status::Finished), "{}", pause);
+        assert_eq!(output.contents() + &rest.contents(), expected.contents(), "{}", pause);
+        assert_eq!(second.steps(), steps);
+        assert_eq!(second.interpreter().op_stack, runtime.interpreter().op_stack);
+    }
+}
+
+#[test]
+fn mismatched() {
+    let mut runtime = Runtime::new(module(), Options::default()).unwrap();
+    runtime.set_step_limit(Some(4));
+    runtime.run().unwrap();
+
+    let mut snapshot = runtime.snapshot();
+    snapshot.call_stack[1].locals.push(0);
+    assert_eq!(Runtime::restore(&snapshot, Options::default()).unwrap_err(),
+               LoadError::InvalidSnapshot("locals"));
+
+    let mut snapshot = runtime.snapshot();
+    snapshot.call_stack[1].id = 1;
+    assert_eq!(Runtime::restore(&snapshot, Options::default()).unwrap_err(),
+               LoadError::InvalidSnapshot("function id"));
+
+    let mut snapshot = runtime.snapshot();
+    snapshot.globals.pop();
+    assert_eq!(Runtime::restore(&snapshot, Options::default()).unwrap_err(),
+               LoadError::InvalidSnapshot("globals"));
+
+    let mut snapshot = runtime.snapshot();
+    snapshot.call_stack[0].bc_counter = 100;
+    assert_eq!(Runtime::restore(&snapshot, Options::default()).unwrap_err(),
+               LoadError::InvalidSnapshot("bytecode counter"));
+}
+
+#[test]
+fn cli_checkpoint() {
+    let dir = env::temp_dir();
+    let input = dir.join(format!("raptortime-snapshot-{}.crapt", process::id()));
+    let checkpoint = dir.join(format!("raptortime-snapshot-{}.snap", process::id()));
+    fs::write(&input, module()).unwrap();
+
+    let run = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_raptortime"))
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let all = run(&["-i", input.to_str().unwrap(),
+                    "--checkpoint", checkpoint.to_str().unwrap(), "--checkpoint-every", "5"]);
+    assert_eq!(all, "PRINT: 3\nPRINT: 2\nPRINT: 1\nPRINT: 6\n[0, 0]\n");
+
+    // The last checkpoint was taken after 40 of the 41 steps
+    let rest = run(&["--resume", checkpoint.to_str().unwrap()]);
+    assert_eq!(rest, "[0, 0]\n");
+
+    fs::remove_file(&input).unwrap();
+    fs::remove_file(&checkpoint).unwrap();
+}
@@ -0,0 +1,69 @@
+// Raptor stack traces of a failed program, and --trace-on-error
+extern crate raptortime;
+
+mod common;
+
+use raptortime::Options;
+use raptortime::runtime::Runtime;
+use raptortime::error::RuntimeError;
+use raptortime::assembler::{Assembler, ModuleBuilder};
+use raptortime::instructions::Instruction as Instr;
+
+use common::run_cli;
+
+// case(a, b, c, d) calls callee(3, 0), which divides by zero, or halts
+// instead if `halt`
+fn module(halt: bool) -> Vec<u8> {
+    let case = Assembler::new()
+        .iconst(7).op_with(Instr::STORE, 2)
+        .iconst(3).iconst(0).op_with(Instr::CALL, 1)
+        .finish();
+    let mut callee = Assembler::new();
+    if halt {
+        callee.op(Instr::HALT);
+    }
+    callee.op_with(Instr::LOAD, 0).op_with(Instr::LOAD, 1).op(Instr::DIVIDE);
+    let main = Assembler::new().op_with(Instr::CALL, 0).finish();
+    ModuleBuilder::new()
+        .func(0, "case", 0, 4, &case)
+        .func(1, "callee", 2, 1, &callee.finish())
+        .main(&main)
+        .build()
+}
+
+#[test]
+fn stack_trace() {
+    let mut runtime = Runtime::new(module(false), Options::default()).unwrap();
+    assert_eq!(runtime.run(), Err(RuntimeError::DivisionByZero));
+    assert_eq!(runtime.stack_trace(), vec![
+        "#0 callee (id 1), offset 10, args [0, 3], locals [0]",
+        "#1 case (id 0), offset 20, args [], locals [0, 0, 7, 0]",
+        "#2 main, offset 0",
+    ]);
+}
+
+// Runs a module through the CLI and returns its exit code and stderr
+fn run(name: &str, module: &[u8], args: &[&str]) -> (Option<i32>, String) {
+    let output = run_cli(name, module, args);
+    (output.status.code(), String::from_utf8(output.stderr).unwrap())
+}
+
+#[test]
+fn cli_trace_on_error() {
+    // Runtime errors are always traced
+    let (code, stderr) = run("error", &module(false), &[]);
+    assert_eq!(code, Some(1));
+    assert!(stderr.contains("callee+10: Runtime error: Division by zero"), "{}", stderr);
+    assert!(stderr.contains("Raptor stack trace:"), "{}", stderr);
+    assert!(stderr.contains("#0 callee (id 1), offset 10, args [0, 3], locals [0]"), "{}", stderr);
+    assert!(stderr.contains("#2 main, offset 0"), "{}", stderr);
+
+    // HALT isn't a failure, only traced with the option
+    let (code, stderr) = run("halt", &module(true), &[]);
+    assert_eq!(code, Some(0));
+    assert!(!stderr.contains("Raptor stack trace"), "{}", stderr);
+    let (code, stderr) = run("halt_trace", &module(true), &["--trace-on-error"]);
+    assert_eq!(code, Some(0));
+    assert!(stderr.contains("Halted at callee+0"), "{}", stderr);
+    assert!(stderr.contains("#1 case (id 0), offset 20, args [], locals [0, 0, 7, 0]"), "{}", stderr);
+}